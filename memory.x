@@ -11,10 +11,27 @@
 
 MEMORY
 {
-  FLASH (rx) : ORIGIN = 0x00004000, LENGTH = 0xC000
+  FLASH (rx) : ORIGIN = 0x00004000, LENGTH = 0xC000 - 0x2000
+  DISK (rw)  : ORIGIN = 0x0000E000, LENGTH = 0x2000
   RAM (rwx)  : ORIGIN = 0x20000000 + 8, LENGTH = 0x2000 - 8
 }
 
+/* The last 8 KB of flash hold the disk of the mass storage class, see usb/mass_storage. Nothing */
+/* is linked there, so it survives flashing a new image. */
+
+/* The first 8 bytes of RAM are shared with Toboot, see toboot.rs */
+toboot_runtime = ORIGIN(RAM) - 8;
+
+/* Toboot looks for its header right after the vector table, see toboot.rs */
+SECTIONS
+{
+  .toboot_header ORIGIN(FLASH) + 0x94 :
+  {
+    KEEP(*(.toboot_header));
+  } > FLASH
+}
+INSERT AFTER .vector_table;
+
 /* ENTRY(Reset_Handler) */
 
 /* SECTIONS */
@@ -0,0 +1,120 @@
+//! This module contains the arithmetic used for configuring clock dividers, both the prescalers in
+//! `cmu` and the `LEUART_CLKDIV` register. It does not touch the hardware, which means that it can
+//! also be compiled for the host and unit tested there:
+//!
+//! ```sh
+//! cargo test
+//! ```
+
+/// The frequency of a clock driven by a clock running at `frequency` through a prescaler dividing
+/// by `division`. This is the `Clock::FREQUENCY` of every clock in `cmu` with a divider.
+#[cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
+pub const fn divided_frequency(frequency: f64, division: u64) -> f64 {
+    frequency / division as f64
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
+pub fn scaling_factor(frequency: f64, baudrate: f64) -> u16 {
+    // The formulas in EFM32-HF-RM 17.3.3 says how to calculate the value of leuart.clkdiv from the
+    // desired baud rate. What it does not say is that the 3 lower bits of leuart.clkdiv must be 0
+    // (this is specified in 17.5.4).
+    //
+    // For instance they specify 616 as the value used to best achieve 9600 baud when using a 32768
+    // Hz clock (such as the lfrco). Actually a better value would be 617 or 618, but these are disallowed.
+    //
+    // The fact that the lower bits must be zero is also noticeable in the svd file. At some point we
+    // had a bug where we tried to set w.div().bits(616), which is equivalent to w.bits(616 << 3) -- which obviously
+    // did not go well.
+    //
+    // Here we are using a derived fomula for finding the correct value of clkdiv from the baud rate.
+    // The result from here is meant to be put into w.div().bits(...)
+    // instead of w.bits(...), so if you try to port this to something else, make sure to shift this
+    // up accordingly.
+    let scaling_factor = 32.0 * (frequency / baudrate - 1.0);
+    let scaling_factor = f64::max(0.0, scaling_factor);
+    let scaling_factor = f64::min(0b1111_1111_1111 as f64, scaling_factor);
+    (scaling_factor + 0.5) as u16
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
+pub fn recalculated_baudrate(frequency: f64, scaling_factor: u16) -> f64 {
+    frequency / (1.0 + (scaling_factor as f64) / 32.0)
+}
+
+pub fn error(baudrate: f64, recalculated_baudrate: f64) -> f64 {
+    let error = 1.0 - recalculated_baudrate / baudrate;
+    if error >= 0.0 {
+        error
+    } else {
+        -error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The frequency of the HFRCO in its 21 MHz band
+    const HFRCO: f64 = 21_000_000.0;
+
+    // (frequency, baud rate, expected value of the full `LEUART_CLKDIV` register)
+    const CLKDIV_TABLE: [(f64, f64, u32); 9] = [
+        // The example from EFM32HG-RM 17.3.3, with the LFRCO through LFBCLKLEUART0/1 as in main.rs
+        (32768.0, 9600.0, 616),
+        (32768.0, 4800.0, 1488),
+        (32768.0, 2400.0, 3240),
+        (32768.0, 1200.0, 6736),
+        // The LFRCO through LFBCLKLEUART0/2
+        (divided_frequency(32768.0, 2), 2400.0, 1488),
+        // HFRCO at 21 MHz, through HFCORECLKLE/4 and LFBCLKLEUART0/8
+        (divided_frequency(divided_frequency(HFRCO, 4), 8), 115_200.0, 1200),
+        (divided_frequency(divided_frequency(HFRCO, 4), 8), 57600.0, 2664),
+        // HFRCO at 21 MHz, through HFCORECLKLE/4 and LFBCLKLEUART0/2
+        (divided_frequency(divided_frequency(HFRCO, 4), 2), 115_200.0, 5576),
+        // The clock frequency itself needs no division at all
+        (32768.0, 32768.0, 0),
+    ];
+
+    #[test]
+    fn divided_frequency_matches_clock_tree() {
+        assert_eq!(divided_frequency(HFRCO, 1), HFRCO);
+        assert_eq!(divided_frequency(divided_frequency(HFRCO, 4), 8), 656_250.0);
+        assert_eq!(divided_frequency(32768.0, 32768), 1.0);
+    }
+
+    #[test]
+    fn clkdiv_table() {
+        for &(frequency, baudrate, clkdiv) in CLKDIV_TABLE.iter() {
+            let scaling_factor = scaling_factor(frequency, baudrate);
+            assert_eq!(
+                u32::from(scaling_factor) << 3,
+                clkdiv,
+                "{} baud at {} Hz",
+                baudrate,
+                frequency
+            );
+
+            let recalculated = recalculated_baudrate(frequency, scaling_factor);
+            assert!(error(baudrate, recalculated) < 0.025);
+        }
+    }
+
+    #[test]
+    fn scaling_factor_saturates() {
+        assert_eq!(scaling_factor(32768.0, 100.0), 0b1111_1111_1111);
+        assert_eq!(scaling_factor(32768.0, 115_200.0), 0);
+    }
+
+    #[test]
+    fn recalculated_baudrate_matches_reference_manual() {
+        // 32768 / (1 + 616 / 256)
+        let recalculated = recalculated_baudrate(32768.0, 616 >> 3);
+        assert!((recalculated - 9619.963).abs() < 0.001);
+    }
+
+    #[test]
+    fn error_is_symmetric() {
+        assert_eq!(error(256.0, 288.0), error(256.0, 224.0));
+        assert_eq!(error(9600.0, 9600.0), 0.0);
+    }
+}
@@ -1,4 +1,4 @@
-use super::{Clock, HfCoreClkLeDiv, LfRco, LfXo, Off, ULfRco};
+use super::{Clock, Em2Clock, HfCoreClkLeDiv, LfRco, LfXo, Off, ULfRco};
 use efm32hg309f64;
 use typenum;
 
@@ -14,6 +14,12 @@ clock_switch_and_divide!(
     LfaClkRtc
 );
 
+impl<'source, Source: Em2Clock> Em2Clock for LfaClk<'source, Source> {}
+impl<'source, Source: Em2Clock, Division: typenum::Unsigned> Em2Clock
+    for LfaClkRtc<'source, Source, Division>
+{
+}
+
 macro_rules! lfa_source {
     ($cmu:ident $meth:ident $name:ident $typ:ident { $($code:tt)* }) => {
         /// Enables the `LFACLK` and sets its source by setting the `LFA` and `LFAE` subfields in
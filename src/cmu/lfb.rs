@@ -1,4 +1,4 @@
-use super::{Clock, HfCoreClkLeDiv, LfRco, LfXo, Off, ULfRco};
+use super::{Clock, Em2Clock, HfCoreClkLeDiv, LfRco, LfXo, Off, ULfRco};
 use efm32hg309f64;
 use typenum;
 
@@ -13,6 +13,12 @@ clock_switch_and_divide!(
     LfbClkLeuart0
 );
 
+impl<'source, Source: Em2Clock> Em2Clock for LfbClk<'source, Source> {}
+impl<'source, Source: Em2Clock, Division: typenum::Unsigned> Em2Clock
+    for LfbClkLeuart0<'source, Source, Division>
+{
+}
+
 macro_rules! lfb_source {
     ($cmu:ident $meth:ident $name:ident $typ:ident { $($code:tt)* }) => {
         /// Enables the `LFBCLK` and sets its source by setting the `LFB` and `LFBE` subfields in
@@ -1,4 +1,4 @@
-use super::{Clock, LfRco, LfXo};
+use super::{Clock, Em2Clock, LfRco, LfXo};
 use efm32hg309f64;
 
 clock_switch!(
@@ -12,6 +12,9 @@ clock_switch!(
     LfcClkUsbLe
 );
 
+impl<'source, Source: Em2Clock> Em2Clock for LfcClk<'source, Source> {}
+impl<'source, Source: Em2Clock> Em2Clock for LfcClkUsbLe<'source, Source> {}
+
 macro_rules! lfc_source {
     ($meth:ident $fun:ident $typ:ident) => {
         /// Enables the `LFCCLK` and sets its source by setting the `LFC` subfield in `CMU_LFCLKSEL`.
@@ -1,5 +1,6 @@
 use consts;
 use efm32hg309f64;
+use typenum;
 
 clock_source!(
     /// This type represents ownership over the `LFRCO`, the Low-Frequency RC Oscillator, which
@@ -13,6 +14,9 @@ clock_source!(
     ULfRco
 );
 
+impl<Frequency: typenum::Unsigned> super::Em2Clock for LfRco<Frequency> {}
+impl<Frequency: typenum::Unsigned> super::Em2Clock for ULfRco<Frequency> {}
+
 impl<Frequency> LfRco<Frequency> {
     /// Enables the `LFRCO` by setting the `LFRCOEN` bit in `CMU_OSENCMD`.
     ///
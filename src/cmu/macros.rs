@@ -102,7 +102,8 @@ macro_rules! clock_switch_and_divide {
         impl<'source, Source: ::cmu::Clock, Division: ::typenum::Unsigned> ::cmu::Clock
             for $name<'source, Source, Division>
         {
-            const FREQUENCY: f64 = Source::FREQUENCY / (Division::U64 as f64);
+            const FREQUENCY: f64 =
+                ::clock_math::divided_frequency(Source::FREQUENCY, Division::U64);
         }
 
         impl<'source, Source, Division> ::devices::Device
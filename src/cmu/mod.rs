@@ -28,6 +28,10 @@
 //!
 //! * The clock named name `WDOGCLK` has not been represented yet. Doing so might be useful but has
 //! not yet implemented.
+//!
+//! Clocks that keep running in EM2 implement the `Em2Clock` trait. This makes it possible for
+//! drivers to require at compile time that the clock they are using is still alive when the CPU is
+//! put into deep sleep.
 
 use consts;
 use efm32hg309f64;
@@ -74,6 +78,11 @@ pub trait Clock {
     const FREQUENCY: f64;
 }
 
+/// Marker trait for clocks that keep running in EM2 (Deep Sleep). Only the low-frequency
+/// oscillators are kept alive in EM2, so this is only implemented for those and for the clocks
+/// that are derived from them.
+pub trait Em2Clock: Clock {}
+
 pub struct InitialCmuState {
     pub hfclk: HfClk<'static, Uninitialized, Uninitialized>,
     pub hfcoreclk: HfCoreClk<'static, Uninitialized, Uninitialized>,
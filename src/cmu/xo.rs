@@ -1,4 +1,6 @@
+use consts;
 use efm32hg309f64;
+use typenum;
 
 clock_source!(
     /// This type represents ownership over the `HFXO`, the High-Frequency Crystal Oscillator, which
@@ -10,8 +12,6 @@ clock_source!(
 clock_source!(
     /// This type represents ownership over the `LFXO`, the Low-Frequency Crystal Oscillator, which
     /// uses an external crystal to oscillate at 32768Hz.
-    ///
-    /// Support for this clock has not been implemented yet.
     LfXo
 );
 
@@ -25,7 +25,24 @@ impl<Frequency> HfXo<Frequency> {
     }
 }
 
+impl<Frequency: typenum::Unsigned> super::Em2Clock for LfXo<Frequency> {}
+
 impl<Frequency> LfXo<Frequency> {
+    /// Enables the `LFXO` by setting the `LFXOEN` bit in `CMU_OSENCMD`. The crystal is expected
+    /// to be connected to `PB7` and `PB8`.
+    ///
+    /// This function will block until the `LFXO` as ready, by waiting for the `LFXORDY` bit to be
+    /// set in `CMU_STATUS`.
+    #[inline]
+    pub fn enable_32768hz(self) -> LfXo<consts::Hz32768> {
+        let cmu = unsafe { &*efm32hg309f64::CMU::ptr() };
+
+        cmu.oscencmd.write(|w| w.lfxoen().set_bit());
+        while cmu.status.read().lfxordy().bit_is_clear() {}
+
+        unsafe { self.transmute_state() }
+    }
+
     /// Disables the `LFXO` by setting the `HFXODIS` bitsin `CMU_OSENCMD`.
     #[inline]
     pub fn disable(self) -> LfXo<super::Off> {
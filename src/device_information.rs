@@ -15,6 +15,11 @@ const HFRCO_CALIB_BAND_11: *const u8 = 0x0fe0_81de as *const u8;
 const HFRCO_CALIB_BAND_14: *const u8 = 0x0fe0_81df as *const u8;
 const HFRCO_CALIB_BAND_21: *const u8 = 0x0fe0_81e0 as *const u8;
 
+const UNIQUE_L: *const u32 = 0x0fe0_81f0 as *const u32;
+const UNIQUE_H: *const u32 = 0x0fe0_81f4 as *const u32;
+const MSIZE: *const u32 = 0x0fe0_81f8 as *const u32;
+const PART: *const u32 = 0x0fe0_81fc as *const u32;
+
 #[inline]
 pub fn get_hfrco_calib_band_1() -> u8 {
     unsafe { *HFRCO_CALIB_BAND_1 }
@@ -84,3 +89,36 @@ pub fn get_ushfrco_calib_band_48() -> (u8, u8) {
         )
     }
 }
+
+#[inline]
+pub fn get_unique_id() -> u64 {
+    unsafe { (u64::from(*UNIQUE_H) << 32) | u64::from(*UNIQUE_L) }
+}
+
+/// The size of the flash in KB.
+#[inline]
+pub fn get_flash_size() -> u16 {
+    unsafe { *MSIZE as u16 }
+}
+
+/// The size of the RAM in KB.
+#[inline]
+pub fn get_sram_size() -> u16 {
+    unsafe { (*MSIZE >> 16) as u16 }
+}
+
+/// The part number, e.g. 309 for the EFM32HG309F64.
+#[inline]
+pub fn get_part_number() -> u16 {
+    unsafe { *PART as u16 }
+}
+
+#[inline]
+pub fn get_part_family() -> u8 {
+    unsafe { (*PART >> 16) as u8 }
+}
+
+#[inline]
+pub fn get_prod_rev() -> u8 {
+    unsafe { (*PART >> 24) as u8 }
+}
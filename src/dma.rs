@@ -0,0 +1,402 @@
+//! This module contains a minimal driver for the DMA controller, see chapter 8 in EFM32HG-RM.pdf.
+//!
+//! The DMA controller is an ARM PL230, which reads its configuration from a table of channel
+//! descriptors in RAM. Only the two transfer modes needed by the peripherals in this crate are
+//! supported:
+//!
+//! * Basic mode, used for one-off transfers from memory to a peripheral. Transfers longer than
+//! 1024 elements are split into several cycles, which are re-armed by `handle_interrupt`.
+//!
+//! * Ping-pong mode, used for circular transfers from a peripheral into memory. The buffer is split
+//! into two halves, one for the primary and one for the alternate descriptor, and whichever half
+//! has just been filled is re-armed by `handle_interrupt`.
+//!
+//! Both modes depend on `handle_interrupt` being called from the `DMA` interrupt, which is
+//! normally done by registering it through `nvic::NvicHandle::register`.
+
+use cmu;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cortex_m;
+use efm32hg309f64;
+
+/// The number of channels on the EFM32HG.
+const CHANNELS: usize = 6;
+
+/// The maximum number of elements the controller can move in a single cycle.
+const MAX_CYCLE_LENGTH: usize = 1024;
+
+// Bits in the `control` word of a descriptor, see the PL230 TRM section 3.3.
+const CYCLE_CTRL_MASK: u32 = 0b111;
+const CYCLE_CTRL_BASIC: u32 = 0b001;
+const CYCLE_CTRL_PING_PONG: u32 = 0b011;
+const N_MINUS_1_SHIFT: u32 = 4;
+const N_MINUS_1_MASK: u32 = 0x3ff << N_MINUS_1_SHIFT;
+const SRC_INC_BYTE: u32 = 0b00 << 26;
+const SRC_INC_NONE: u32 = 0b11 << 26;
+const DST_INC_BYTE: u32 = 0b00 << 30;
+const DST_INC_NONE: u32 = 0b11 << 30;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    src_end: u32,
+    dst_end: u32,
+    control: u32,
+    user: u32,
+}
+
+// The primary descriptors for all 8 possible channels come first, followed by the alternate
+// descriptors. The table must be aligned to its own size.
+#[repr(C, align(256))]
+struct DescriptorTable {
+    primary: [Descriptor; 8],
+    alternate: [Descriptor; 8],
+}
+
+const EMPTY_DESCRIPTOR: Descriptor = Descriptor {
+    src_end: 0,
+    dst_end: 0,
+    control: 0,
+    user: 0,
+};
+
+static mut DESCRIPTORS: DescriptorTable = DescriptorTable {
+    primary: [EMPTY_DESCRIPTOR; 8],
+    alternate: [EMPTY_DESCRIPTOR; 8],
+};
+
+// State used by `handle_interrupt` to re-arm channels. For basic transfers `REMAINING` is the
+// number of bytes that have not yet been handed to a cycle and `NEXT_ADDRESS` is where they
+// start. For ping-pong transfers `REMAINING` is always zero, `HALF_LENGTH` is non-zero and
+// `NEXT_ADDRESS` is the start of the buffer.
+macro_rules! per_channel {
+    ($name:ident) => {
+        static $name: [AtomicUsize; CHANNELS] = [
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+        ];
+    };
+}
+
+per_channel!(REMAINING);
+per_channel!(NEXT_ADDRESS);
+per_channel!(HALF_LENGTH);
+per_channel!(COMPLETED_HALVES);
+
+/// A request line which can trigger a DMA channel. The values are the `SOURCESEL` and `SIGSEL`
+/// subfields in `DMA_CHx_CTRL`.
+#[derive(Clone, Copy)]
+pub struct Request {
+    source: u8,
+    signal: u8,
+}
+
+pub const LEUART0_RXDATAV: Request = Request {
+    source: 0b01_0000,
+    signal: 0,
+};
+pub const LEUART0_TXBL: Request = Request {
+    source: 0b01_0000,
+    signal: 1,
+};
+pub const LEUART0_TXEMPTY: Request = Request {
+    source: 0b01_0000,
+    signal: 2,
+};
+
+/// Trait for the zero-sized types representing ownership over a single DMA channel.
+pub trait Channel {
+    const NR: usize;
+
+    /// Selects the request line triggering the channel by writing to `DMA_CHx_CTRL`.
+    fn set_request(&mut self, request: Request);
+}
+
+macro_rules! channel {
+    ($name:ident, $nr:expr, $ctrl:ident) => {
+        pub struct $name {
+            non_send: PhantomData<*mut ()>,
+        }
+
+        impl $name {
+            #[inline]
+            unsafe fn claim_ownership() -> Self {
+                $name {
+                    non_send: PhantomData,
+                }
+            }
+        }
+
+        impl Channel for $name {
+            const NR: usize = $nr;
+
+            #[inline]
+            fn set_request(&mut self, request: Request) {
+                let dma = unsafe { &*efm32hg309f64::DMA::ptr() };
+                dma.$ctrl.write(|w| unsafe {
+                    w.sourcesel()
+                        .bits(request.source)
+                        .sigsel()
+                        .bits(request.signal)
+                });
+            }
+        }
+    };
+}
+
+channel!(Channel0, 0, ch0_ctrl);
+channel!(Channel1, 1, ch1_ctrl);
+channel!(Channel2, 2, ch2_ctrl);
+channel!(Channel3, 3, ch3_ctrl);
+channel!(Channel4, 4, ch4_ctrl);
+channel!(Channel5, 5, ch5_ctrl);
+
+pub struct InitialDmaState {
+    pub ch0: Channel0,
+    pub ch1: Channel1,
+    pub ch2: Channel2,
+    pub ch3: Channel3,
+    pub ch4: Channel4,
+    pub ch5: Channel5,
+}
+
+impl InitialDmaState {
+    /// Enables the DMA controller by setting the `EN` bit in `DMA_CONFIG`, and points it at the
+    /// descriptor table by writing `DMA_CTRLBASE`.
+    ///
+    /// # Safety
+    /// This function assumes that the `DMA` given in the argument is in its initial state and that
+    /// the function is only called once.
+    #[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+    pub unsafe fn get_initial_state<Source>(
+        dma: efm32hg309f64::DMA,
+        clk: &'static cmu::HfCoreClkDma<'static, Source>,
+    ) -> InitialDmaState
+    where
+        cmu::HfCoreClkDma<'static, Source>: cmu::Clock,
+    {
+        let _ = clk;
+        dma.ctrlbase
+            .write(|w| w.bits(&DESCRIPTORS as *const DescriptorTable as u32));
+        dma.config.write(|w| w.en().set_bit());
+
+        InitialDmaState {
+            ch0: Channel0::claim_ownership(),
+            ch1: Channel1::claim_ownership(),
+            ch2: Channel2::claim_ownership(),
+            ch3: Channel3::claim_ownership(),
+            ch4: Channel4::claim_ownership(),
+            ch5: Channel5::claim_ownership(),
+        }
+    }
+}
+
+#[inline]
+fn control(src_inc: u32, dst_inc: u32, length: usize, cycle_ctrl: u32) -> u32 {
+    debug_assert!(length > 0 && length <= MAX_CYCLE_LENGTH);
+    // The size fields are left at zero, which means bytes
+    dst_inc | src_inc | (((length - 1) as u32) << N_MINUS_1_SHIFT) | cycle_ctrl
+}
+
+#[inline]
+unsafe fn primary(nr: usize) -> *mut Descriptor {
+    &mut DESCRIPTORS.primary[nr]
+}
+
+#[inline]
+unsafe fn alternate(nr: usize) -> *mut Descriptor {
+    &mut DESCRIPTORS.alternate[nr]
+}
+
+#[inline]
+unsafe fn arm_basic(nr: usize, src: usize, dst: usize, length: usize) {
+    ptr::write_volatile(
+        primary(nr),
+        Descriptor {
+            src_end: (src + length - 1) as u32,
+            dst_end: dst as u32,
+            control: control(SRC_INC_BYTE, DST_INC_NONE, length, CYCLE_CTRL_BASIC),
+            user: 0,
+        },
+    );
+}
+
+#[inline]
+unsafe fn arm_ping_pong(descriptor: *mut Descriptor, src: usize, dst: usize, length: usize) {
+    ptr::write_volatile(
+        descriptor,
+        Descriptor {
+            src_end: src as u32,
+            dst_end: (dst + length - 1) as u32,
+            control: control(SRC_INC_NONE, DST_INC_BYTE, length, CYCLE_CTRL_PING_PONG),
+            user: 0,
+        },
+    );
+}
+
+#[inline]
+fn enable_channel(nr: usize) {
+    let dma = unsafe { &*efm32hg309f64::DMA::ptr() };
+    unsafe {
+        dma.chaltc.write(|w| w.bits(1 << nr));
+        dma.chuseburstc.write(|w| w.bits(1 << nr));
+        dma.chreqmaskc.write(|w| w.bits(1 << nr));
+        dma.ien.modify(|r, w| w.bits(r.bits() | (1 << nr)));
+        dma.chens.write(|w| w.bits(1 << nr));
+    }
+}
+
+#[inline]
+fn disable_channel(nr: usize) {
+    let dma = unsafe { &*efm32hg309f64::DMA::ptr() };
+    unsafe {
+        dma.chenc.write(|w| w.bits(1 << nr));
+        dma.ien.modify(|r, w| w.bits(r.bits() & !(1 << nr)));
+    }
+    REMAINING[nr].store(0, Ordering::Release);
+    HALF_LENGTH[nr].store(0, Ordering::Release);
+}
+
+#[inline]
+fn channel_enabled(nr: usize) -> bool {
+    let dma = unsafe { &*efm32hg309f64::DMA::ptr() };
+    dma.chens.read().bits() & (1 << nr) != 0
+}
+
+/// Starts moving `src` into the peripheral register at `dst`, one byte every time `request`
+/// fires.
+pub(crate) fn start_to_peripheral<C: Channel>(
+    channel: &mut C,
+    request: Request,
+    src: &'static [u8],
+    dst: *const u8,
+) {
+    if src.is_empty() {
+        return;
+    }
+
+    let nr = C::NR;
+    let length = src.len().min(MAX_CYCLE_LENGTH);
+    channel.set_request(request);
+    cortex_m::interrupt::free(|_| unsafe {
+        REMAINING[nr].store(src.len() - length, Ordering::Relaxed);
+        NEXT_ADDRESS[nr].store(src.as_ptr() as usize + length, Ordering::Relaxed);
+        HALF_LENGTH[nr].store(0, Ordering::Relaxed);
+        arm_basic(nr, src.as_ptr() as usize, dst as usize, length);
+        enable_channel(nr);
+    });
+}
+
+/// Starts filling the `len` bytes at `dst` from the peripheral register at `src` in a circle, one
+/// byte every time `request` fires. The length must be even and at most 2048 bytes.
+pub(crate) unsafe fn start_circular_from_peripheral<C: Channel>(
+    channel: &mut C,
+    request: Request,
+    src: *const u8,
+    dst: *mut u8,
+    len: usize,
+) {
+    assert!(len % 2 == 0 && len != 0 && len <= 2 * MAX_CYCLE_LENGTH);
+
+    let nr = C::NR;
+    let half = len / 2;
+    let dst = dst as usize;
+    channel.set_request(request);
+    cortex_m::interrupt::free(|_| {
+        REMAINING[nr].store(0, Ordering::Relaxed);
+        NEXT_ADDRESS[nr].store(dst, Ordering::Relaxed);
+        HALF_LENGTH[nr].store(half, Ordering::Relaxed);
+        COMPLETED_HALVES[nr].store(0, Ordering::Relaxed);
+        arm_ping_pong(primary(nr), src as usize, dst, half);
+        arm_ping_pong(alternate(nr), src as usize, dst + half, half);
+        enable_channel(nr);
+    });
+}
+
+/// Returns true once a transfer started with `start_to_peripheral` has been handed completely to
+/// the peripheral.
+pub(crate) fn is_done<C: Channel>(_channel: &C) -> bool {
+    let nr = C::NR;
+    REMAINING[nr].load(Ordering::Acquire) == 0 && !channel_enabled(nr)
+}
+
+/// Stops the channel, no matter what it is doing.
+pub(crate) fn stop<C: Channel>(_channel: &mut C) {
+    cortex_m::interrupt::free(|_| disable_channel(C::NR));
+}
+
+// The halves of a circular transfer are filled alternately by the primary and the alternate
+// descriptor, starting with the primary. The number of halves handled by `handle_interrupt` thus
+// tells which descriptor is currently filling, or has just filled, its half.
+#[inline]
+unsafe fn current_half(nr: usize, completed_halves: usize) -> *mut Descriptor {
+    if completed_halves % 2 == 0 {
+        primary(nr)
+    } else {
+        alternate(nr)
+    }
+}
+
+/// Returns the total number of bytes written by a circular transfer since it was started. The
+/// position in the buffer is this number modulo the buffer length.
+pub(crate) fn circular_position<C: Channel>(_channel: &C) -> usize {
+    let nr = C::NR;
+    let half = HALF_LENGTH[nr].load(Ordering::Relaxed);
+    cortex_m::interrupt::free(|_| unsafe {
+        let completed_halves = COMPLETED_HALVES[nr].load(Ordering::Relaxed);
+        let control = ptr::read_volatile(&(*current_half(nr, completed_halves)).control);
+        // A finished descriptor has its cycle type cleared by the controller
+        let written = if control & CYCLE_CTRL_MASK == 0 {
+            half
+        } else {
+            half - (((control & N_MINUS_1_MASK) >> N_MINUS_1_SHIFT) as usize + 1)
+        };
+        completed_halves * half + written
+    })
+}
+
+/// Clears the `DMA_IF` flags and re-arms the channels used for long or circular transfers. This
+/// must be called from the `DMA` interrupt.
+pub fn handle_interrupt() {
+    let dma = unsafe { &*efm32hg309f64::DMA::ptr() };
+    let flags = dma.if_.read().bits();
+    dma.ifc.write(|w| unsafe { w.bits(flags) });
+
+    for nr in 0..CHANNELS {
+        if flags & (1 << nr) == 0 {
+            continue;
+        }
+
+        let half = HALF_LENGTH[nr].load(Ordering::Relaxed);
+        if half != 0 {
+            let completed_halves = COMPLETED_HALVES[nr].load(Ordering::Relaxed);
+            let dst = NEXT_ADDRESS[nr].load(Ordering::Relaxed) + (completed_halves % 2) * half;
+            unsafe {
+                let descriptor = current_half(nr, completed_halves);
+                let src = ptr::read_volatile(&(*descriptor).src_end) as usize;
+                arm_ping_pong(descriptor, src, dst, half);
+            }
+            COMPLETED_HALVES[nr].store(completed_halves + 1, Ordering::Relaxed);
+            continue;
+        }
+
+        let remaining = REMAINING[nr].load(Ordering::Relaxed);
+        if remaining != 0 {
+            let src = NEXT_ADDRESS[nr].load(Ordering::Relaxed);
+            let length = remaining.min(MAX_CYCLE_LENGTH);
+            unsafe {
+                let dst = ptr::read_volatile(&(*primary(nr)).dst_end) as usize;
+                arm_basic(nr, src, dst, length);
+            }
+            NEXT_ADDRESS[nr].store(src + length, Ordering::Relaxed);
+            REMAINING[nr].store(remaining - length, Ordering::Release);
+            enable_channel(nr);
+        }
+    }
+}
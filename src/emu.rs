@@ -0,0 +1,47 @@
+//! This module contains functions for putting the CPU into the low energy modes described in
+//! chapter 10 of EFM32HG-RM.pdf.
+//!
+//! Only EM2 (Deep Sleep) is supported. In EM2 all high-frequency clocks are stopped, so only
+//! peripherals running from a clock implementing `cmu::Em2Clock` can wake the CPU again. When
+//! waking up, the `HFRCO` is restarted with the band it had before going to sleep.
+
+use core::mem;
+use cortex_m;
+use efm32hg309f64;
+
+// The SEVONPEND bit in SCB_SCR, which turns every pending interrupt into a wake-up event for
+// `wfe`, even if the interrupt is disabled in the NVIC.
+const SCR_SEVONPEND: u32 = 1 << 4;
+
+/// Puts the CPU into EM2 until `done` returns true.
+///
+/// The CPU is woken up when `interrupt` becomes pending, regardless of whether it is enabled in
+/// the NVIC. If it is not enabled, it is unpended again after waking up, so it can wake the CPU
+/// once more. It is the responsibility of the caller to make sure that the interrupt will
+/// eventually be raised, and that the peripheral raising it runs from a clock that stays alive in
+/// EM2.
+pub(crate) fn sleep_em2_until<F: FnMut() -> bool>(
+    interrupt: efm32hg309f64::Interrupt,
+    mut done: F,
+) {
+    let mut scb = unsafe { mem::transmute::<(), cortex_m::peripheral::SCB>(()) };
+    let mut nvic = unsafe { mem::transmute::<(), efm32hg309f64::NVIC>(()) };
+    unsafe {
+        scb.scr.modify(|scr| scr | SCR_SEVONPEND);
+    }
+    scb.set_sleepdeep();
+
+    while !done() {
+        cortex_m::asm::wfe();
+        // An enabled interrupt is left pending for its handler, which runs as soon as the
+        // interrupt is no longer masked
+        if !efm32hg309f64::NVIC::is_enabled(interrupt) {
+            nvic.clear_pending(interrupt);
+        }
+    }
+
+    scb.clear_sleepdeep();
+    unsafe {
+        scb.scr.modify(|scr| scr & !SCR_SEVONPEND);
+    }
+}
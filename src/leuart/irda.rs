@@ -0,0 +1,119 @@
+//! The pulse generator of the `LEUART0`, which turns every zero bit on the line into a short high
+//! pulse as used by IrDA transceivers. See section 17.3.8 in EFM32HG-RM.pdf.
+
+use super::{ClockOn, Error, Leuart, RxOn, TxOn};
+use clock_math::{error, recalculated_baudrate};
+use cmu;
+use core::fmt;
+use efm32hg309f64;
+use embedded_hal::blocking;
+use embedded_hal::serial;
+use nb;
+
+/// A `Leuart` with the pulse generator enabled. It has the same API for reading and writing as a
+/// `Leuart` in normal mode.
+pub struct IrdaLeuart<'devices, Location, TxMode, RxMode> {
+    leuart: Leuart<'devices, Location, TxMode, RxMode, ClockOn>,
+}
+
+impl<'devices, Location, TxMode, RxMode> Leuart<'devices, Location, TxMode, RxMode, ClockOn> {
+    /// Enables the pulse generator by setting the `PULSEEN` bit and the `PULSEW` subfield in
+    /// `LEUART_PULSECTRL`. The `width` is the length of the pulses in seconds.
+    ///
+    /// The pulse generator counts in cycles of the clock driving the `LEUART0`, so the width
+    /// must round to between 1 and 16 of those cycles, and must be shorter than a bit at the
+    /// currently configured baud rate.
+    ///
+    /// This function will not write to `LEUART_PULSECTRL` until the relevant bit in
+    /// `LEUART_SYNCBUSY` is clear.
+    #[cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
+    pub fn pulse_mode<InnerSource, InnerDiv>(
+        self,
+        clk: &'devices cmu::lfb::LfbClkLeuart0<'devices, InnerSource, InnerDiv>,
+        width: f64,
+    ) -> IrdaLeuart<'devices, Location, TxMode, RxMode>
+    where
+        cmu::lfb::LfbClkLeuart0<'devices, InnerSource, InnerDiv>: cmu::Clock,
+    {
+        use cmu::Clock;
+        let _ = clk;
+        let frequency = cmu::lfb::LfbClkLeuart0::<InnerSource, InnerDiv>::FREQUENCY;
+        let cycles = (width * frequency + 0.5) as u32;
+        assert!(cycles >= 1 && cycles <= 16);
+        debug_assert!(error(width, cycles as f64 / frequency) < 0.25);
+
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        let baudrate = recalculated_baudrate(frequency, regs.clkdiv.read().div().bits());
+        assert!((cycles as f64) / frequency < 1.0 / baudrate);
+
+        while regs.syncbusy.read().pulsectrl().bit_is_set() {}
+        regs.pulsectrl.write(|w| unsafe {
+            w.pulseen().set_bit().pulsew().bits((cycles - 1) as u8)
+        });
+        while regs.syncbusy.read().pulsectrl().bit_is_set() {}
+
+        IrdaLeuart { leuart: self }
+    }
+}
+
+impl<'devices, Location, TxMode, RxMode> IrdaLeuart<'devices, Location, TxMode, RxMode> {
+    /// Disables the pulse generator by clearing `LEUART_PULSECTRL`.
+    ///
+    /// This function will not write to `LEUART_PULSECTRL` until the relevant bit in
+    /// `LEUART_SYNCBUSY` is clear.
+    pub fn normal_mode(self) -> Leuart<'devices, Location, TxMode, RxMode, ClockOn> {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        while regs.syncbusy.read().pulsectrl().bit_is_set() {}
+        regs.pulsectrl.reset();
+        while regs.syncbusy.read().pulsectrl().bit_is_set() {}
+        self.leuart
+    }
+}
+
+impl<'devices, Location, RxMode> IrdaLeuart<'devices, Location, TxOn, RxMode> {
+    #[inline]
+    pub fn write_blocking(&mut self, bytes: &[u8]) {
+        self.leuart.write_blocking(bytes)
+    }
+}
+
+impl<'devices, Location, TxMode> IrdaLeuart<'devices, Location, TxMode, RxOn> {
+    #[inline]
+    pub unsafe fn read_blocking(&mut self, buf: &mut [u8]) {
+        self.leuart.read_blocking(buf)
+    }
+}
+
+impl<'devices, Location, RxMode> fmt::Write for IrdaLeuart<'devices, Location, TxOn, RxMode> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        fmt::Write::write_str(&mut self.leuart, s)
+    }
+}
+
+impl<'devices, Location, RxMode> serial::Write<u8> for IrdaLeuart<'devices, Location, TxOn, RxMode> {
+    type Error = Error;
+
+    #[inline]
+    fn write(&mut self, word: u8) -> nb::Result<(), Error> {
+        serial::Write::<u8>::write(&mut self.leuart, word)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        serial::Write::<u8>::flush(&mut self.leuart)
+    }
+}
+
+impl<'devices, Location, RxMode> blocking::serial::write::Default<u8>
+    for IrdaLeuart<'devices, Location, TxOn, RxMode>
+{
+}
+
+impl<'devices, Location, TxMode> serial::Read<u8> for IrdaLeuart<'devices, Location, TxMode, RxOn> {
+    type Error = Error;
+
+    #[inline]
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        serial::Read::<u8>::read(&mut self.leuart)
+    }
+}
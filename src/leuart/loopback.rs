@@ -0,0 +1,100 @@
+//! The loopback mode of the `LEUART0`, where the receiver is connected to the transmitter
+//! internally. This makes it possible to check that the `LEUART0` works without any external
+//! wiring. See section 17.3.4 in EFM32HG-RM.pdf.
+
+use super::{ClockOn, Leuart, RxLoopback, RxOff, TxOn};
+use efm32hg309f64;
+
+/// The pattern sent by `self_test` if nothing else is needed. It covers all-zero and all-one
+/// bytes, alternating bits and a walking one.
+pub const SELF_TEST_PATTERN: [u8; 12] = [
+    0x00, 0xff, 0x55, 0xaa, 0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80,
+];
+
+/// A byte which did not come back as it was sent during `self_test`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The position of the byte in the pattern.
+    pub index: usize,
+    pub sent: u8,
+    /// The byte which was received instead, or `None` if nothing was received or it was received
+    /// with a framing or parity error.
+    pub received: Option<u8>,
+}
+
+impl<'devices, Location> Leuart<'devices, Location, TxOn, RxOff, ClockOn> {
+    /// Connects the receiver to the transmitter by setting the `LOOPBK` bit in `LEUART_CTRL`, and
+    /// enables the receiver by setting the `RXEN` bit in `LEUART_CMD`. The transmitter keeps
+    /// driving the TX pin, but the RX pin is not used.
+    ///
+    /// This function will not write to `LEUART_CTRL` or `LEUART_CMD` until the relevant bits in
+    /// `LEUART_SYNCBUSY` are clear.
+    pub fn loopback(self) -> Leuart<'devices, Location, TxOn, RxLoopback, ClockOn> {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        while regs.syncbusy.read().ctrl().bit_is_set() {}
+        regs.ctrl.modify(|_, w| w.loopbk().set_bit());
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+        regs.cmd.write(|w| w.rxen().set_bit().clearrx().set_bit());
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+
+        unsafe { self.transmute_mode() }
+    }
+}
+
+impl<'devices, Location> Leuart<'devices, Location, TxOn, RxLoopback, ClockOn> {
+    /// Disables the receiver and disconnects it from the transmitter again, by setting the `RXDIS`
+    /// bit in `LEUART_CMD` and clearing the `LOOPBK` bit in `LEUART_CTRL`.
+    ///
+    /// This function will not write to `LEUART_CMD` or `LEUART_CTRL` until the relevant bits in
+    /// `LEUART_SYNCBUSY` are clear.
+    pub fn normal_mode(self) -> Leuart<'devices, Location, TxOn, RxOff, ClockOn> {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+        regs.cmd.write(|w| w.rxdis().set_bit().clearrx().set_bit());
+        while regs.syncbusy.read().ctrl().bit_is_set() {}
+        regs.ctrl.modify(|_, w| w.loopbk().clear_bit());
+        while regs.syncbusy.read().ctrl().bit_is_set() {}
+
+        unsafe { self.transmute_mode() }
+    }
+
+    /// Sends every byte of `pattern` and checks that it is echoed back, calling `mismatch` for
+    /// every byte which was not. Returns `true` if all bytes came back as they were sent.
+    ///
+    /// The bytes are sent one at a time, waiting for the `TXC` bit in `LEUART_STATUS` in between,
+    /// at which point the stop bit has been sampled by the receiver as well.
+    pub fn self_test<F: FnMut(Mismatch)>(&mut self, pattern: &[u8], mut mismatch: F) -> bool {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+        regs.cmd.write(|w| w.clearrx().set_bit());
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+        regs.ifc.write(|w| w.rxof().set_bit());
+
+        let mut passed = true;
+        for (index, &sent) in pattern.iter().enumerate() {
+            regs.txdata.write(|w| unsafe { w.txdata().bits(sent) });
+            while regs.status.read().txc().bit_is_clear() {}
+
+            let received = if regs.status.read().rxdatav().bit_is_set() {
+                let rxdatax = regs.rxdatax.read();
+                if rxdatax.ferr().bit_is_set() || rxdatax.perr().bit_is_set() {
+                    None
+                } else {
+                    Some(rxdatax.rxdata().bits() as u8)
+                }
+            } else {
+                None
+            };
+
+            if received != Some(sent) {
+                passed = false;
+                mismatch(Mismatch {
+                    index,
+                    sent,
+                    received,
+                });
+            }
+        }
+        passed
+    }
+}
@@ -0,0 +1,1013 @@
+// use cmu::Clock;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr;
+use clock_math::{error, recalculated_baudrate, scaling_factor};
+use cmu;
+use cortex_m;
+use dma;
+use efm32hg309f64;
+use emu;
+use embedded_hal::blocking;
+use embedded_hal::serial;
+use nb;
+
+use gpio::*;
+
+mod irda;
+pub use self::irda::IrdaLeuart;
+mod loopback;
+pub use self::loopback::{Mismatch, SELF_TEST_PATTERN};
+
+// use heapless::consts::*;
+// use heapless::RingBuffer;
+// use typenum;
+
+// static mut LEUART_RXBUF: RingBuffer<u8, U256, u8> = RingBuffer::u8();
+// static mut LEUART_TXBUF: RingBuffer<u8, U256, u8> = RingBuffer::u8();
+
+pub trait LocationPins {
+    type TxPin;
+    type RxPin;
+
+    /// Reads the current level of the RX pin from the `DIN` register of its GPIO port, bypassing
+    /// the receiver.
+    fn rx_is_high() -> bool;
+}
+
+macro_rules! location {
+    ($location_id:ident, $tx_pin:ident, $rx_pin:ident) => {
+        pub struct $location_id;
+        impl LocationPins for $location_id {
+            type TxPin = $tx_pin<
+                pin_modes::PinMode<
+                    pin_modes::input_modes::Enabled,
+                    pin_modes::output_modes::PushPull,
+                >,
+            >;
+            type RxPin = $rx_pin<
+                pin_modes::PinMode<
+                    pin_modes::input_modes::Enabled,
+                    pin_modes::output_modes::PushPull,
+                >,
+            >;
+
+            #[inline]
+            fn rx_is_high() -> bool {
+                use embedded_hal::digital::InputPin;
+                // The pin types are zero-sized, and reading the input does not change the state
+                // of the pin, so the one borrowed by `enable_rx` is not disturbed.
+                let rx_pin = unsafe { mem::transmute::<(), Self::RxPin>(()) };
+                rx_pin.is_high()
+            }
+        }
+    };
+}
+
+// The pins for location 0 (PD4/PD5) and location 2 (PE14/PE15) are not bonded out on the QFN24
+// package, so there is nothing to route to. Location 0 is still the reset value of
+// `LEUART_ROUTE`, which is why the type exists. Location 5 (PC14/PC15) is left out as well, as
+// those are the USB D- and D+ pins.
+pub struct Location0;
+// Location 1 TX==PB13, RX==PB14. See EFM32HF309 Datasheet section 4.2
+location!(Location1, Pb13, Pb14);
+// Location 3 TX==PF0, RX==PF1. See EFM32HF309 Datasheet section 4.2
+location!(Location3, Pf0, Pf1);
+// Location 4 TX==PF2, RX==PA0. See EFM32HF309 Datasheet section 4.2
+location!(Location4, Pf2, Pa0);
+
+pub mod frame_formats {
+    pub mod data_bits {
+        pub struct Eight;
+        pub struct Nine;
+    }
+
+    pub mod parities {
+        pub struct None;
+        pub struct Even;
+        pub struct Odd;
+    }
+
+    pub mod stop_bits {
+        pub struct One;
+        pub struct Two;
+    }
+
+    pub mod polarities {
+        pub struct Normal;
+        pub struct Inverted;
+    }
+}
+
+use self::frame_formats::{data_bits, parities, polarities, stop_bits};
+
+/// The format of the frames sent and received by the `LEUART0`. A new `FrameFormat` is 8N1 with
+/// normal polarity, which is also the reset state of `LEUART_CTRL`.
+///
+/// Frames are always sent least significant bit first, the `LEUART0` does not support anything
+/// else.
+pub struct FrameFormat<DataBits, Parity, StopBits, Polarity> {
+    data_bits: PhantomData<DataBits>,
+    parity: PhantomData<Parity>,
+    stop_bits: PhantomData<StopBits>,
+    polarity: PhantomData<Polarity>,
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(new_without_default_derive))]
+impl FrameFormat<data_bits::Eight, parities::None, stop_bits::One, polarities::Normal> {
+    #[inline]
+    pub fn new() -> Self {
+        FrameFormat {
+            data_bits: PhantomData,
+            parity: PhantomData,
+            stop_bits: PhantomData,
+            polarity: PhantomData,
+        }
+    }
+}
+
+impl<DataBits, Parity, StopBits, Polarity> FrameFormat<DataBits, Parity, StopBits, Polarity> {
+    #[inline]
+    fn transmute<NewDataBits, NewParity, NewStopBits, NewPolarity>(
+        self,
+    ) -> FrameFormat<NewDataBits, NewParity, NewStopBits, NewPolarity> {
+        let _ = self;
+        FrameFormat {
+            data_bits: PhantomData,
+            parity: PhantomData,
+            stop_bits: PhantomData,
+            polarity: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn data_bits_8(self) -> FrameFormat<data_bits::Eight, Parity, StopBits, Polarity> {
+        self.transmute()
+    }
+
+    #[inline]
+    pub fn data_bits_9(self) -> FrameFormat<data_bits::Nine, Parity, StopBits, Polarity> {
+        self.transmute()
+    }
+
+    #[inline]
+    pub fn no_parity(self) -> FrameFormat<DataBits, parities::None, StopBits, Polarity> {
+        self.transmute()
+    }
+
+    #[inline]
+    pub fn even_parity(self) -> FrameFormat<DataBits, parities::Even, StopBits, Polarity> {
+        self.transmute()
+    }
+
+    #[inline]
+    pub fn odd_parity(self) -> FrameFormat<DataBits, parities::Odd, StopBits, Polarity> {
+        self.transmute()
+    }
+
+    #[inline]
+    pub fn stop_bits_1(self) -> FrameFormat<DataBits, Parity, stop_bits::One, Polarity> {
+        self.transmute()
+    }
+
+    #[inline]
+    pub fn stop_bits_2(self) -> FrameFormat<DataBits, Parity, stop_bits::Two, Polarity> {
+        self.transmute()
+    }
+
+    #[inline]
+    pub fn normal_polarity(self) -> FrameFormat<DataBits, Parity, StopBits, polarities::Normal> {
+        self.transmute()
+    }
+
+    #[inline]
+    pub fn inverted_polarity(
+        self,
+    ) -> FrameFormat<DataBits, Parity, StopBits, polarities::Inverted> {
+        self.transmute()
+    }
+}
+
+pub trait ValidFrameFormat {
+    const NINE_DATA_BITS: bool;
+    /// The value of the `PARITY` subfield in `LEUART_CTRL`.
+    const PARITY: u8;
+    const TWO_STOP_BITS: bool;
+    const INVERTED: bool;
+}
+
+macro_rules! frame_format_field {
+    ($field:ident $konst:ident $typ:ty, $($module:ident :: $name:ident => $val:expr),*) => {
+        pub trait $field {
+            const $konst: $typ;
+        }
+        $(
+            impl $field for $module::$name {
+                const $konst: $typ = $val;
+            }
+        )*
+    };
+}
+
+frame_format_field!(DataBitsField NINE_DATA_BITS bool,
+                    data_bits::Eight => false,
+                    data_bits::Nine => true);
+frame_format_field!(ParityField PARITY u8,
+                    parities::None => 0b00,
+                    parities::Even => 0b10,
+                    parities::Odd => 0b11);
+frame_format_field!(StopBitsField TWO_STOP_BITS bool,
+                    stop_bits::One => false,
+                    stop_bits::Two => true);
+frame_format_field!(PolarityField INVERTED bool,
+                    polarities::Normal => false,
+                    polarities::Inverted => true);
+
+impl<DataBits, Parity, StopBits, Polarity> ValidFrameFormat
+    for FrameFormat<DataBits, Parity, StopBits, Polarity>
+where
+    DataBits: DataBitsField,
+    Parity: ParityField,
+    StopBits: StopBitsField,
+    Polarity: PolarityField,
+{
+    const NINE_DATA_BITS: bool = DataBits::NINE_DATA_BITS;
+    const PARITY: u8 = Parity::PARITY;
+    const TWO_STOP_BITS: bool = StopBits::TWO_STOP_BITS;
+    const INVERTED: bool = Polarity::INVERTED;
+}
+
+pub struct TxOn;
+pub struct TxOff;
+
+pub struct RxOn;
+pub struct RxOff;
+pub struct RxLoopback;
+
+pub struct ClockOff;
+pub struct ClockOn;
+
+pub struct Leuart<'devices, Location, TxMode, RxMode, ClockState> {
+    location: PhantomData<Location>,
+    tx_mode: PhantomData<TxMode>,
+    rx_mode: PhantomData<RxMode>,
+    clock_state: PhantomData<ClockState>,
+    devices: PhantomData<&'devices ()>,
+    non_send: PhantomData<*mut ()>,
+}
+
+impl<'devices, Location, TxMode, RxMode, ClockState>
+    Leuart<'devices, Location, TxMode, RxMode, ClockState>
+{
+    #[inline]
+    unsafe fn transmute_mode<'new_devices, NewLocation, NewTxMode, NewRxMode, NewClockState>(
+        self,
+    ) -> Leuart<'new_devices, NewLocation, NewTxMode, NewRxMode, NewClockState> {
+        mem::forget(self);
+        Leuart {
+            location: PhantomData,
+            tx_mode: PhantomData,
+            rx_mode: PhantomData,
+            clock_state: PhantomData,
+            devices: PhantomData,
+            non_send: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub unsafe fn claim_ownership() -> Self {
+        Leuart {
+            location: PhantomData,
+            tx_mode: PhantomData,
+            rx_mode: PhantomData,
+            clock_state: PhantomData,
+            devices: PhantomData,
+            non_send: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn baudrate<InnerSource, InnerDiv>(
+        self,
+        clk: &'devices cmu::lfb::LfbClkLeuart0<'devices, InnerSource, InnerDiv>,
+        baudrate: f64,
+    ) -> Leuart<'devices, Location, TxMode, RxMode, ClockOn>
+    where
+        cmu::lfb::LfbClkLeuart0<'devices, InnerSource, InnerDiv>: cmu::Clock,
+    {
+        use cmu::Clock;
+        let _ = clk;
+        let frequency = cmu::lfb::LfbClkLeuart0::<InnerSource, InnerDiv>::FREQUENCY;
+        let scaling_factor = scaling_factor(frequency, baudrate);
+        let recalculated_baudrate = recalculated_baudrate(frequency, scaling_factor);
+        let error = error(baudrate, recalculated_baudrate);
+        debug_assert!(error < 0.025);
+
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        regs.clkdiv
+            .write(|w| unsafe { w.div().bits(scaling_factor) });
+        while regs.syncbusy.read().clkdiv().bit_is_set() {}
+        // The 3 low bits of `LEUART_CLKDIV` must be 0, see `clock_math::scaling_factor`
+        debug_assert_eq!(regs.clkdiv.read().bits() & 0x7, 0);
+        unsafe { self.transmute_mode() }
+    }
+
+    #[inline]
+    pub fn disable(self) -> Leuart<'static, Location, TxOff, RxOff, ClockOff> {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        regs.cmd.write(|w| {
+            w.txdis()
+                .set_bit()
+                .rxdis()
+                .set_bit()
+                .cleartx()
+                .set_bit()
+                .clearrx()
+                .set_bit()
+        });
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+
+        unsafe { self.transmute_mode() }
+    }
+}
+
+impl Leuart<'static, Location0, TxOff, RxOff, ClockOff> {
+    #[inline]
+    pub unsafe fn get_initial_state(
+        leuart: efm32hg309f64::LEUART0,
+    ) -> Leuart<'static, Location0, TxOff, RxOff, ClockOff> {
+        let _ = leuart;
+        Leuart::claim_ownership()
+    }
+}
+
+macro_rules! location_fn {
+    ($meth:ident $location_id:ident $fun:ident) => {
+        /// Selects the pins used by the `LEUART0` by setting the `LOCATION` subfield in
+        /// `LEUART_ROUTE`.
+        #[inline]
+        pub fn $meth(self) -> Leuart<'devices, $location_id, TxMode, RxMode, ClockOn> {
+            let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+            regs.route.write(|w| w.location().$fun());
+
+            unsafe { self.transmute_mode() }
+        }
+    };
+}
+
+impl<'devices, Location, TxMode, RxMode> Leuart<'devices, Location, TxMode, RxMode, ClockOn> {
+    location_fn!(location1 Location1 loc1);
+    location_fn!(location3 Location3 loc3);
+    location_fn!(location4 Location4 loc4);
+}
+
+impl<'devices, Location> Leuart<'devices, Location, TxOff, RxOff, ClockOn> {
+    /// Sets the frame format by updating the `DATABITS`, `PARITY`, `STOPBITS` and `INV` subfields
+    /// in `LEUART_CTRL`.
+    ///
+    /// The reference manual only allows changing the frame format while both the receiver and
+    /// transmitter are disabled, which is why this is only available in that state. This function
+    /// will not write to `LEUART_CTRL` until the relevant bit in `LEUART_SYNCBUSY` is clear, and
+    /// will block until the write has been synchronized.
+    #[inline]
+    pub fn frame_format<F: ValidFrameFormat>(self, frame_format: F) -> Self {
+        let _ = frame_format;
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        while regs.syncbusy.read().ctrl().bit_is_set() {}
+        regs.ctrl.modify(|_, w| {
+            let w = match F::PARITY {
+                0b10 => w.parity().even(),
+                0b11 => w.parity().odd(),
+                _ => w.parity().none(),
+            };
+            w.databits()
+                .bit(F::NINE_DATA_BITS)
+                .stopbits()
+                .bit(F::TWO_STOP_BITS)
+                .inv()
+                .bit(F::INVERTED)
+        });
+        while regs.syncbusy.read().ctrl().bit_is_set() {}
+        self
+    }
+}
+
+impl<'devices, Location: LocationPins, TxMode, RxMode>
+    Leuart<'devices, Location, TxMode, RxMode, ClockOn>
+{
+    pub fn enable_tx(
+        self,
+        tx_pin: &'devices mut Location::TxPin,
+    ) -> Leuart<'devices, Location, TxOn, RxMode, ClockOn> {
+        let _ = tx_pin;
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        regs.route.modify(|_, w| w.txpen().set_bit());
+        regs.cmd.write(|w| w.txen().set_bit().cleartx().set_bit());
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+
+        unsafe { self.transmute_mode() }
+    }
+
+    pub fn enable_rx(
+        self,
+        rx_pin: &'devices mut Location::RxPin,
+    ) -> Leuart<'devices, Location, TxMode, RxOn, ClockOn> {
+        let _ = rx_pin;
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        regs.route.modify(|_, w| w.rxpen().set_bit());
+        regs.cmd.write(|w| w.rxen().set_bit().clearrx().set_bit());
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+
+        unsafe { self.transmute_mode() }
+    }
+}
+
+impl<'devices, Location, RxMode> Leuart<'devices, Location, TxOn, RxMode, ClockOn> {
+    pub fn write_blocking(&mut self, bytes: &[u8]) {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        for &b in bytes {
+            regs.txdata.write(|w| unsafe { w.txdata().bits(b) });
+            while regs.status.read().txc().bit_is_clear() {}
+        }
+    }
+}
+
+impl<'devices, Location, RxMode> Leuart<'devices, Location, TxOn, RxMode, ClockOn> {
+    /// Starts sending `bytes` using DMA, triggered by the `TXBL` request line of `LEUART0`.
+    ///
+    /// The `Leuart` and the channel are handed back once the transfer is done. Transfers longer
+    /// than 1024 bytes rely on `dma::handle_interrupt` being called from the `DMA` interrupt.
+    pub fn write_dma<C: dma::Channel>(
+        self,
+        mut channel: C,
+        bytes: &'static [u8],
+    ) -> TxDmaTransfer<Self, C> {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        let txdata = &regs.txdata as *const _ as *const u8;
+        dma::start_to_peripheral(&mut channel, dma::LEUART0_TXBL, bytes, txdata);
+        TxDmaTransfer {
+            leuart: self,
+            channel,
+        }
+    }
+}
+
+impl<'devices, Location: LocationPins, TxMode> Leuart<'devices, Location, TxMode, RxOn, ClockOn> {
+    /// Measures the baud rate of an incoming `0x55` sync character and updates `LEUART_CLKDIV` to
+    /// match it. Returns the detected baud rate, or `None` if no sync character was measured
+    /// within `timeout` seconds, or if the detected rate cannot be reached with less than 2.5%
+    /// error from `clk`. In both cases `LEUART_CLKDIV` is left untouched.
+    ///
+    /// The character is timed by polling the RX pin while `syst` counts `HFCORECLK` cycles, which
+    /// is why the core clock has to be given as well. Before the character, the line has to have
+    /// been idle for 10 bit periods at 9600 baud, the slowest supported rate, so the measurement
+    /// does not start in the middle of a frame. On the line `0x55` has a falling edge every 2 bit
+    /// periods, starting with the start bit. The first falling edge is waited for with interrupts
+    /// enabled, while the following 6 bit periods are timed with interrupts disabled. Each of
+    /// those edges has to arrive within 3 bit periods at 9600 baud, which bounds the time spent
+    /// with interrupts disabled to about 1 ms.
+    ///
+    /// The sync character will usually end up garbled in the receive buffer, so the receiver is
+    /// cleared by setting the `CLEARRX` bit in `LEUART_CMD` afterwards. This function will not
+    /// write to `LEUART_CLKDIV` or `LEUART_CMD` until the relevant bits in `LEUART_SYNCBUSY` are
+    /// clear.
+    pub fn auto_baud<CoreSource, CoreDivision, InnerSource, InnerDiv>(
+        &mut self,
+        syst: &mut cortex_m::peripheral::SYST,
+        hfcoreclk: &cmu::HfCoreClk<'devices, CoreSource, CoreDivision>,
+        clk: &cmu::lfb::LfbClkLeuart0<'devices, InnerSource, InnerDiv>,
+        timeout: f64,
+    ) -> Option<f64>
+    where
+        cmu::HfCoreClk<'devices, CoreSource, CoreDivision>: cmu::Clock,
+        cmu::lfb::LfbClkLeuart0<'devices, InnerSource, InnerDiv>: cmu::Clock,
+    {
+        use cmu::Clock;
+        use cortex_m::peripheral::syst::SystClkSource;
+        let _ = hfcoreclk;
+        let _ = clk;
+        let core_frequency = cmu::HfCoreClk::<CoreSource, CoreDivision>::FREQUENCY;
+        let frequency = cmu::lfb::LfbClkLeuart0::<InnerSource, InnerDiv>::FREQUENCY;
+
+        // The number of core cycles in a bit period at the slowest supported baud rate
+        let slowest_bit = core_frequency / 9600.0;
+        let idle_cycles = (10.0 * slowest_bit) as u32;
+        let edge_cycles = (3.0 * slowest_bit) as u32;
+        let timeout_cycles = (timeout * core_frequency) as u32;
+
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(0x00ff_ffff);
+        syst.clear_current();
+        syst.enable_counter();
+
+        let cycles = Self::time_sync_character(idle_cycles, edge_cycles, timeout_cycles);
+        syst.disable_counter();
+        let cycles = cycles?;
+
+        let baudrate = 6.0 * core_frequency / f64::from(cycles);
+        let scaling_factor = scaling_factor(frequency, baudrate);
+        if error(baudrate, recalculated_baudrate(frequency, scaling_factor)) >= 0.025 {
+            return None;
+        }
+
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        while regs.syncbusy.read().clkdiv().bit_is_set() {}
+        regs.clkdiv
+            .write(|w| unsafe { w.div().bits(scaling_factor) });
+        while regs.syncbusy.read().clkdiv().bit_is_set() {}
+        debug_assert_eq!(regs.clkdiv.read().bits() & 0x7, 0);
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+        regs.cmd.write(|w| w.clearrx().set_bit());
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+
+        Some(baudrate)
+    }
+
+    /// The part of `auto_baud` which watches the RX pin. Returns the number of SysTick cycles
+    /// from the second to the fifth falling edge of the sync character, or `None` if any of the
+    /// waits took too long.
+    fn time_sync_character(
+        idle_cycles: u32,
+        edge_cycles: u32,
+        timeout_cycles: u32,
+    ) -> Option<u32> {
+        let mut stopwatch = Stopwatch::start();
+
+        // Wait for the line to have been idle for long enough, so we do not start in the middle
+        // of a character
+        let mut high_since = 0;
+        loop {
+            let now = stopwatch.elapsed();
+            if now >= timeout_cycles {
+                return None;
+            }
+            if !Location::rx_is_high() {
+                high_since = now;
+            } else if now - high_since >= idle_cycles {
+                break;
+            }
+        }
+
+        // Wait for the falling edge of the start bit
+        let mut start = high_since;
+        while Location::rx_is_high() {
+            start = stopwatch.elapsed();
+            if start >= timeout_cycles {
+                return None;
+            }
+        }
+
+        cortex_m::interrupt::free(|_| {
+            // The remaining 4 falling edges, each of which must come soon after the previous one
+            let mut previous = stopwatch.elapsed();
+            let mut first = None;
+            for _ in 0..4 {
+                while !Location::rx_is_high() {
+                    if stopwatch.elapsed() - previous >= edge_cycles {
+                        return None;
+                    }
+                }
+                while Location::rx_is_high() {
+                    if stopwatch.elapsed() - previous >= edge_cycles {
+                        return None;
+                    }
+                }
+                previous = stopwatch.elapsed();
+                first = first.or(Some(previous));
+            }
+
+            // Wait for the stop bit, so the divider does not change in the middle of the character
+            while !Location::rx_is_high() {
+                if stopwatch.elapsed() - previous >= edge_cycles {
+                    return None;
+                }
+            }
+
+            // If an interrupt delayed us past the second falling edge, the first 2 bit periods
+            // appear to be much longer than the others
+            let first = first?;
+            let cycles = previous - first;
+            if 2 * (first - start) > cycles {
+                return None;
+            }
+            Some(cycles)
+        })
+    }
+}
+
+/// Counts the cycles elapsed on the SysTick, which counts down and wraps at 24 bits, so it has to
+/// be polled at least once per wrap.
+struct Stopwatch {
+    last: u32,
+    elapsed: u32,
+}
+
+impl Stopwatch {
+    #[inline]
+    fn start() -> Stopwatch {
+        Stopwatch {
+            last: cortex_m::peripheral::SYST::get_current(),
+            elapsed: 0,
+        }
+    }
+
+    /// The number of cycles since `start`, saturating instead of wrapping.
+    #[inline]
+    fn elapsed(&mut self) -> u32 {
+        let now = cortex_m::peripheral::SYST::get_current();
+        let cycles = self.last.wrapping_sub(now) & 0x00ff_ffff;
+        self.last = now;
+        self.elapsed = self.elapsed.saturating_add(cycles);
+        self.elapsed
+    }
+}
+
+impl<'devices, Location, TxMode> Leuart<'devices, Location, TxMode, RxOn, ClockOn> {
+    /// Starts receiving into `buffer` using DMA in a circle, triggered by the `RXDATAV` request
+    /// line of `LEUART0`. The length of `buffer` must be even and at most 2048 bytes.
+    ///
+    /// This relies on `dma::handle_interrupt` being called from the `DMA` interrupt, which
+    /// re-arms each half of the buffer once it has been filled.
+    pub fn read_dma_circular<C: dma::Channel>(
+        self,
+        mut channel: C,
+        buffer: &'static mut [u8],
+    ) -> CircularRxDma<Self, C> {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        let rxdata = &regs.rxdata as *const _ as *const u8;
+        unsafe {
+            dma::start_circular_from_peripheral(
+                &mut channel,
+                dma::LEUART0_RXDATAV,
+                rxdata,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+            );
+        }
+        CircularRxDma {
+            leuart: self,
+            channel,
+            buffer,
+            read: 0,
+        }
+    }
+
+    /// Blocks the receiver until `frame` has been received, by writing it to `LEUART_STARTFRAME`,
+    /// setting the `SFUBRX` bit in `LEUART_CTRL` and setting the `RXBLOCKEN` bit in `LEUART_CMD`.
+    /// The start frame itself is put in the receive buffer.
+    ///
+    /// This function will not write to any of the registers until the relevant bits in
+    /// `LEUART_SYNCBUSY` are clear.
+    pub fn block_until_start_frame(&mut self, frame: u16) {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        while regs.syncbusy.read().startframe().bit_is_set() {}
+        regs.startframe
+            .write(|w| unsafe { w.startframe().bits(frame & 0x1ff) });
+        while regs.syncbusy.read().ctrl().bit_is_set() {}
+        regs.ctrl.modify(|_, w| w.sfubrx().set_bit());
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+        regs.cmd.write(|w| w.rxblocken().set_bit());
+        while regs.syncbusy.read().cmd().bit_is_set() {}
+    }
+
+    /// Sets the frame which raises the `SIGF` flag in `LEUART_IF` when it is received, by writing
+    /// it to `LEUART_SIGFRAME`.
+    ///
+    /// This function will not write to `LEUART_SIGFRAME` until the relevant bit in
+    /// `LEUART_SYNCBUSY` is clear.
+    pub fn signal_frame(&mut self, frame: u16) {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        while regs.syncbusy.read().sigframe().bit_is_set() {}
+        regs.sigframe
+            .write(|w| unsafe { w.sigframe().bits(frame & 0x1ff) });
+        regs.ifc.write(|w| w.sigf().set_bit());
+    }
+
+    /// Puts the CPU into EM2 (Deep Sleep) until `wakeup` happens on the receiver.
+    ///
+    /// The `LEUART0` can only receive in EM2 if it is driven from a low-frequency oscillator,
+    /// which is why the clock has to be given as proof. At 32768 Hz, 9600 baud is the fastest
+    /// standard rate that can be received reliably.
+    pub fn sleep_until<Source, Division>(
+        &mut self,
+        clk: &cmu::lfb::LfbClkLeuart0<'devices, Source, Division>,
+        wakeup: Wakeup,
+    ) where
+        cmu::lfb::LfbClkLeuart0<'devices, Source, Division>: cmu::Em2Clock,
+    {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        let ien = regs.ien.read().bits();
+        self.enable_wakeup(clk, wakeup);
+        emu::sleep_em2_until(efm32hg309f64::Interrupt::LEUART0, || self.has_woken(wakeup));
+        regs.ien.write(|w| unsafe { w.bits(ien) });
+    }
+
+    /// Lets `wakeup` wake the CPU from EM2 by setting the matching bit in `LEUART_IEN`, for when
+    /// the CPU is put to sleep by something else, such as `UsbDevice::sleep_while_suspended`. The
+    /// `LEUART0` interrupt does not have to be enabled in the NVIC for this.
+    ///
+    /// The clock has to be given as proof that the `LEUART0` keeps running in EM2, just like for
+    /// `sleep_until`.
+    pub fn enable_wakeup<Source, Division>(
+        &mut self,
+        clk: &cmu::lfb::LfbClkLeuart0<'devices, Source, Division>,
+        wakeup: Wakeup,
+    ) where
+        cmu::lfb::LfbClkLeuart0<'devices, Source, Division>: cmu::Em2Clock,
+    {
+        let _ = clk;
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        match wakeup {
+            Wakeup::RxData => regs.ien.modify(|_, w| w.rxdatav().set_bit()),
+            Wakeup::StartFrame => {
+                regs.ifc.write(|w| w.startf().set_bit());
+                regs.ien.modify(|_, w| w.startf().set_bit())
+            }
+            Wakeup::SignalFrame => {
+                regs.ifc.write(|w| w.sigf().set_bit());
+                regs.ien.modify(|_, w| w.sigf().set_bit())
+            }
+        }
+    }
+
+    /// Whether `wakeup` has happened on the receiver, according to `LEUART_IF`. The `STARTF` and
+    /// `SIGF` flags are cleared when set, while `RXDATAV` stays set until the receive buffer has
+    /// been emptied.
+    pub fn has_woken(&mut self, wakeup: Wakeup) -> bool {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        let flags = regs.if_.read();
+        match wakeup {
+            Wakeup::RxData => flags.rxdatav().bit_is_set(),
+            Wakeup::StartFrame if flags.startf().bit_is_set() => {
+                regs.ifc.write(|w| w.startf().set_bit());
+                true
+            }
+            Wakeup::SignalFrame if flags.sigf().bit_is_set() => {
+                regs.ifc.write(|w| w.sigf().set_bit());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub unsafe fn read_blocking(&mut self, buf: &mut [u8]) {
+        let regs = &*efm32hg309f64::LEUART0::ptr();
+        for b in buf.iter_mut() {
+            while regs.status.read().rxdatav().bit_is_clear() {}
+            *b = regs.rxdata.read().rxdata().bits();
+        }
+    }
+}
+
+impl<'devices, Location, RxMode> fmt::Write for Leuart<'devices, Location, TxOn, RxMode, ClockOn> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_blocking(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// The events on the receiver that `Leuart::sleep_until` and `Leuart::enable_wakeup` can wait
+/// for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wakeup {
+    /// Any frame is received.
+    RxData,
+    /// The frame given to `Leuart::block_until_start_frame` is received.
+    StartFrame,
+    /// The frame given to `Leuart::signal_frame` is received.
+    SignalFrame,
+}
+
+/// A DMA transfer started by `Leuart::write_dma`.
+pub struct TxDmaTransfer<Leuart, Channel> {
+    leuart: Leuart,
+    channel: Channel,
+}
+
+impl<Leuart, Channel: dma::Channel> TxDmaTransfer<Leuart, Channel> {
+    /// Returns true once every byte has been handed to the `LEUART0`. The last byte might still be
+    /// in the process of being sent, use `flush` on the returned `Leuart` to wait for that.
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        dma::is_done(&self.channel)
+    }
+
+    /// Sleeps until the transfer is done. The `DMA` interrupt has to be enabled for the CPU to
+    /// wake up, which happens when a handler is registered for it through `nvic`.
+    #[inline]
+    pub fn wait(self) -> (Leuart, Channel) {
+        while !self.is_done() {
+            cortex_m::asm::wfi();
+        }
+        (self.leuart, self.channel)
+    }
+}
+
+/// A circular DMA transfer started by `Leuart::read_dma_circular`.
+///
+/// The transfer keeps overwriting the buffer, so data is lost if it is not read at least once
+/// every `buffer.len()` bytes. This is not detected.
+pub struct CircularRxDma<Leuart, Channel> {
+    leuart: Leuart,
+    channel: Channel,
+    buffer: &'static mut [u8],
+    read: usize,
+}
+
+impl<Leuart, Channel: dma::Channel> CircularRxDma<Leuart, Channel> {
+    /// Returns the number of bytes that have been received but not yet read.
+    #[inline]
+    pub fn available(&self) -> usize {
+        dma::circular_position(&self.channel).wrapping_sub(self.read)
+    }
+
+    /// Copies as many unread bytes as possible into `buf` and returns how many there were.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = self.available().min(buf.len());
+        for b in buf[..count].iter_mut() {
+            let index = self.read % self.buffer.len();
+            *b = unsafe { ptr::read_volatile(self.buffer.as_ptr().add(index)) };
+            self.read = self.read.wrapping_add(1);
+        }
+        count
+    }
+
+    /// Stops the transfer and gives back the `Leuart`, the channel and the buffer.
+    #[inline]
+    pub fn stop(mut self) -> (Leuart, Channel, &'static mut [u8]) {
+        dma::stop(&mut self.channel);
+        (self.leuart, self.channel, self.buffer)
+    }
+}
+
+/// Errors that can be reported while receiving.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The stop bit of the received frame was not high.
+    Framing,
+    /// The parity bit of the received frame did not match the data.
+    Parity,
+    /// A frame was received while the receive buffer was full, so it was lost.
+    Overrun,
+}
+
+impl<'devices, Location, RxMode> serial::Write<u8>
+    for Leuart<'devices, Location, TxOn, RxMode, ClockOn>
+{
+    /// Transmitting never fails, this is only here to satisfy the trait.
+    type Error = Error;
+
+    /// Puts a byte in the transmit buffer if the `TXBL` bit in `LEUART_STATUS` says that there is
+    /// room for it.
+    #[inline]
+    fn write(&mut self, word: u8) -> nb::Result<(), Error> {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        if regs.status.read().txbl().bit_is_clear() {
+            return Err(nb::Error::WouldBlock);
+        }
+        regs.txdata.write(|w| unsafe { w.txdata().bits(word) });
+        Ok(())
+    }
+
+    /// Waits for the `TXC` bit in `LEUART_STATUS`, which is set when both the transmit buffer and
+    /// the shift register are empty.
+    #[inline]
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        if regs.status.read().txc().bit_is_clear() {
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(())
+    }
+}
+
+impl<'devices, Location, RxMode> blocking::serial::write::Default<u8>
+    for Leuart<'devices, Location, TxOn, RxMode, ClockOn>
+{
+}
+
+impl<'devices, Location, RxMode> serial::Write<u16>
+    for Leuart<'devices, Location, TxOn, RxMode, ClockOn>
+{
+    type Error = Error;
+
+    /// Like the `u8` version, but writes through `LEUART_TXDATAX` so the ninth data bit is sent
+    /// as well. The ninth bit is ignored unless the frame format has nine data bits.
+    #[inline]
+    fn write(&mut self, word: u16) -> nb::Result<(), Error> {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        if regs.status.read().txbl().bit_is_clear() {
+            return Err(nb::Error::WouldBlock);
+        }
+        regs.txdatax
+            .write(|w| unsafe { w.txdatax().bits(word & 0x1ff) });
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> nb::Result<(), Error> {
+        serial::Write::<u8>::flush(self)
+    }
+}
+
+impl<'devices, Location, RxMode> blocking::serial::write::Default<u16>
+    for Leuart<'devices, Location, TxOn, RxMode, ClockOn>
+{
+}
+
+impl<'devices, Location, TxMode> Leuart<'devices, Location, TxMode, RxOn, ClockOn> {
+    /// Takes a frame from the receive buffer if the `RXDATAV` bit in `LEUART_STATUS` is set,
+    /// including the ninth data bit of frame formats with nine data bits.
+    ///
+    /// The frame is read through `LEUART_RXDATAX`, so the framing and parity error flags belonging
+    /// to it can be checked. An overflow reported through `RXOF` in `LEUART_IF` is cleared and
+    /// returned before the buffered frame is looked at.
+    ///
+    /// This is not an implementation of `serial::Read<u16>`, as having both that and
+    /// `serial::Read<u8>` would make every `read()` call ambiguous.
+    #[inline]
+    pub fn read_9_bits(&mut self) -> nb::Result<u16, Error> {
+        let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+        if regs.if_.read().rxof().bit_is_set() {
+            regs.ifc.write(|w| w.rxof().set_bit());
+            return Err(nb::Error::Other(Error::Overrun));
+        }
+        if regs.status.read().rxdatav().bit_is_clear() {
+            return Err(nb::Error::WouldBlock);
+        }
+        let rxdatax = regs.rxdatax.read();
+        if rxdatax.ferr().bit_is_set() {
+            Err(nb::Error::Other(Error::Framing))
+        } else if rxdatax.perr().bit_is_set() {
+            Err(nb::Error::Other(Error::Parity))
+        } else {
+            Ok(rxdatax.rxdata().bits())
+        }
+    }
+}
+
+impl<'devices, Location, TxMode> serial::Read<u8>
+    for Leuart<'devices, Location, TxMode, RxOn, ClockOn>
+{
+    type Error = Error;
+
+    /// Takes a byte from the receive buffer. If the frame format has nine data bits, the ninth bit
+    /// is dropped, use `read_9_bits` to keep it.
+    #[inline]
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        self.read_9_bits().map(|word| word as u8)
+    }
+}
+
+//     pub fn write(&self, buf: &[u8]) {
+//         let tx_producer = unsafe { &mut LEUART_TXBUF };
+//         let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+
+//         for byte in buf {
+//             while tx_producer.enqueue(*byte).is_err() {
+//                 cortex_m::asm::wfe()
+//             }
+//         }
+//         cortex_m::interrupt::free(|_| {
+//             regs.ien.modify(|_, w| w.txbl().set_bit());
+//             regs.cmd.write(|w| w.txen().set_bit());
+//         });
+//     }
+
+//     pub fn read(&self, buf: &mut [u8]) {
+//         let rx_consumer = unsafe { &mut LEUART_RXBUF };
+//         for ptr in buf.iter_mut() {
+//             loop {
+//                 match rx_consumer.dequeue() {
+//                     Some(byte) => {
+//                         *ptr = byte;
+//                         break;
+//                     }
+//                     None => cortex_m::asm::wfi(),
+//                 }
+//             }
+//         }
+//     }
+// }
+
+// interrupt!(LEUART0, leuart0_handler);
+// fn leuart0_handler() {
+//     let regs = unsafe { &*efm32hg309f64::LEUART0::ptr() };
+//     let leuart_if: efm32hg309f64::leuart0::if_::R = regs.if_.read();
+
+//     if leuart_if.txbl().bit_is_set() {
+//         let tx_consumer = unsafe { &mut LEUART_TXBUF };
+//         match tx_consumer.dequeue() {
+//             Some(byte) => regs.txdata.write(|w| unsafe { w.txdata().bits(byte) }),
+//             None => regs.ien.modify(|_, w| w.txbl().clear_bit()),
+//         }
+//     }
+
+//     if leuart_if.rxdatav().bit_is_set() {
+//         let rx_producer = unsafe { &mut LEUART_RXBUF };
+//         let byte = regs.rxdata.read().rxdata().bits();
+//         rx_producer.enqueue(byte).ok();
+//         cortex_m::asm::sev();
+//     }
+// }
@@ -1,46 +1,94 @@
-#![feature(panic_implementation)]
-#![no_main]
-#![no_std]
+// The firmware itself only builds for the microcontroller, but the platform-independent parts
+// (currently `clock_math` and parts of `msc` and `usb`) can be unit tested on the host using:
+//
+//     cargo test
 
+// `clock_math::divided_frequency` is used for the `Clock::FREQUENCY` constants
+#![feature(const_fn)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(not(test))]
 extern crate cortex_m;
+#[cfg(not(test))]
 extern crate embedded_hal;
 
+#[cfg(not(test))]
 #[macro_use(entry, exception)]
 extern crate cortex_m_rt as rt;
 
+#[cfg(not(test))]
 #[macro_use]
 extern crate static_assertions;
 
+#[cfg(not(test))]
 #[macro_use(interrupt)]
 extern crate efm32hg309f64;
 
+#[cfg(not(test))]
 extern crate heapless;
 
+#[cfg(not(test))]
+extern crate nb;
+
+#[cfg(not(test))]
 extern crate typenum;
 
+#[cfg(all(not(test), feature = "usb-device"))]
+extern crate usb_device;
+
+// The host tests link `std`, while the modules shared with the firmware use `core`
+#[cfg(test)]
+extern crate core;
+
+mod clock_math;
+
+#[cfg(not(test))]
 pub mod cmu;
+#[cfg(not(test))]
 pub mod consts;
+#[cfg(not(test))]
 pub mod device_information;
+#[cfg(not(test))]
 pub mod devices;
+#[cfg(not(test))]
+pub mod dma;
+#[cfg(not(test))]
+pub mod emu;
+#[cfg(not(test))]
 pub mod gpio;
+#[cfg(not(test))]
 pub mod leuart;
+pub mod msc;
+#[cfg(not(test))]
 pub mod nvic;
+#[cfg(not(test))]
 pub mod panic;
+#[cfg(not(test))]
+pub mod toboot;
 pub mod usb;
 
+#[cfg(not(test))]
 use devices::StaticDevice;
+
 // use gpio::*;
 // use leuart::*;
 // use embedded_hal::digital::OutputPin;
 
+#[cfg(not(test))]
 fn init_wdog(wdog: &efm32hg309f64::wdog::RegisterBlock) {
     // Disable the watchdog
     wdog.ctrl.reset();
 }
 
+#[cfg(not(test))]
 fn init_cmu(
     cmu: cmu::InitialCmuState,
-) -> &'static cmu::lfb::LfbClkLeuart0<'static, impl cmu::Clock, typenum::consts::U8> {
+) -> (
+    &'static cmu::lfb::LfbClkLeuart0<'static, impl cmu::Em2Clock, typenum::consts::U1>,
+    &'static cmu::HfCoreClkUsb<'static, impl cmu::Clock>,
+    &'static cmu::HfCoreClkUsbC<'static, impl cmu::Clock>,
+) {
     // Initialize source clocks
     let lfrco = cmu.lfrco.enable_32768hz().finalize();
     let hfrco = cmu.hfrco.enable_21mhz().finalize();
@@ -49,28 +97,31 @@ fn init_cmu(
     // Initialize the main clocks
     let hfclk = cmu.hfclk.div1().hfrco(hfrco).finalize();
     let hfcoreclk = cmu.hfcoreclk.div1(hfclk).finalize();
-    let hfcoreclklediv = cmu.hfcoreclklediv.enable_div4(hfcoreclk).finalize();
+    // The LEUART runs from the LFRCO, but the HFCORECLKLE is still needed to access the registers
+    // of the low energy peripherals
+    let _hfcoreclklediv = cmu.hfcoreclklediv.enable_div4(hfcoreclk).finalize();
     let hfperclk = cmu.hfperclk.enable_div1(hfclk).finalize();
 
     // Initialize the three low-frequency clocks
     let lfa = cmu.lfaclk.enable_lfrco(lfrco).finalize();
-    let lfb = cmu.lfbclk.enable_hfcoreclklediv(hfcoreclklediv).finalize();
+    let lfb = cmu.lfbclk.enable_lfrco(lfrco).finalize();
     let lfc = cmu.lfcclk.enable_lfrco(lfrco).finalize();
 
     // Initialize the usb clocks
-    let _usb = cmu.hfcoreclkusb.enable(hfcoreclk).finalize();
-    let _usbc = cmu.hfcoreclkusbc.enable_ushfrco(ushfrco).finalize();
+    let usb = cmu.hfcoreclkusb.enable(hfcoreclk).finalize();
+    let usbc = cmu.hfcoreclkusbc.enable_ushfrco(ushfrco).finalize();
     let _usble = cmu.lfcclkusble.enable(lfc).finalize();
 
     // Initialize peripherals
     let _gpio = cmu.hfperclkgpio.enable(hfperclk).finalize();
     let _dma = cmu.hfcoreclkdma.enable(hfcoreclk).finalize();
     let _rtc = cmu.lfaclkrtc.enable_div1(lfa).finalize();
-    let leuart = cmu.lfbclkleuart0.enable_div8(lfb).finalize();
+    let leuart = cmu.lfbclkleuart0.enable_div1(lfb).finalize();
 
-    leuart
+    (leuart, usb, usbc)
 }
 
+#[cfg(not(test))]
 fn init_rtc(ms: u32, rtc: &efm32hg309f64::rtc::RegisterBlock) {
     // Set the rtc compare value
     let ticks_per_1000ms = 32768;
@@ -85,7 +136,14 @@ fn init_rtc(ms: u32, rtc: &efm32hg309f64::rtc::RegisterBlock) {
     rtc.ien.write(|w| w.comp0().set_bit());
 }
 
+// The configuration descriptor of the virtual serial port, which is built at startup
+#[cfg(not(test))]
+static mut USB_CONFIGURATION: [u8; usb::cdc::CONFIGURATION_LENGTH] =
+    [0; usb::cdc::CONFIGURATION_LENGTH];
+
+#[cfg(not(test))]
 entry!(main);
+#[cfg(not(test))]
 #[inline]
 fn main() -> ! {
     let ep = efm32hg309f64::Peripherals::take().unwrap();
@@ -94,7 +152,7 @@ fn main() -> ! {
     init_wdog(&ep.WDOG);
 
     let cmu = unsafe { cmu::InitialCmuState::get_initial_state(ep.CMU) };
-    let lfb_leuart = init_cmu(cmu);
+    let (lfb_leuart, usb_clk, usbc_clk) = init_cmu(cmu);
     init_rtc(80, &ep.RTC);
 
     let gpio = unsafe { gpio::InitialGpioState::get_initial_state(ep.GPIO) };
@@ -102,12 +160,27 @@ fn main() -> ! {
     let mut pb13 = gpio
         .pb13
         .mode(gpio::pin_modes::PinMode::new().push_pull().input_enable());
+    let mut pb14 = gpio
+        .pb14
+        .mode(gpio::pin_modes::PinMode::new().push_pull().input_enable());
 
+    // Running from the LFRCO lets the LEUART keep receiving in EM2, at up to 9600 baud
     let leuart = unsafe { leuart::Leuart::get_initial_state(ep.LEUART0) };
     let mut leuart = leuart
-        .baudrate(&lfb_leuart, 115200.0)
+        .baudrate(&lfb_leuart, 9600.0)
         .location1()
-        .enable_tx(&mut pb13);
+        .enable_tx(&mut pb13)
+        .loopback();
+    // The pattern goes out on the TX pin as well, before anything else is sent
+    let self_test_passed = leuart.self_test(&leuart::SELF_TEST_PATTERN, |_| ());
+    let mut leuart = leuart.normal_mode().enable_rx(&mut pb14);
+    leuart.enable_wakeup(lfb_leuart, leuart::Wakeup::RxData);
+
+    let descriptors = usb::cdc::descriptors(unsafe { &mut USB_CONFIGURATION })
+        .expect("Invalid USB configuration descriptor");
+    let mut usb_device = usb::UsbDevice::new(ep.USB, usb_clk, usbc_clk, descriptors);
+    let mut cdc = usb::cdc::CdcAcm::new();
+    let mut dfu = usb::dfu::DfuRuntime::new(usb::cdc::DFU_INTERFACE);
 
     let mut rtc_handler = nvic::InterruptHandler::new(|| {
         let rtc = unsafe { &*efm32hg309f64::RTC::ptr() };
@@ -117,14 +190,63 @@ fn main() -> ! {
     });
 
     nvic::Nvic::new(cp.NVIC).with_handler(|handler| {
-        handler.register(efm32hg309f64::Interrupt::RTC, &mut rtc_handler);
+        handler.register(
+            efm32hg309f64::Interrupt::RTC,
+            nvic::Priority::Low,
+            &mut rtc_handler,
+        );
+        // A byte which has been received on one side, but not yet written to the other
+        let mut to_cdc = None;
+        let mut to_leuart = None;
+        // A failed self test is reported once the host opens the virtual serial port, while a
+        // passed one stays quiet so it does not end up in the data passed along
+        let mut report: &[u8] = if self_test_passed {
+            &[]
+        } else {
+            b"LEUART self test failed\r\n"
+        };
         loop {
-            leuart.write_blocking(b"Hello!\n");
+            use embedded_hal::serial::{Read, Write};
+
+            usb_device.poll(&mut [&mut cdc, &mut dfu]);
+            // Sleep in EM2 while the bus is suspended, until a frame on the LEUART wakes the host
+            usb_device.sleep_while_suspended(&mut [&mut cdc, &mut dfu], || {
+                leuart.has_woken(leuart::Wakeup::RxData)
+            });
+
+            // Pass bytes between the LEUART and the virtual serial port without ever waiting for
+            // either of them, so endpoint 0 keeps being serviced even if the host stops reading.
+            // This also empties the receive buffer of the LEUART, so it does not wake the chip
+            // again right away.
+            if !report.is_empty() && cdc.dtr() {
+                let written = cdc.write_bytes(report);
+                report = &report[written..];
+            }
+            if to_cdc.is_none() {
+                to_cdc = Read::<u8>::read(&mut leuart).ok();
+            }
+            if let Some(byte) = to_cdc {
+                if Write::<u8>::write(&mut cdc, byte).is_ok() {
+                    to_cdc = None;
+                }
+            }
+            let _ = Write::<u8>::flush(&mut cdc);
+
+            if to_leuart.is_none() {
+                to_leuart = Read::<u8>::read(&mut cdc).ok();
+            }
+            if let Some(byte) = to_leuart {
+                if Write::<u8>::write(&mut leuart, byte).is_ok() {
+                    to_leuart = None;
+                }
+            }
         }
     })
 }
 
+#[cfg(not(test))]
 exception!(*, default_handler);
+#[cfg(not(test))]
 fn default_handler(_irqn: i16) {
     loop {
         cortex_m::asm::nop()
@@ -0,0 +1,190 @@
+//! A driver for writing and erasing the flash through the MSC, the Memory System Controller. See
+//! chapter 7 in EFM32HG-RM.pdf.
+//!
+//! The flash is erased in pages of 1 KB, after which every word can be written once. The CPU is
+//! stalled while it fetches instructions from the flash during an erase or write, so interrupts
+//! are delayed by up to 20 ms while erasing a page.
+//!
+//! The MSC times erasing and writing with the `AUXHFRCO`, which it turns on by itself while it
+//! needs it, but it has to be told the frequency the `AUXHFRCO` is set to.
+//!
+//! Only the disk, the last 8 KB of flash which `memory.x` keeps free, can be erased and written,
+//! so a bad address can not wipe Toboot or the running image.
+
+#[cfg(not(test))]
+use cmu;
+#[cfg(not(test))]
+use core::slice;
+#[cfg(not(test))]
+use efm32hg309f64;
+#[cfg(not(test))]
+use typenum;
+
+/// The size of a flash page, which is the smallest unit that can be erased.
+pub const PAGE_SIZE: usize = 1024;
+/// The size of the flash, counting the 16 KB used by Toboot.
+pub const FLASH_SIZE: u32 = 64 * 1024;
+/// The start of the disk, which has to match `DISK` in `memory.x`.
+pub const DISK_START: u32 = 0x0000_e000;
+/// The size of the disk.
+pub const DISK_SIZE: u32 = 0x2000;
+
+// Writing this to `MSC_LOCK` unlocks the registers used for erasing and writing
+#[cfg(not(test))]
+const UNLOCK_CODE: u16 = 0x1b71;
+// How long to wait for an erase or write before giving up. This is the value used by emlib.
+#[cfg(not(test))]
+const TIMEOUT: u32 = 10_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The address is not word aligned, or outside the flash. Erasing and writing also fail
+    /// with this outside the disk.
+    InvalidAddress,
+    /// The page is locked by the `MSC_LOCK` bits in the lock bits page.
+    Locked,
+    /// The MSC did not finish in time.
+    Timeout,
+}
+
+#[cfg(not(test))]
+pub struct Msc {
+    msc: efm32hg309f64::MSC,
+}
+
+#[cfg(not(test))]
+impl Msc {
+    /// Takes ownership of the MSC, and sets `MSC_TIMEBASE` from the frequency of the `AUXHFRCO`.
+    /// The frequency can not be changed afterwards, which is ensured by borrowing it.
+    pub fn new<Frequency: typenum::Unsigned>(
+        msc: efm32hg309f64::MSC,
+        _auxhfrco: &'static cmu::AuxHfRco<Frequency>,
+    ) -> Msc {
+        // The number of cycles in 1 us, with 10% margin for the inaccuracy of the oscillator
+        let frequency = <cmu::AuxHfRco<Frequency> as cmu::Clock>::FREQUENCY;
+        let cycles = (frequency * 1.1 / 1_000_000.0) as u8 + 1;
+        msc.timebase.modify(|_, w| unsafe { w.base().bits(cycles) });
+        Msc { msc }
+    }
+
+    /// The `len` bytes of flash starting at `address`.
+    pub fn read(&self, address: u32, len: usize) -> Result<&[u8], Error> {
+        if !within(address, len, 0, FLASH_SIZE) {
+            return Err(Error::InvalidAddress);
+        }
+        Ok(unsafe { slice::from_raw_parts(address as *const u8, len) })
+    }
+
+    /// Erases the page at `address`, which must be page aligned and in the disk, setting all of
+    /// its bits to 1.
+    pub fn erase_page(&mut self, address: u32) -> Result<(), Error> {
+        if address % PAGE_SIZE as u32 != 0 || !within(address, PAGE_SIZE, DISK_START, DISK_SIZE) {
+            return Err(Error::InvalidAddress);
+        }
+        self.unlocked(|msc| {
+            msc.load_address(address)?;
+            msc.msc.writecmd.write(|w| w.erasepage().set_bit());
+            msc.wait_while_busy()
+        })
+    }
+
+    /// Writes `data` starting at `address`, which must be word aligned and in the disk. The words
+    /// can only be written once after the page has been erased, as writing only clears bits.
+    pub fn write(&mut self, address: u32, data: &[u32]) -> Result<(), Error> {
+        if address % 4 != 0 || !within(address, 4 * data.len(), DISK_START, DISK_SIZE) {
+            return Err(Error::InvalidAddress);
+        }
+        self.unlocked(|msc| {
+            for (i, &word) in data.iter().enumerate() {
+                // The address is loaded for every word, as it does not wrap into the next page
+                msc.load_address(address + 4 * i as u32)?;
+                msc.msc.wdata.write(|w| unsafe { w.wdata().bits(word) });
+                msc.msc.writecmd.write(|w| w.writeonce().set_bit());
+                msc.wait_while_busy()?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Runs `f` with writing enabled in `MSC_WRITECTRL`, and the registers unlocked.
+    fn unlocked<F>(&mut self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Msc) -> Result<(), Error>,
+    {
+        self.msc.lock.write(|w| unsafe { w.lockkey().bits(UNLOCK_CODE) });
+        self.msc.writectrl.modify(|_, w| w.wren().set_bit());
+        let result = f(self);
+        self.msc.writectrl.modify(|_, w| w.wren().clear_bit());
+        self.msc.lock.write(|w| unsafe { w.lockkey().bits(0) });
+        result
+    }
+
+    /// Loads `address` into the MSC by writing `MSC_ADDRB` and setting `LADDRIM` in
+    /// `MSC_WRITECMD`, which fails if the address is invalid or locked.
+    fn load_address(&mut self, address: u32) -> Result<(), Error> {
+        self.msc.addrb.write(|w| unsafe { w.addrb().bits(address) });
+        self.msc.writecmd.write(|w| w.laddrim().set_bit());
+        let status = self.msc.status.read();
+        if status.invaddr().bit_is_set() {
+            Err(Error::InvalidAddress)
+        } else if status.locked().bit_is_set() {
+            Err(Error::Locked)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Waits for the `BUSY` bit in `MSC_STATUS` to be cleared.
+    fn wait_while_busy(&mut self) -> Result<(), Error> {
+        for _ in 0..TIMEOUT {
+            if self.msc.status.read().busy().bit_is_clear() {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+}
+
+/// Whether the `len` bytes starting at `address` lie in the `size` bytes starting at `start`.
+fn within(address: u32, len: usize, start: u32, size: u32) -> bool {
+    match address.checked_add(len as u32) {
+        Some(end) => address >= start && end <= start + size,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_is_the_end_of_the_flash() {
+        assert_eq!(DISK_START + DISK_SIZE, FLASH_SIZE);
+        assert_eq!(DISK_START % PAGE_SIZE as u32, 0);
+        assert_eq!(DISK_SIZE % PAGE_SIZE as u32, 0);
+    }
+
+    #[test]
+    fn flash_range() {
+        assert!(within(0, PAGE_SIZE, 0, FLASH_SIZE));
+        assert!(within(FLASH_SIZE - 4, 4, 0, FLASH_SIZE));
+        assert!(within(FLASH_SIZE, 0, 0, FLASH_SIZE));
+        assert!(!within(FLASH_SIZE - 4, 5, 0, FLASH_SIZE));
+        assert!(!within(FLASH_SIZE, 1, 0, FLASH_SIZE));
+        // The end would overflow
+        assert!(!within(0xffff_fffc, 8, 0, FLASH_SIZE));
+    }
+
+    #[test]
+    fn disk_range() {
+        let in_disk = |address, len| within(address, len, DISK_START, DISK_SIZE);
+        let end = DISK_START + DISK_SIZE;
+        assert!(in_disk(DISK_START, DISK_SIZE as usize));
+        assert!(in_disk(end - PAGE_SIZE as u32, PAGE_SIZE));
+        // Toboot, the running image and the page right before the disk
+        assert!(!in_disk(0, PAGE_SIZE));
+        assert!(!in_disk(0x4000, 4));
+        assert!(!in_disk(DISK_START - 4, 8));
+        assert!(!in_disk(end, PAGE_SIZE));
+    }
+}
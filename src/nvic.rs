@@ -4,6 +4,7 @@ use core::ptr;
 use core::sync::atomic::{AtomicPtr, Ordering};
 use cortex_m;
 use cortex_m::interrupt::Nr;
+use cortex_m::peripheral::scb::SystemHandler;
 use efm32hg309f64;
 
 #[repr(C)]
@@ -37,6 +38,112 @@ impl<F> InterruptHandler<F> {
     }
 }
 
+/// The priority of an interrupt or exception. Lower levels preempt higher ones, and everything
+/// starts out at `Highest` after reset.
+///
+/// The Cortex-M0+ only implements the top 2 bits of each priority field, so there are 4 levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Highest = 0,
+    High = 1,
+    Low = 2,
+    Lowest = 3,
+}
+
+impl Priority {
+    #[inline]
+    fn bits(self) -> u8 {
+        (self as u8) << 6
+    }
+
+    #[inline]
+    fn from_bits(bits: u8) -> Priority {
+        match bits >> 6 {
+            0 => Priority::Highest,
+            1 => Priority::High,
+            2 => Priority::Low,
+            _ => Priority::Lowest,
+        }
+    }
+}
+
+/// The system exceptions with a configurable priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemException {
+    SvCall,
+    PendSv,
+    SysTick,
+}
+
+impl SystemException {
+    #[inline]
+    fn handler(self) -> SystemHandler {
+        match self {
+            SystemException::SvCall => SystemHandler::SVCall,
+            SystemException::PendSv => SystemHandler::PendSV,
+            SystemException::SysTick => SystemHandler::SysTick,
+        }
+    }
+}
+
+/// Anything which can be given a handler and a priority through `NvicHandle`.
+#[derive(Clone, Copy)]
+pub enum Source {
+    Interrupt(efm32hg309f64::Interrupt),
+    System(SystemException),
+}
+
+impl Source {
+    /// The index of the handler in `HANDLERS`, where the system exceptions come after the device
+    /// interrupts.
+    #[inline]
+    fn index(self) -> usize {
+        match self {
+            Source::Interrupt(interrupt) => {
+                let nr = interrupt.nr() as usize;
+                assert!(nr < INTERRUPTS.len());
+                nr
+            }
+            Source::System(exception) => INTERRUPTS.len() + exception as usize,
+        }
+    }
+}
+
+impl From<efm32hg309f64::Interrupt> for Source {
+    #[inline]
+    fn from(interrupt: efm32hg309f64::Interrupt) -> Source {
+        Source::Interrupt(interrupt)
+    }
+}
+
+impl From<SystemException> for Source {
+    #[inline]
+    fn from(exception: SystemException) -> Source {
+        Source::System(exception)
+    }
+}
+
+/// A handler registered for an interrupt through `NvicHandle`, which is handed back by
+/// `NvicHandle::replace` and `NvicHandle::unregister`. It stays borrowed for as long as the
+/// `NvicHandle`, so it can be registered again later.
+pub struct Handler<'a> {
+    handler: *mut InterruptHandler<()>,
+    lifetime: PhantomData<&'a mut InterruptHandler<()>>,
+}
+
+impl<'a, F> From<&'a mut InterruptHandler<F>> for Handler<'a>
+where
+    F: FnMut() + Send + Sync + 'a,
+{
+    #[inline]
+    fn from(f: &'a mut InterruptHandler<F>) -> Handler<'a> {
+        Handler {
+            handler: f.unify(),
+            lifetime: PhantomData,
+        }
+    }
+}
+
 pub struct Nvic {
     non_send_sync: PhantomData<*mut ()>,
 }
@@ -56,6 +163,31 @@ unsafe fn disable_interrupt(interrupt: efm32hg309f64::Interrupt) {
     nvic.disable(interrupt);
 }
 
+// The priority registers are written a word at a time on the Cortex-M0+, so this must not be
+// interrupted by another change of priority.
+fn set_priority(source: Source, priority: Priority) {
+    cortex_m::interrupt::free(|_| unsafe {
+        match source {
+            Source::Interrupt(interrupt) => {
+                let mut nvic = mem::transmute::<(), efm32hg309f64::NVIC>(());
+                nvic.set_priority(interrupt, priority.bits());
+            }
+            Source::System(exception) => {
+                let mut scb = mem::transmute::<(), cortex_m::peripheral::SCB>(());
+                scb.set_priority(exception.handler(), priority.bits());
+            }
+        }
+    })
+}
+
+fn get_priority(source: Source) -> Priority {
+    let bits = match source {
+        Source::Interrupt(interrupt) => efm32hg309f64::NVIC::get_priority(interrupt),
+        Source::System(exception) => cortex_m::peripheral::SCB::get_priority(exception.handler()),
+    };
+    Priority::from_bits(bits)
+}
+
 const INTERRUPTS: [efm32hg309f64::Interrupt; 21] = [
     efm32hg309f64::Interrupt::DMA,
     efm32hg309f64::Interrupt::GPIO_EVEN,
@@ -80,7 +212,10 @@ const INTERRUPTS: [efm32hg309f64::Interrupt; 21] = [
     efm32hg309f64::Interrupt::TIMER2,
 ];
 
-static INTERRUPT_HANDLERS: [AtomicPtr<InterruptHandler<()>>; 21] = [
+static HANDLERS: [AtomicPtr<InterruptHandler<()>>; 24] = [
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
     AtomicPtr::new(ptr::null_mut()),
     AtomicPtr::new(ptr::null_mut()),
     AtomicPtr::new(ptr::null_mut()),
@@ -105,22 +240,89 @@ static INTERRUPT_HANDLERS: [AtomicPtr<InterruptHandler<()>>; 21] = [
 ];
 
 impl<'a> NvicHandle<'a> {
-    pub fn register<F>(&self, interrupt: efm32hg309f64::Interrupt, f: &'a mut InterruptHandler<F>)
+    /// Registers `f` as the handler of a device interrupt or a system exception with the given
+    /// priority, and enables it if it is a device interrupt.
+    ///
+    /// The system exceptions can not be disabled, but only happen when asked for: `SysTick` once
+    /// the SysTick timer is started with its interrupt enabled, `PendSv` when it is pended through
+    /// `SCB::set_pendsv`, and `SvCall` when the `svc` instruction is executed.
+    pub fn register<S, F>(&self, source: S, priority: Priority, f: &'a mut InterruptHandler<F>)
     where
+        S: Into<Source>,
         F: FnMut() + Send + Sync + 'a,
     {
         assert_eq_size_val!(f, [0u8; 4], 0usize);
 
-        let f: *mut InterruptHandler<()> = f.unify();
+        let source = source.into();
+        set_priority(source, priority);
+        self.replace(source, f);
+    }
+
+    /// Registers `handler` in place of the current one, which is returned. A device interrupt is
+    /// disabled while the handlers are swapped, and enabled afterwards with the priority it
+    /// already had. System exceptions can not be disabled, so they are swapped with all
+    /// interrupts masked instead.
+    ///
+    /// The handler can be a new one, or one returned by an earlier call to `replace` or
+    /// `unregister`.
+    pub fn replace<S, H>(&self, source: S, handler: H) -> Option<Handler<'a>>
+    where
+        S: Into<Source>,
+        H: Into<Handler<'a>>,
+    {
+        let source = source.into();
+        let previous = self.swap(source, handler.into().handler);
+        if let Source::Interrupt(interrupt) = source {
+            unsafe {
+                enable_interrupt(interrupt);
+            }
+        }
+        previous
+    }
+
+    /// Removes the handler, which is returned, and disables the interrupt if it is a device
+    /// interrupt. System exceptions without a handler are ignored.
+    pub fn unregister<S: Into<Source>>(&self, source: S) -> Option<Handler<'a>> {
+        self.swap(source.into(), ptr::null_mut())
+    }
 
-        let nr = interrupt.nr() as usize;
-        assert!(nr <= 20);
+    fn swap(&self, source: Source, handler: *mut InterruptHandler<()>) -> Option<Handler<'a>> {
+        let current = &HANDLERS[source.index()];
+        let previous = match source {
+            Source::Interrupt(interrupt) => {
+                // A pending interrupt stays pending while it is disabled, so it is handled by the
+                // new handler once it is enabled again
+                unsafe {
+                    disable_interrupt(interrupt);
+                }
+                cortex_m::asm::dsb();
+                cortex_m::asm::isb();
+                current.swap(handler, Ordering::AcqRel)
+            }
+            Source::System(_) => {
+                cortex_m::interrupt::free(|_| current.swap(handler, Ordering::AcqRel))
+            }
+        };
 
-        INTERRUPT_HANDLERS[nr].store(f, Ordering::Release);
-        unsafe {
-            enable_interrupt(interrupt);
+        if previous.is_null() {
+            None
+        } else {
+            Some(Handler {
+                handler: previous,
+                lifetime: PhantomData,
+            })
         }
     }
+
+    /// Changes the priority of a device interrupt or a system exception, such as
+    /// `SystemException::SysTick`.
+    pub fn set_priority<S: Into<Source>>(&self, source: S, priority: Priority) {
+        set_priority(source.into(), priority);
+    }
+
+    pub fn priority<S: Into<Source>>(&self, source: S) -> Priority {
+        get_priority(source.into())
+    }
 }
 
 impl Nvic {
@@ -136,16 +338,17 @@ impl Nvic {
         R: 'static,
         F: 'nvic + FnOnce(NvicHandle<'nvic>) -> R,
     {
-        let mut saved_handles: [*mut InterruptHandler<()>; 21] = [ptr::null_mut(); 21];
+        let mut saved_handles: [*mut InterruptHandler<()>; 24] = [ptr::null_mut(); 24];
 
-        for (saved, current) in saved_handles.iter_mut().zip(INTERRUPT_HANDLERS.iter()) {
+        for (saved, current) in saved_handles.iter_mut().zip(HANDLERS.iter()) {
             *saved = current.load(Ordering::Relaxed);
         }
 
-        let _ = Cleanup { saved_handles };
+        // This has to be bound to a name, as `_` would drop it right away
+        let _cleanup = Cleanup { saved_handles };
 
         struct Cleanup {
-            saved_handles: [*mut InterruptHandler<()>; 21],
+            saved_handles: [*mut InterruptHandler<()>; 24],
         }
 
         impl Drop for Cleanup {
@@ -157,15 +360,14 @@ impl Nvic {
                 // that, especially since I do not want to consider panic
                 // semantics.
                 cortex_m::interrupt::free(|_| {
-                    for ((&saved, current), &interrupt) in self
-                        .saved_handles
-                        .iter()
-                        .zip(INTERRUPT_HANDLERS.iter())
-                        .zip(INTERRUPTS.iter())
+                    for (index, (&saved, current)) in
+                        self.saved_handles.iter().zip(HANDLERS.iter()).enumerate()
                     {
-                        if saved.is_null() {
+                        // The system exceptions can not be disabled, but are ignored without
+                        // a handler
+                        if saved.is_null() && index < INTERRUPTS.len() {
                             unsafe {
-                                disable_interrupt(interrupt);
+                                disable_interrupt(INTERRUPTS[index]);
                             }
                         }
                         current.store(saved, Ordering::Release);
@@ -182,10 +384,8 @@ impl Nvic {
 }
 
 #[inline]
-fn call_interrupt(interrupt: efm32hg309f64::Interrupt) {
-    let nr = interrupt.nr() as usize;
-    assert!(nr <= 21);
-    let handler = INTERRUPT_HANDLERS[nr].load(Ordering::Acquire);
+fn call_handler(source: Source) {
+    let handler = HANDLERS[source.index()].load(Ordering::Acquire);
     if let Some(handler) = unsafe { handler.as_mut() } {
         handler.call_inner();
     }
@@ -195,7 +395,16 @@ macro_rules! make_interrupt (
     ($interrupt:ident, $handler:ident) => {
         interrupt!($interrupt, $handler);
         fn $handler() {
-            call_interrupt(efm32hg309f64::Interrupt::$interrupt);
+            call_handler(Source::Interrupt(efm32hg309f64::Interrupt::$interrupt));
+        }
+    }
+);
+
+macro_rules! make_exception (
+    ($exception:ident, $variant:ident, $handler:ident) => {
+        exception!($exception, $handler);
+        fn $handler() {
+            call_handler(Source::System(SystemException::$variant));
         }
     }
 );
@@ -221,3 +430,7 @@ make_interrupt!(USART0_RX, handle_usart0_rx);
 make_interrupt!(USART0_TX, handle_usart0_tx);
 make_interrupt!(USB, handle_usb);
 make_interrupt!(TIMER2, handle_timer2);
+
+make_exception!(SVCall, SvCall, handle_svcall);
+make_exception!(PendSV, PendSv, handle_pendsv);
+make_exception!(SysTick, SysTick, handle_systick);
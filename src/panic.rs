@@ -5,8 +5,7 @@ use gpio;
 use leuart;
 use rt::ExceptionFrame;
 
-#[panic_implementation]
-#[no_mangle]
+#[panic_handler]
 pub fn panic_impl(panic_info: &PanicInfo) -> ! {
     cortex_m::interrupt::disable();
     pub struct UnknownState;
@@ -17,13 +16,14 @@ pub fn panic_impl(panic_info: &PanicInfo) -> ! {
             .hfrco(&hfrco);
         let hfcoreclk =
             cmu::hfcoreclk::HfCoreClk::<UnknownState, UnknownState>::claim_ownership().div1(&hfclk);
-        let hfcoreclklediv =
+        let _hfcoreclklediv =
             cmu::hfcoreclkle::HfCoreClkLeDiv::<UnknownState, UnknownState>::claim_ownership()
                 .enable_div4(&hfcoreclk);
-        let lfb = cmu::lfb::LfbClk::<UnknownState>::claim_ownership()
-            .enable_hfcoreclklediv(&hfcoreclklediv);
+        // The same clock and baud rate as in main.rs, so the same terminal can be used
+        let lfrco = cmu::lfrco::LfRco::<UnknownState>::claim_ownership().enable_32768hz();
+        let lfb = cmu::lfb::LfbClk::<UnknownState>::claim_ownership().enable_lfrco(&lfrco);
         let lfb_leuart = cmu::lfb::LfbClkLeuart0::<UnknownState, UnknownState>::claim_ownership()
-            .enable_div8(&lfb);
+            .enable_div1(&lfb);
 
         let mut pa0 = gpio::Pa0::<UnknownState>::claim_ownership()
             .mode(gpio::pin_modes::PinMode::new().push_pull().input_enable());
@@ -32,7 +32,9 @@ pub fn panic_impl(panic_info: &PanicInfo) -> ! {
             .mode(gpio::pin_modes::PinMode::new().push_pull().input_enable());
 
         let mut leuart = leuart::Leuart::<UnknownState, UnknownState, UnknownState, UnknownState>::claim_ownership()
-            .baudrate(&lfb_leuart, 115200.0)
+            .disable()
+            .baudrate(&lfb_leuart, 9600.0)
+            .frame_format(leuart::FrameFormat::new())
             .location1()
             .enable_tx(&mut pb13);
 
@@ -0,0 +1,90 @@
+//! Support for the Toboot bootloader, which lives in the first 16 KB of flash. See
+//! https://github.com/im-tomu/toboot.
+//!
+//! Toboot and the application share the 8 bytes at the start of RAM, which `memory.x` leaves out
+//! of the `RAM` region and exports as `toboot_runtime`. Toboot checks them on every boot, which is
+//! how the application can ask to be put back into the bootloader.
+//!
+//! The application also carries a Toboot v2 header right after the vector table, at offset 0x94,
+//! which is where `memory.x` places the `.toboot_header` section. Without it, Toboot falls back to
+//! guessing whether the image is valid. The header is configured with these cargo features:
+//!
+//! * `toboot-irq`: Toboot leaves interrupts enabled when starting the application.
+//! * `toboot-poweron-enter`: Toboot stays in the bootloader after a power-on reset.
+//! * `toboot-lock-entry`: Toboot can no longer be entered by shorting the pins, so only
+//!   `reboot_to_bootloader` gets there.
+
+use core::mem;
+use core::ptr;
+use cortex_m;
+
+/// The value of `magic` which makes Toboot stay in the bootloader instead of starting the
+/// application.
+const TOBOOT_FORCE_ENTRY_MAGIC: u32 = 0x7462_4346;
+
+const TOBOOT_V2_MAGIC: u32 = 0x9070_70b2;
+const TOBOOT_LOCKOUT_MAGIC: u32 = 0x1834_9420;
+
+const CONFIG_FLAG_ENABLE_IRQ: u8 = 1 << 0;
+const CONFIG_FLAG_POWERON_ENTER: u8 = 1 << 1;
+
+// Has to match the origin of `FLASH` in `memory.x`. Toboot counts in pages of 1 KB.
+const APP_START: u32 = 0x0000_4000;
+const PAGE_SIZE: u32 = 1024;
+
+/// The layout of the header, as `struct toboot_configuration` in Toboot's `toboot-api.h`.
+#[repr(C)]
+pub struct TobootConfiguration {
+    magic: u32,
+    /// Incremented by Toboot every time a new image is flashed.
+    reserved_gen: u16,
+    /// The first page of the application.
+    start: u8,
+    config: u8,
+    lock_entry: u32,
+    /// The pages to erase when a new image is flashed, besides the ones it is written to.
+    erase_mask_lo: u32,
+    erase_mask_hi: u32,
+    /// A hash of the header, which Toboot fills in while flashing.
+    reserved_hash: u32,
+}
+
+const CONFIG: u8 = (cfg!(feature = "toboot-irq") as u8 * CONFIG_FLAG_ENABLE_IRQ)
+    | (cfg!(feature = "toboot-poweron-enter") as u8 * CONFIG_FLAG_POWERON_ENTER);
+
+const LOCK_ENTRY: u32 = cfg!(feature = "toboot-lock-entry") as u32 * TOBOOT_LOCKOUT_MAGIC;
+
+#[no_mangle]
+#[link_section = ".toboot_header"]
+pub static TOBOOT_HEADER: TobootConfiguration = TobootConfiguration {
+    magic: TOBOOT_V2_MAGIC,
+    reserved_gen: 0,
+    start: (APP_START / PAGE_SIZE) as u8,
+    config: CONFIG,
+    lock_entry: LOCK_ENTRY,
+    erase_mask_lo: 0,
+    erase_mask_hi: 0,
+    reserved_hash: 0,
+};
+
+#[repr(C)]
+struct TobootRuntime {
+    magic: u32,
+    boot_count: u16,
+    board_model: u16,
+}
+
+extern "C" {
+    static mut toboot_runtime: TobootRuntime;
+}
+
+/// Resets the chip into Toboot, which then waits for a new image over DFU.
+pub fn reboot_to_bootloader() -> ! {
+    cortex_m::interrupt::disable();
+    unsafe {
+        ptr::write_volatile(&mut toboot_runtime.magic, TOBOOT_FORCE_ENTRY_MAGIC);
+    }
+
+    let mut scb = unsafe { mem::transmute::<(), cortex_m::peripheral::SCB>(()) };
+    scb.system_reset()
+}
@@ -0,0 +1,353 @@
+//! An implementation of `usb_device::bus::UsbBus` for the USB peripheral, so the class crates
+//! written for the `usb-device` stack can be used instead of `UsbDevice`. This module is only
+//! available with the `usb-device` cargo feature.
+
+use super::device::{flush_fifos, init_usb};
+use super::endpoint;
+use super::fifo::{FifoAllocator, FifoLayout, ENDPOINTS, MAX_PACKET_SIZE};
+use cmu;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use efm32hg309f64;
+use usb_device::bus::{PollResult, UsbBusAllocator};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{Result, UsbDirection, UsbError};
+
+static mut SETUP_PACKET: [u8; 8] = [0; 8];
+
+// Whether `SETUP_PACKET` contains a packet which has not been read yet
+static SETUP_READY: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+struct Endpoint {
+    ep_type: EndpointType,
+    max_packet_size: u16,
+}
+
+fn endpoint_type_bits(ep_type: EndpointType) -> u8 {
+    match ep_type {
+        EndpointType::Control => 0,
+        EndpointType::Isochronous => 1,
+        EndpointType::Bulk => 2,
+        EndpointType::Interrupt => 3,
+    }
+}
+
+/// The USB peripheral as a `usb_device::bus::UsbBus`.
+pub struct UsbBus<'devices> {
+    in_endpoints: [Option<Endpoint>; ENDPOINTS],
+    out_endpoints: [Option<Endpoint>; ENDPOINTS],
+    devices: PhantomData<&'devices ()>,
+}
+
+impl<'devices> UsbBus<'devices> {
+    /// Takes ownership of the USB peripheral. Both the core and its bus interface have to be
+    /// clocked, which is why the clocks are given as proof. The core is not touched until the
+    /// `usb-device` stack enables it.
+    pub fn new<UsbSource, UsbCSource>(
+        usb: efm32hg309f64::USB,
+        usb_clk: &'devices cmu::HfCoreClkUsb<'devices, UsbSource>,
+        usbc_clk: &'devices cmu::HfCoreClkUsbC<'devices, UsbCSource>,
+    ) -> UsbBusAllocator<UsbBus<'devices>>
+    where
+        cmu::HfCoreClkUsb<'devices, UsbSource>: cmu::Clock,
+        cmu::HfCoreClkUsbC<'devices, UsbCSource>: cmu::Clock,
+    {
+        let _ = usb;
+        let _ = usb_clk;
+        let _ = usbc_clk;
+        UsbBusAllocator::new(UsbBus {
+            in_endpoints: [None; ENDPOINTS],
+            out_endpoints: [None; ENDPOINTS],
+            devices: PhantomData,
+        })
+    }
+
+    /// The FIFO layout for the allocated IN endpoints, or `None` if it does not fit.
+    fn fifo_layout(&self) -> Option<FifoLayout> {
+        let mut allocator = FifoAllocator::new();
+        for (index, endpoint) in self.in_endpoints.iter().enumerate() {
+            if let Some(endpoint) = endpoint {
+                if !allocator.allocate_in(index, endpoint.max_packet_size) {
+                    return None;
+                }
+            }
+        }
+        Some(allocator.layout())
+    }
+
+    fn out_endpoint(&self, ep_addr: EndpointAddress) -> Result<Endpoint> {
+        if ep_addr.is_in() || ep_addr.index() >= ENDPOINTS {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        self.out_endpoints[ep_addr.index()].ok_or(UsbError::InvalidEndpoint)
+    }
+
+    fn in_endpoint(&self, ep_addr: EndpointAddress) -> Result<Endpoint> {
+        if ep_addr.is_out() || ep_addr.index() >= ENDPOINTS {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        self.in_endpoints[ep_addr.index()].ok_or(UsbError::InvalidEndpoint)
+    }
+}
+
+impl<'devices> ::usb_device::bus::UsbBus for UsbBus<'devices> {
+    // The Synopsys core answers the status stage of `SET_ADDRESS` from the old address by itself
+    const QUIRK_SET_ADDRESS_BEFORE_STATUS: bool = true;
+
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        if max_packet_size > MAX_PACKET_SIZE {
+            return Err(UsbError::EndpointOverflow);
+        }
+
+        let index = {
+            let endpoints = match ep_dir {
+                UsbDirection::In => &self.in_endpoints,
+                UsbDirection::Out => &self.out_endpoints,
+            };
+            match ep_addr {
+                Some(ep_addr) => {
+                    let index = ep_addr.index();
+                    if ep_addr.direction() != ep_dir
+                        || index >= ENDPOINTS
+                        || endpoints[index].is_some()
+                    {
+                        return Err(UsbError::InvalidEndpoint);
+                    }
+                    index
+                }
+                None => (1..ENDPOINTS)
+                    .find(|&index| endpoints[index].is_none())
+                    .ok_or(UsbError::EndpointOverflow)?,
+            }
+        };
+
+        // Endpoint 0 is always the control endpoint, and may not be used for anything else
+        if (index == 0) != (ep_type == EndpointType::Control) {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let endpoint = Some(Endpoint {
+            ep_type,
+            max_packet_size,
+        });
+        match ep_dir {
+            UsbDirection::In => self.in_endpoints[index] = endpoint,
+            UsbDirection::Out => self.out_endpoints[index] = endpoint,
+        }
+
+        if self.fifo_layout().is_none() {
+            match ep_dir {
+                UsbDirection::In => self.in_endpoints[index] = None,
+                UsbDirection::Out => self.out_endpoints[index] = None,
+            }
+            return Err(UsbError::EndpointMemoryOverflow);
+        }
+
+        Ok(EndpointAddress::from_parts(index, ep_dir))
+    }
+
+    fn enable(&mut self) {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        let cmu = unsafe { &*efm32hg309f64::CMU::ptr() };
+        cmu.usbcrctrl.write(|w| w.en().set_bit());
+
+        // `alloc_ep` has made sure that the layout fits
+        init_usb(usb, &self.fifo_layout().unwrap());
+
+        usb.daintmsk.modify(|r, w| unsafe {
+            let mut bits = r.bits();
+            for index in 0..ENDPOINTS {
+                if self.in_endpoints[index].is_some() {
+                    bits |= 1 << index;
+                }
+                if self.out_endpoints[index].is_some() {
+                    bits |= 1 << (16 + index);
+                }
+            }
+            w.bits(bits)
+        });
+    }
+
+    fn reset(&self) {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        usb.dcfg.modify(|_, w| unsafe { w.devaddr().bits(0) });
+        flush_fifos(usb);
+        endpoint::deactivate_all();
+        SETUP_READY.store(0, Ordering::Relaxed);
+
+        for index in 1..ENDPOINTS {
+            if let Some(ep) = self.in_endpoints[index] {
+                endpoint::activate_in(index, endpoint_type_bits(ep.ep_type), ep.max_packet_size);
+            }
+            if let Some(ep) = self.out_endpoints[index] {
+                endpoint::activate_out(index, endpoint_type_bits(ep.ep_type), ep.max_packet_size);
+            }
+        }
+
+        // A value of 0 in the `MPS` subfield means 64 bytes
+        usb.diep0ctl.modify(|_, w| unsafe { w.mps().bits(0) });
+        endpoint::arm_out(0, MAX_PACKET_SIZE);
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        usb.dcfg.modify(|_, w| unsafe { w.devaddr().bits(addr) });
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let ep = self.in_endpoint(ep_addr)?;
+        if buf.len() > ep.max_packet_size as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        if endpoint::in_busy(ep_addr.index()) {
+            return Err(UsbError::WouldBlock);
+        }
+        endpoint::start_in(ep_addr.index(), buf);
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        let ep = self.out_endpoint(ep_addr)?;
+        let index = ep_addr.index();
+
+        if index == 0 && SETUP_READY.load(Ordering::Acquire) != 0 {
+            if buf.len() < 8 {
+                return Err(UsbError::BufferOverflow);
+            }
+            buf[..8].copy_from_slice(unsafe { &SETUP_PACKET });
+            SETUP_READY.store(0, Ordering::Release);
+            endpoint::arm_out(0, ep.max_packet_size);
+            return Ok(8);
+        }
+
+        if !endpoint::out_ready(index) {
+            return Err(UsbError::WouldBlock);
+        }
+        endpoint::take_out(index, ep.max_packet_size, buf).map_err(|error| match error {
+            endpoint::Error::InvalidEndpoint => UsbError::InvalidEndpoint,
+            endpoint::Error::BufferOverflow => UsbError::BufferOverflow,
+        })
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        match (ep_addr.direction(), ep_addr.index()) {
+            (UsbDirection::In, 0) => usb.diep0ctl.modify(|_, w| w.stall().bit(stalled)),
+            (UsbDirection::Out, 0) => usb.doep0ctl.modify(|_, w| w.stall().bit(stalled)),
+            // Clearing a halt also resets the data toggle to DATA0
+            (UsbDirection::In, index) if index < ENDPOINTS => {
+                in_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+                    ctl.modify(|_, w| w.stall().bit(stalled).setd0pidef().bit(!stalled))
+                })
+            }
+            (UsbDirection::Out, index) if index < ENDPOINTS => {
+                out_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+                    ctl.modify(|_, w| w.stall().bit(stalled).setd0pidef().bit(!stalled))
+                })
+            }
+            _ => (),
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        match (ep_addr.direction(), ep_addr.index()) {
+            (UsbDirection::In, 0) => usb.diep0ctl.read().stall().bit_is_set(),
+            (UsbDirection::Out, 0) => usb.doep0ctl.read().stall().bit_is_set(),
+            (UsbDirection::In, index) if index < ENDPOINTS => {
+                in_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+                    ctl.read().stall().bit_is_set()
+                })
+            }
+            (UsbDirection::Out, index) if index < ENDPOINTS => {
+                out_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+                    ctl.read().stall().bit_is_set()
+                })
+            }
+            _ => false,
+        }
+    }
+
+    /// Stops the PHY clock by setting the `STOPPCLK` bit in `USB_PCGCCTL`.
+    fn suspend(&self) {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        usb.pcgcctl.modify(|_, w| w.stoppclk().set_bit());
+    }
+
+    /// Restarts the PHY clock by clearing the `STOPPCLK` bit in `USB_PCGCCTL`.
+    fn resume(&self) {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        usb.pcgcctl.modify(|_, w| w.stoppclk().clear_bit());
+    }
+
+    fn poll(&self) -> PollResult {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        let intsts = usb.gintsts.read();
+
+        if intsts.usbrst().bit_is_set() {
+            usb.gintsts.write(|w| w.usbrst().set_bit());
+            return PollResult::Reset;
+        }
+        if intsts.enumdone().bit_is_set() {
+            usb.gintsts.write(|w| w.enumdone().set_bit());
+        }
+        if intsts.usbsusp().bit_is_set() {
+            usb.gintsts.write(|w| w.usbsusp().set_bit());
+            return PollResult::Suspend;
+        }
+        if intsts.wkupint().bit_is_set() {
+            usb.gintsts.write(|w| w.wkupint().set_bit());
+            return PollResult::Resume;
+        }
+
+        let daint = usb.daint.read().bits();
+        let mut ep_in_complete = 0;
+
+        if daint & 1 != 0 {
+            let diep0int = usb.diep0int.read();
+            usb.diep0int.write(|w| unsafe { w.bits(diep0int.bits()) });
+            if diep0int.xfercompl().bit_is_set() {
+                ep_in_complete |= 1;
+            }
+        }
+        if daint & (1 << 16) != 0 {
+            let doep0int = usb.doep0int.read();
+            usb.doep0int.write(|w| unsafe { w.bits(doep0int.bits()) });
+            if doep0int.setup().bit_is_set() {
+                // The core writes SETUP packets one after another, starting at the address in
+                // `USB_DOEP0DMAADDR`, so the newest one is right before where it points now
+                unsafe {
+                    let address = usb.doep0dmaaddr.read().bits() - 8;
+                    ptr::copy_nonoverlapping(address as *const u8, SETUP_PACKET.as_mut_ptr(), 8);
+                }
+                SETUP_READY.store(1, Ordering::Release);
+            } else if doep0int.xfercompl().bit_is_set() {
+                endpoint::set_out_ready(0);
+            }
+        }
+
+        ep_in_complete |= endpoint::handle_interrupts();
+
+        let ep_out = endpoint::out_ready_mask();
+        let ep_setup = SETUP_READY.load(Ordering::Acquire) as u16;
+        if ep_out == 0 && ep_in_complete == 0 && ep_setup == 0 {
+            PollResult::None
+        } else {
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
+    }
+}
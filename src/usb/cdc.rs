@@ -0,0 +1,320 @@
+//! A virtual serial port using the Abstract Control Model of the CDC class. See the USB CDC 1.2
+//! and PSTN 1.2 specifications.
+//!
+//! The host sees a regular serial port (`/dev/ttyACM*` on Linux), so test logs and commands can
+//! go over the same cable that is used for flashing. The line coding chosen by the host is
+//! remembered and reported back, but has no effect on anything.
+
+use super::{endpoint, Class, ControlResult, RequestKind, SetupPacket};
+use super::{ConfigurationBuilder, DescriptorError, Descriptors, DeviceDescriptor};
+use super::{STRINGS, TESTBOARD_DEVICE};
+use core::fmt;
+use embedded_hal::blocking;
+use embedded_hal::serial;
+use nb;
+
+/// The bulk endpoint carrying data from the host.
+pub const DATA_OUT: u8 = 0x01;
+/// The bulk endpoint carrying data to the host.
+pub const DATA_IN: u8 = 0x81;
+/// The interrupt endpoint for serial state notifications. No notifications are sent, but the
+/// endpoint is required by the specification.
+pub const NOTIFICATION: u8 = 0x82;
+
+const COMMUNICATION_INTERFACE: u8 = 0;
+const DATA_INTERFACE: u8 = 1;
+const PACKET_SIZE: usize = 64;
+
+/// The class-specific requests of the Abstract Control Model. See table 13 in the PSTN 1.2
+/// specification.
+pub mod request {
+    pub const SET_LINE_CODING: u8 = 0x20;
+    pub const GET_LINE_CODING: u8 = 0x21;
+    pub const SET_CONTROL_LINE_STATE: u8 = 0x22;
+    pub const SEND_BREAK: u8 = 0x23;
+}
+
+const CS_INTERFACE: u8 = 0x24;
+
+/// The size of the configuration descriptor built by `descriptors`, which is the smallest buffer
+/// it accepts.
+pub const CONFIGURATION_LENGTH: usize = 93;
+
+/// The interface number of the DFU run-time interface in `descriptors`, to be given to
+/// `dfu::DfuRuntime::new`.
+pub const DFU_INTERFACE: u8 = 2;
+
+// The interface association makes this a composite device, which hosts only look for with the
+// Miscellaneous device class
+static DEVICE: [u8; 18] = DeviceDescriptor {
+    class: 0xef,
+    subclass: 0x02,
+    protocol: 0x01,
+    ..TESTBOARD_DEVICE
+}
+.bytes();
+
+/// The descriptors of a device with the virtual serial port, and a DFU run-time interface so
+/// `flash.sh` can put the board into the bootloader. The configuration descriptor is built into
+/// `buffer`, which has to hold at least `CONFIGURATION_LENGTH` bytes.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+pub fn descriptors(buffer: &'static mut [u8]) -> Result<Descriptors, DescriptorError> {
+    let configuration = ConfigurationBuilder::new(buffer, 1, 0x80, 100)
+        // Communications class with the Abstract Control Model
+        .interface_association(0, 2, 0x02, 0x02, 0x00)
+        .interface(COMMUNICATION_INTERFACE, 0, 0x02, 0x02, 0x00, 0)
+        .class_specific(&[
+            5,                          // bFunctionLength
+            CS_INTERFACE,               // bDescriptorType
+            0x00,                       // bDescriptorSubtype: Header
+            0x20, 0x01,                 // bcdCDC: 1.20
+        ])
+        .class_specific(&[
+            5,                          // bFunctionLength
+            CS_INTERFACE,               // bDescriptorType
+            0x01,                       // bDescriptorSubtype: Call Management
+            0x00,                       // bmCapabilities: No call management
+            DATA_INTERFACE,             // bDataInterface
+        ])
+        .class_specific(&[
+            4,                          // bFunctionLength
+            CS_INTERFACE,               // bDescriptorType
+            0x02,                       // bDescriptorSubtype: Abstract Control Management
+            0x06,                       // bmCapabilities: Line coding, line state and break
+        ])
+        .class_specific(&[
+            5,                          // bFunctionLength
+            CS_INTERFACE,               // bDescriptorType
+            0x06,                       // bDescriptorSubtype: Union
+            COMMUNICATION_INTERFACE,    // bControlInterface
+            DATA_INTERFACE,             // bSubordinateInterface0
+        ])
+        .endpoint(NOTIFICATION, 0x03, 8, 255)
+        // CDC Data class
+        .interface(DATA_INTERFACE, 0, 0x0a, 0x00, 0x00, 0)
+        .endpoint(DATA_OUT, 0x02, PACKET_SIZE as u16, 0)
+        .endpoint(DATA_IN, 0x02, PACKET_SIZE as u16, 0)
+        .dfu_runtime(DFU_INTERFACE)
+        .build()?;
+
+    Ok(Descriptors {
+        device: &DEVICE,
+        configuration,
+        strings: STRINGS,
+    })
+}
+
+/// The virtual serial port. It has to be given to `UsbDevice::poll` to see the control requests
+/// sent by the host, and its endpoints have to be part of the configuration descriptor, as they
+/// are in `descriptors`.
+///
+/// Written bytes are collected into packets, which are sent when full or when the port is
+/// flushed. Everything written while the device is not configured, or while the host has not
+/// opened the port (signalled by `DTR`), is discarded, so logging never blocks with no one
+/// listening.
+pub struct CdcAcm {
+    configured: bool,
+    line_coding: [u8; 7],
+    dtr: bool,
+    rts: bool,
+    read_buffer: [u8; PACKET_SIZE],
+    read_pos: usize,
+    read_len: usize,
+    write_buffer: [u8; PACKET_SIZE],
+    write_len: usize,
+    // Whether the last packet sent was full, so the transfer has to be ended by a zero-length
+    // packet
+    needs_zlp: bool,
+}
+
+impl CdcAcm {
+    pub fn new() -> CdcAcm {
+        CdcAcm {
+            configured: false,
+            // 115200 baud, 1 stop bit, no parity, 8 data bits
+            line_coding: [0x00, 0xc2, 0x01, 0x00, 0, 0, 8],
+            dtr: false,
+            rts: false,
+            read_buffer: [0; PACKET_SIZE],
+            read_pos: 0,
+            read_len: 0,
+            write_buffer: [0; PACKET_SIZE],
+            write_len: 0,
+            needs_zlp: false,
+        }
+    }
+
+    /// The baud rate last set by the host.
+    #[inline]
+    pub fn baudrate(&self) -> u32 {
+        let c = &self.line_coding;
+        u32::from(c[0]) | u32::from(c[1]) << 8 | u32::from(c[2]) << 16 | u32::from(c[3]) << 24
+    }
+
+    /// Whether the host has opened the port, which it signals by setting `DTR`.
+    #[inline]
+    pub fn dtr(&self) -> bool {
+        self.dtr
+    }
+
+    #[inline]
+    pub fn rts(&self) -> bool {
+        self.rts
+    }
+
+    fn connected(&self) -> bool {
+        self.configured && self.dtr
+    }
+
+    /// Sends the collected bytes as a single packet, or a zero-length packet if there are none.
+    fn send_packet(&mut self) -> nb::Result<(), endpoint::Error> {
+        endpoint::write(DATA_IN, &self.write_buffer[..self.write_len])?;
+        self.needs_zlp = self.write_len == PACKET_SIZE;
+        self.write_len = 0;
+        Ok(())
+    }
+
+    /// Writes as many of `bytes` as there is room for in the packet buffer, returning how many
+    /// were written. The bytes are sent when the buffer is full, so call `flush` to send the rest
+    /// right away.
+    ///
+    /// This never waits for the host, as the host only reads the data when it wants to, and
+    /// `UsbDevice::poll` has to keep being called in the meantime.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> usize {
+        let mut count = 0;
+        for &b in bytes {
+            if serial::Write::<u8>::write(self, b).is_err() {
+                break;
+            }
+            count += 1;
+        }
+        count
+    }
+
+    /// Reads as many received bytes as fit in `buf`, returning how many were read.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut count = 0;
+        for b in buf.iter_mut() {
+            match serial::Read::<u8>::read(self) {
+                Ok(byte) => *b = byte,
+                Err(_) => break,
+            }
+            count += 1;
+        }
+        count
+    }
+}
+
+impl Default for CdcAcm {
+    fn default() -> CdcAcm {
+        CdcAcm::new()
+    }
+}
+
+impl Class for CdcAcm {
+    fn reset(&mut self) {
+        self.set_configuration(0);
+    }
+
+    fn set_configuration(&mut self, configuration: u8) {
+        self.configured = configuration != 0;
+        self.dtr = false;
+        self.rts = false;
+        self.read_pos = 0;
+        self.read_len = 0;
+        self.write_len = 0;
+        self.needs_zlp = false;
+    }
+
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> ControlResult {
+        let interface = u16::from(COMMUNICATION_INTERFACE);
+        if setup.kind() != RequestKind::Class || setup.index != interface {
+            return ControlResult::Ignored;
+        }
+
+        match setup.request {
+            request::SET_LINE_CODING if data.len() == 7 => {
+                self.line_coding.copy_from_slice(data);
+                ControlResult::Accepted(0)
+            }
+            request::GET_LINE_CODING if data.len() == 7 => {
+                data.copy_from_slice(&self.line_coding);
+                ControlResult::Accepted(7)
+            }
+            request::SET_CONTROL_LINE_STATE => {
+                self.dtr = setup.value & 0b01 != 0;
+                self.rts = setup.value & 0b10 != 0;
+                ControlResult::Accepted(0)
+            }
+            request::SEND_BREAK => ControlResult::Accepted(0),
+            _ => ControlResult::Rejected,
+        }
+    }
+}
+
+impl fmt::Write for CdcAcm {
+    /// Fails if the string does not fit in the packet buffer, in which case only the beginning of
+    /// it has been written.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.write_bytes(s.as_bytes()) == s.len() {
+            Ok(())
+        } else {
+            Err(fmt::Error)
+        }
+    }
+}
+
+impl serial::Write<u8> for CdcAcm {
+    type Error = endpoint::Error;
+
+    /// Puts a byte in the packet buffer, sending the buffer first if it is full.
+    fn write(&mut self, word: u8) -> nb::Result<(), endpoint::Error> {
+        if !self.connected() {
+            return Ok(());
+        }
+        if self.write_len == PACKET_SIZE {
+            self.send_packet()?;
+        }
+        self.write_buffer[self.write_len] = word;
+        self.write_len += 1;
+        Ok(())
+    }
+
+    /// Sends the packet buffer, followed by a zero-length packet if it was full, and waits for
+    /// the host to pick it up.
+    fn flush(&mut self) -> nb::Result<(), endpoint::Error> {
+        if !self.connected() {
+            return Ok(());
+        }
+        if self.write_len != 0 || self.needs_zlp {
+            self.send_packet()?;
+        }
+        if endpoint::in_busy((DATA_IN & 0x7f) as usize) {
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(())
+    }
+}
+
+impl blocking::serial::write::Default<u8> for CdcAcm {}
+
+impl serial::Read<u8> for CdcAcm {
+    type Error = endpoint::Error;
+
+    /// Takes a byte from the last packet received, reading a new packet when it is used up.
+    fn read(&mut self) -> nb::Result<u8, endpoint::Error> {
+        if self.read_pos == self.read_len {
+            if !self.configured {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.read_len = endpoint::read(DATA_OUT, &mut self.read_buffer)?;
+            self.read_pos = 0;
+            if self.read_len == 0 {
+                return Err(nb::Error::WouldBlock);
+            }
+        }
+        let byte = self.read_buffer[self.read_pos];
+        self.read_pos += 1;
+        Ok(byte)
+    }
+}
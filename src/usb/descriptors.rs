@@ -0,0 +1,966 @@
+//! The descriptors presented to the host during enumeration. See chapter 9.6 of the USB 2.0
+//! specification.
+//!
+//! Descriptors can either be written out as byte arrays, or built into a static buffer with
+//! `DeviceDescriptor`, `ConfigurationBuilder` and `BosBuilder`. Either way they are checked by the
+//! same validation functions, and `UsbDevice::new` refuses descriptors which do not pass.
+//!
+//! The classes all present themselves as `TESTBOARD_DEVICE` with the strings in `STRINGS`, and
+//! build their configuration descriptors with `ConfigurationBuilder`, including the DFU run-time
+//! interface added by `ConfigurationBuilder::dfu_runtime`.
+
+use super::descriptor_type;
+use super::fifo::{ENDPOINTS, MAX_PACKET_SIZE};
+use super::EP0_MAX_PACKET_SIZE;
+use core::cmp;
+
+/// The longest string, in UTF-16 code units, which fits in the buffer used to answer
+/// `GET_DESCRIPTOR` for strings.
+pub(crate) const MAX_STRING_LENGTH: usize = 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorError {
+    /// The descriptor did not fit in the buffer given to the builder.
+    BufferTooSmall,
+    /// A `bLength` does not match the type of the descriptor, or runs past the end.
+    InvalidLength { offset: usize },
+    /// `wTotalLength` does not match the length of the descriptor set.
+    InvalidTotalLength,
+    /// A field holds a value which is not allowed, such as a `bDescriptorType` of the wrong
+    /// type or a `bMaxPacketSize0` other than 64.
+    InvalidField { offset: usize },
+    /// The interfaces are not numbered from 0 without gaps, or `bNumInterfaces` or
+    /// `bNumEndpoints` does not match what follows.
+    InvalidInterfaceNumbering { offset: usize },
+    /// An endpoint is not one of endpoint 1 to 3, is used twice, or has a packet size larger
+    /// than 64 bytes.
+    InvalidEndpoint { offset: usize },
+    /// An interface association covers interfaces which do not exist, or is not followed by its
+    /// first interface.
+    InvalidAssociation { offset: usize },
+    /// A string index in the device descriptor has no string, or a string is longer than
+    /// `MAX_STRING_LENGTH`.
+    InvalidString { index: u8 },
+}
+
+/// The descriptors of a device with a single configuration.
+///
+/// The string descriptor with index 0 (the supported languages) is generated from this, as is
+/// the one used for `iSerialNumber` in the device descriptor, which contains the unique id of
+/// the chip. The string with index `n` is `strings[n - 1]`.
+#[derive(Debug, Clone, Copy)]
+pub struct Descriptors {
+    pub device: &'static [u8],
+    pub configuration: &'static [u8],
+    pub strings: &'static [&'static str],
+}
+
+impl Descriptors {
+    /// The `bConfigurationValue` of the configuration descriptor.
+    #[inline]
+    pub fn configuration_value(&self) -> u8 {
+        self.configuration[5]
+    }
+
+    /// Whether the `Self Powered` bit is set in `bmAttributes` of the configuration descriptor.
+    #[inline]
+    pub fn self_powered(&self) -> bool {
+        self.configuration[7] & 0x40 != 0
+    }
+
+    /// The `iSerialNumber` of the device descriptor.
+    #[inline]
+    pub fn serial_number_index(&self) -> u8 {
+        self.device[16]
+    }
+
+    /// Checks the device and configuration descriptors, and that every string index used by the
+    /// device descriptor has a string.
+    pub fn validate(&self) -> Result<(), DescriptorError> {
+        validate_device(self.device)?;
+        validate_configuration(self.configuration)?;
+
+        for &index in &self.device[14..17] {
+            let exists = index == 0
+                || index == self.serial_number_index()
+                || (index as usize) <= self.strings.len();
+            if !exists {
+                return Err(DescriptorError::InvalidString { index });
+            }
+        }
+        for (i, string) in self.strings.iter().enumerate() {
+            if string.encode_utf16().count() > MAX_STRING_LENGTH {
+                return Err(DescriptorError::InvalidString { index: i as u8 + 1 });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `device` is a device descriptor for a device with a 64 byte control endpoint.
+pub fn validate_device(device: &[u8]) -> Result<(), DescriptorError> {
+    if device.len() != 18 || device[0] != 18 {
+        return Err(DescriptorError::InvalidLength { offset: 0 });
+    }
+    if device[1] != descriptor_type::DEVICE {
+        return Err(DescriptorError::InvalidField { offset: 1 });
+    }
+    if device[7] as usize != EP0_MAX_PACKET_SIZE {
+        return Err(DescriptorError::InvalidField { offset: 7 });
+    }
+    if device[17] != 1 {
+        return Err(DescriptorError::InvalidField { offset: 17 });
+    }
+    Ok(())
+}
+
+/// Checks a configuration descriptor together with all of the descriptors following it.
+///
+/// Besides the lengths, this checks that the interfaces are numbered from 0, that every
+/// interface has as many endpoint descriptors as it claims, that only endpoint 1 to 3 are used
+/// and each of them only once per direction, that their transmit FIFOs fit, and that interface
+/// associations point at existing interfaces.
+pub fn validate_configuration(configuration: &[u8]) -> Result<(), DescriptorError> {
+    use self::DescriptorError::*;
+
+    if configuration.len() < 9 || configuration[0] != 9 {
+        return Err(InvalidLength { offset: 0 });
+    }
+    if configuration[1] != descriptor_type::CONFIGURATION {
+        return Err(InvalidField { offset: 1 });
+    }
+    let total_length = u16::from(configuration[2]) | u16::from(configuration[3]) << 8;
+    if total_length as usize != configuration.len() {
+        return Err(InvalidTotalLength);
+    }
+    let num_interfaces = configuration[4];
+
+    // The next interface number expected with alternate setting 0, the number of endpoint
+    // descriptors still expected for the current interface, and the endpoints seen so far with
+    // IN endpoints in the upper bits
+    let mut next_interface = 0;
+    let mut endpoints_left = 0;
+    let mut endpoints_seen = 0u32;
+    let mut association_first = None;
+
+    let mut offset = 9;
+    while offset < configuration.len() {
+        let length = configuration[offset] as usize;
+        if length < 2 || offset + length > configuration.len() {
+            return Err(InvalidLength { offset });
+        }
+        let descriptor = &configuration[offset..offset + length];
+
+        match descriptor[1] {
+            descriptor_type::INTERFACE_ASSOCIATION => {
+                if length != 8 {
+                    return Err(InvalidLength { offset });
+                }
+                let (first, count) = (descriptor[2], descriptor[3]);
+                if count == 0 || u16::from(first) + u16::from(count) > u16::from(num_interfaces) {
+                    return Err(InvalidAssociation { offset });
+                }
+                association_first = Some((first, offset));
+            }
+            descriptor_type::INTERFACE => {
+                if length != 9 {
+                    return Err(InvalidLength { offset });
+                }
+                if endpoints_left != 0 {
+                    return Err(InvalidInterfaceNumbering { offset });
+                }
+                let (number, alternate) = (descriptor[2], descriptor[3]);
+                if let Some((first, association)) = association_first.take() {
+                    if number != first || alternate != 0 {
+                        return Err(InvalidAssociation { offset: association });
+                    }
+                }
+                if alternate == 0 {
+                    if number != next_interface {
+                        return Err(InvalidInterfaceNumbering { offset });
+                    }
+                    next_interface += 1;
+                } else if number >= next_interface {
+                    return Err(InvalidInterfaceNumbering { offset });
+                }
+                endpoints_left = descriptor[4];
+            }
+            descriptor_type::ENDPOINT => {
+                // Audio class endpoints have the two extra fields `bRefresh` and `bSynchAddress`
+                if length != 7 && length != 9 {
+                    return Err(InvalidLength { offset });
+                }
+                if endpoints_left == 0 {
+                    return Err(InvalidInterfaceNumbering { offset });
+                }
+                endpoints_left -= 1;
+
+                let address = descriptor[2];
+                let index = (address & 0x7f) as usize;
+                let max_packet_size = u16::from(descriptor[4]) | u16::from(descriptor[5]) << 8;
+                if address & 0x70 != 0
+                    || index == 0
+                    || index >= ENDPOINTS
+                    || max_packet_size > MAX_PACKET_SIZE
+                {
+                    return Err(InvalidEndpoint { offset });
+                }
+                let bit = 1 << (index + if address & 0x80 != 0 { 16 } else { 0 });
+                // Alternate settings may reuse the endpoints of their interface
+                if endpoints_seen & bit != 0 && configuration_alternate(configuration, offset) == 0
+                {
+                    return Err(InvalidEndpoint { offset });
+                }
+                endpoints_seen |= bit;
+            }
+            descriptor_type::CONFIGURATION | descriptor_type::DEVICE => {
+                return Err(InvalidField { offset: offset + 1 });
+            }
+            // Class-specific descriptors are passed along untouched
+            _ => (),
+        }
+        offset += length;
+    }
+
+    if endpoints_left != 0 || next_interface != num_interfaces {
+        return Err(InvalidInterfaceNumbering { offset: 4 });
+    }
+    if let Some((_, association)) = association_first {
+        return Err(InvalidAssociation { offset: association });
+    }
+    Ok(())
+}
+
+/// The `bAlternateSetting` of the interface which the descriptor at `offset` belongs to.
+fn configuration_alternate(configuration: &[u8], offset: usize) -> u8 {
+    let mut alternate = 0;
+    let mut i = 9;
+    while i < offset {
+        if configuration[i + 1] == descriptor_type::INTERFACE {
+            alternate = configuration[i + 3];
+        }
+        i += configuration[i] as usize;
+    }
+    alternate
+}
+
+/// Checks a BOS descriptor together with the device capability descriptors following it.
+pub fn validate_bos(bos: &[u8]) -> Result<(), DescriptorError> {
+    use self::DescriptorError::*;
+
+    if bos.len() < 5 || bos[0] != 5 {
+        return Err(InvalidLength { offset: 0 });
+    }
+    if bos[1] != descriptor_type::BOS {
+        return Err(InvalidField { offset: 1 });
+    }
+    let total_length = u16::from(bos[2]) | u16::from(bos[3]) << 8;
+    if total_length as usize != bos.len() {
+        return Err(InvalidTotalLength);
+    }
+
+    let mut capabilities = 0;
+    let mut offset = 5;
+    while offset < bos.len() {
+        let length = bos[offset] as usize;
+        if length < 3 || offset + length > bos.len() {
+            return Err(InvalidLength { offset });
+        }
+        if bos[offset + 1] != descriptor_type::DEVICE_CAPABILITY {
+            return Err(InvalidField { offset: offset + 1 });
+        }
+        capabilities += 1;
+        offset += length;
+    }
+    if capabilities != bos[4] {
+        return Err(InvalidField { offset: 4 });
+    }
+    Ok(())
+}
+
+/// Encodes `chars` as a string descriptor in `buffer`, truncating the string if it does not fit.
+pub fn string_descriptor<I: Iterator<Item = u16>>(chars: I, buffer: &mut [u8]) -> &[u8] {
+    let mut length = 2;
+    for c in chars.take((cmp::min(buffer.len(), 255) - 2) / 2) {
+        buffer[length] = c as u8;
+        buffer[length + 1] = (c >> 8) as u8;
+        length += 2;
+    }
+    buffer[0] = length as u8;
+    buffer[1] = descriptor_type::STRING;
+    &buffer[..length]
+}
+
+/// The fields of a device descriptor which are not fixed by the hardware.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceDescriptor {
+    /// `bcdUSB`, which has to be at least 2.10 for hosts to ask for a BOS descriptor.
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// `bcdDevice`, the release number of the device in binary-coded decimal.
+    pub release: u16,
+    pub manufacturer: u8,
+    pub product: u8,
+    pub serial_number: u8,
+}
+
+impl DeviceDescriptor {
+    /// The descriptor for a device with a 64 byte control endpoint and a single configuration.
+    pub const fn bytes(self) -> [u8; 18] {
+        [
+            18,
+            descriptor_type::DEVICE,
+            self.usb_version as u8,
+            (self.usb_version >> 8) as u8,
+            self.class,
+            self.subclass,
+            self.protocol,
+            EP0_MAX_PACKET_SIZE as u8,
+            self.vendor_id as u8,
+            (self.vendor_id >> 8) as u8,
+            self.product_id as u8,
+            (self.product_id >> 8) as u8,
+            self.release as u8,
+            (self.release >> 8) as u8,
+            self.manufacturer,
+            self.product,
+            self.serial_number,
+            1,
+        ]
+    }
+}
+
+/// Writes a configuration descriptor and the interface, endpoint and class-specific descriptors
+/// following it into a buffer.
+///
+/// `wTotalLength`, `bNumInterfaces` and the `bNumEndpoints` of each interface are filled in by
+/// `build`, which also validates the result with `validate_configuration`.
+///
+/// ```ignore
+/// let configuration = ConfigurationBuilder::new(unsafe { &mut BUFFER }, 1, 0x80, 100)
+///     .interface(0, 0, 0xff, 0, 0, 0)
+///     .endpoint(0x81, 0x02, 64, 0)
+///     .dfu_runtime(1)
+///     .build()?;
+/// ```
+pub struct ConfigurationBuilder {
+    buffer: &'static mut [u8],
+    len: usize,
+    // The offset of the last interface descriptor
+    interface: Option<usize>,
+    error: Option<DescriptorError>,
+}
+
+impl ConfigurationBuilder {
+    /// Starts the configuration descriptor. `attributes` is `bmAttributes`, where bit 7 must be
+    /// set, and the maximum power is given in mA.
+    pub fn new(
+        buffer: &'static mut [u8],
+        value: u8,
+        attributes: u8,
+        max_power_ma: u16,
+    ) -> ConfigurationBuilder {
+        let builder = ConfigurationBuilder {
+            buffer,
+            len: 0,
+            interface: None,
+            error: None,
+        };
+        let max_power = cmp::min(max_power_ma / 2, 250) as u8;
+        builder.descriptor(&[
+            9,
+            descriptor_type::CONFIGURATION,
+            0,
+            0,
+            0,
+            value,
+            0,
+            attributes | 0x80,
+            max_power,
+        ])
+    }
+
+    fn descriptor(mut self, bytes: &[u8]) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+        if self.len + bytes.len() > self.buffer.len() {
+            self.error = Some(DescriptorError::BufferTooSmall);
+            return self;
+        }
+        self.buffer[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        self
+    }
+
+    /// Groups `count` interfaces starting with `first` into a single function. The first of
+    /// them has to follow right after.
+    pub fn interface_association(
+        self,
+        first: u8,
+        count: u8,
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+    ) -> Self {
+        self.descriptor(&[
+            8,
+            descriptor_type::INTERFACE_ASSOCIATION,
+            first,
+            count,
+            class,
+            subclass,
+            protocol,
+            0,
+        ])
+    }
+
+    /// Starts an interface. The endpoints added until the next interface belong to it.
+    pub fn interface(
+        mut self,
+        number: u8,
+        alternate: u8,
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+        string: u8,
+    ) -> Self {
+        let offset = self.len;
+        self = self.descriptor(&[
+            9,
+            descriptor_type::INTERFACE,
+            number,
+            alternate,
+            0,
+            class,
+            subclass,
+            protocol,
+            string,
+        ]);
+        self.interface = Some(offset);
+        self
+    }
+
+    /// Adds an endpoint to the current interface. `attributes` is `bmAttributes`, where the
+    /// lower 2 bits select the transfer type.
+    pub fn endpoint(self, address: u8, attributes: u8, max_packet_size: u16, interval: u8) -> Self {
+        self.endpoint_descriptor(&[
+            7,
+            descriptor_type::ENDPOINT,
+            address,
+            attributes,
+            max_packet_size as u8,
+            (max_packet_size >> 8) as u8,
+            interval,
+        ])
+    }
+
+    /// Adds an endpoint with the two extra fields of the Audio class to the current interface.
+    /// `bRefresh` and `bSynchAddress` are 0, as there are no synchronization endpoints, which is
+    /// what the endpoints of MIDI streaming interfaces need.
+    pub fn audio_endpoint(
+        self,
+        address: u8,
+        attributes: u8,
+        max_packet_size: u16,
+        interval: u8,
+    ) -> Self {
+        self.endpoint_descriptor(&[
+            9,
+            descriptor_type::ENDPOINT,
+            address,
+            attributes,
+            max_packet_size as u8,
+            (max_packet_size >> 8) as u8,
+            interval,
+            0,
+            0,
+        ])
+    }
+
+    fn endpoint_descriptor(mut self, bytes: &[u8]) -> Self {
+        match self.interface {
+            Some(interface) if self.error.is_none() => self.buffer[interface + 4] += 1,
+            Some(_) => (),
+            None => {
+                self.error = Some(DescriptorError::InvalidInterfaceNumbering { offset: self.len })
+            }
+        }
+        self.descriptor(bytes)
+    }
+
+    /// Adds a DFU run-time interface as interface `number`, followed by its functional
+    /// descriptor. This is what `dfu::DfuRuntime` answers for, and what `flash.sh` uses to put
+    /// the board into Toboot.
+    pub fn dfu_runtime(self, number: u8) -> Self {
+        self.interface(number, 0, 0xfe, 0x01, 0x01, 0)
+            .class_specific(&DFU_FUNCTIONAL_DESCRIPTOR)
+    }
+
+    /// Adds a class-specific descriptor, which has to start with its `bLength`.
+    pub fn class_specific(mut self, bytes: &[u8]) -> Self {
+        if bytes.len() < 2 || bytes[0] as usize != bytes.len() {
+            if self.error.is_none() {
+                self.error = Some(DescriptorError::InvalidLength { offset: self.len });
+            }
+            return self;
+        }
+        self.descriptor(bytes)
+    }
+
+    pub fn build(self) -> Result<&'static [u8], DescriptorError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let len = self.len;
+        let buffer = self.buffer;
+        if len > 0xffff {
+            return Err(DescriptorError::BufferTooSmall);
+        }
+        buffer[2] = len as u8;
+        buffer[3] = (len >> 8) as u8;
+
+        let mut interfaces = 0;
+        let mut offset = 9;
+        while offset < len {
+            if buffer[offset + 1] == descriptor_type::INTERFACE && buffer[offset + 3] == 0 {
+                interfaces += 1;
+            }
+            offset += buffer[offset] as usize;
+        }
+        buffer[4] = interfaces;
+
+        let buffer: &'static [u8] = buffer;
+        validate_configuration(&buffer[..len])?;
+        Ok(&buffer[..len])
+    }
+}
+
+/// Writes a BOS descriptor and its device capability descriptors into a buffer. See chapter 9.6.2
+/// in the USB 3.2 specification.
+///
+/// `wTotalLength` and `bNumDeviceCaps` are filled in by `build`.
+pub struct BosBuilder {
+    buffer: &'static mut [u8],
+    len: usize,
+    capabilities: u8,
+    error: Option<DescriptorError>,
+}
+
+impl BosBuilder {
+    pub fn new(buffer: &'static mut [u8]) -> BosBuilder {
+        let mut builder = BosBuilder {
+            buffer,
+            len: 0,
+            capabilities: 0,
+            error: None,
+        };
+        builder.append(&[5, descriptor_type::BOS, 0, 0, 0]);
+        builder
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        if self.len + bytes.len() > self.buffer.len() {
+            self.error = Some(DescriptorError::BufferTooSmall);
+            return;
+        }
+        self.buffer[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    /// Adds a device capability descriptor of the given `bDevCapabilityType`, where `data` is
+    /// everything after that field.
+    pub fn capability(mut self, capability_type: u8, data: &[u8]) -> Self {
+        if data.len() > 255 - 3 {
+            self.error = Some(DescriptorError::InvalidLength { offset: self.len });
+            return self;
+        }
+        self.append(&[
+            3 + data.len() as u8,
+            descriptor_type::DEVICE_CAPABILITY,
+            capability_type,
+        ]);
+        self.append(data);
+        self.capabilities += 1;
+        self
+    }
+
+    pub fn build(self) -> Result<&'static [u8], DescriptorError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let len = self.len;
+        let buffer = self.buffer;
+        buffer[2] = len as u8;
+        buffer[3] = (len >> 8) as u8;
+        buffer[4] = self.capabilities;
+
+        let buffer: &'static [u8] = buffer;
+        validate_bos(&buffer[..len])?;
+        Ok(&buffer[..len])
+    }
+}
+
+/// US English, which is the only language supported.
+pub(crate) const LANGUAGE_IDS: [u8; 4] = [4, descriptor_type::STRING, 0x09, 0x04];
+
+/// The descriptor type of the DFU functional descriptor, which follows the interface descriptor of
+/// a DFU interface.
+pub const DFU_FUNCTIONAL: u8 = 0x21;
+
+/// The DFU functional descriptor added by `ConfigurationBuilder::dfu_runtime`. See table 4.2 in
+/// the USB DFU 1.1 specification.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const DFU_FUNCTIONAL_DESCRIPTOR: [u8; 9] = [
+    9,                              // bLength
+    DFU_FUNCTIONAL,                 // bDescriptorType
+    0x0d,                           // bmAttributes: Will detach, tolerant, can download
+    0xe8, 0x03,                     // wDetachTimeOut: 1000 ms
+    0x00, 0x04,                     // wTransferSize: 1024 bytes
+    0x10, 0x01,                     // bcdDFUVersion: 1.1
+];
+
+/// The device descriptor shared by all of the classes, which leave the class codes to their
+/// interfaces. 1209:70b1 is the id used by Toboot, which is also what `flash.sh` looks for.
+pub const TESTBOARD_DEVICE: DeviceDescriptor = DeviceDescriptor {
+    usb_version: 0x0200,
+    class: 0x00,
+    subclass: 0x00,
+    protocol: 0x00,
+    vendor_id: 0x1209,
+    product_id: 0x70b1,
+    release: 0x0100,
+    manufacturer: 1,
+    product: 2,
+    serial_number: 3,
+};
+
+/// The manufacturer and product strings of `TESTBOARD_DEVICE`.
+pub const STRINGS: &[&str] = &["Idolf", "testboard"];
+
+static DEVICE: [u8; 18] = TESTBOARD_DEVICE.bytes();
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const CONFIGURATION: [u8; 9] = [
+    9,                              // bLength
+    descriptor_type::CONFIGURATION, // bDescriptorType
+    9, 0,                           // wTotalLength
+    0,                              // bNumInterfaces
+    1,                              // bConfigurationValue
+    0,                              // iConfiguration
+    0x80,                           // bmAttributes: Bus powered
+    50,                             // bMaxPower: 100 mA
+];
+
+/// A device without any interfaces, which is enough to be enumerated by the host.
+pub static DEFAULT_DESCRIPTORS: Descriptors = Descriptors {
+    device: &DEVICE,
+    configuration: &CONFIGURATION,
+    strings: STRINGS,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(len: usize) -> &'static mut [u8] {
+        Box::leak(vec![0; len].into_boxed_slice())
+    }
+
+    // A configuration with an interface association over two interfaces, one of which has an
+    // alternate setting reusing its endpoint, and a DFU run-time interface
+    fn composite() -> ConfigurationBuilder {
+        ConfigurationBuilder::new(buffer(256), 1, 0x80, 100)
+            .interface_association(0, 2, 0x02, 0x02, 0x00)
+            .interface(0, 0, 0x02, 0x02, 0x00, 0)
+            .class_specific(&[5, 0x24, 0x00, 0x20, 0x01])
+            .endpoint(0x82, 0x03, 8, 255)
+            .interface(1, 0, 0x0a, 0x00, 0x00, 0)
+            .endpoint(0x01, 0x02, 64, 0)
+            .endpoint(0x81, 0x02, 64, 0)
+            .interface(1, 1, 0x0a, 0x00, 0x00, 0)
+            .endpoint(0x81, 0x02, 32, 0)
+            .dfu_runtime(2)
+    }
+
+    // Copies `configuration` and lets `change` modify the copy.
+    fn modified<F: FnOnce(&mut Vec<u8>)>(configuration: &[u8], change: F) -> Vec<u8> {
+        let mut configuration = configuration.to_vec();
+        change(&mut configuration);
+        configuration
+    }
+
+    #[test]
+    fn testboard_device() {
+        let device = TESTBOARD_DEVICE.bytes();
+        assert_eq!(validate_device(&device), Ok(()));
+        assert_eq!(&device[..4], &[18, descriptor_type::DEVICE, 0x00, 0x02]);
+        assert_eq!(&device[7..12], &[64, 0x09, 0x12, 0xb1, 0x70]);
+        assert_eq!(DEFAULT_DESCRIPTORS.validate(), Ok(()));
+    }
+
+    #[test]
+    fn device_fields() {
+        let device = DeviceDescriptor {
+            usb_version: 0x0210,
+            class: 0xef,
+            ..TESTBOARD_DEVICE
+        }
+        .bytes();
+        assert_eq!(&device[2..5], &[0x10, 0x02, 0xef]);
+        assert_eq!(
+            validate_device(&device[..17]),
+            Err(DescriptorError::InvalidLength { offset: 0 })
+        );
+
+        let mut device = device;
+        device[7] = 8;
+        assert_eq!(
+            validate_device(&device),
+            Err(DescriptorError::InvalidField { offset: 7 })
+        );
+    }
+
+    #[test]
+    fn builder_fills_in_counts() {
+        let configuration = composite().build().unwrap();
+        assert_eq!(
+            configuration.len(),
+            9 + 8 + 9 + 5 + 7 + 9 + 7 + 7 + 9 + 7 + 9 + 9
+        );
+        assert_eq!(usize::from(configuration[2]), configuration.len());
+        assert_eq!(configuration[3], 0);
+        // The alternate setting is not counted as an interface of its own
+        assert_eq!(configuration[4], 3);
+        // bNumEndpoints of the communication and data interfaces
+        assert_eq!(configuration[9 + 8 + 4], 1);
+        assert_eq!(configuration[9 + 8 + 9 + 5 + 7 + 4], 2);
+    }
+
+    #[test]
+    fn builder_dfu_runtime() {
+        let configuration = ConfigurationBuilder::new(buffer(64), 1, 0x80, 100)
+            .dfu_runtime(0)
+            .build()
+            .unwrap();
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let expected: &[u8] = &[
+            9, descriptor_type::CONFIGURATION, 27, 0, 1, 1, 0, 0x80, 50,
+            9, descriptor_type::INTERFACE, 0, 0, 0, 0xfe, 0x01, 0x01, 0,
+            9, DFU_FUNCTIONAL, 0x0d, 0xe8, 0x03, 0x00, 0x04, 0x10, 0x01,
+        ];
+        assert_eq!(configuration, expected);
+    }
+
+    #[test]
+    fn builder_errors() {
+        let too_small = ConfigurationBuilder::new(buffer(20), 1, 0x80, 100).dfu_runtime(0);
+        assert_eq!(too_small.build(), Err(DescriptorError::BufferTooSmall));
+
+        let no_interface =
+            ConfigurationBuilder::new(buffer(64), 1, 0x80, 100).endpoint(0x81, 0x02, 64, 0);
+        assert_eq!(
+            no_interface.build(),
+            Err(DescriptorError::InvalidInterfaceNumbering { offset: 9 })
+        );
+
+        let bad_length = ConfigurationBuilder::new(buffer(64), 1, 0x80, 100)
+            .interface(0, 0, 0xff, 0, 0, 0)
+            .class_specific(&[4, 0x24, 0x00]);
+        assert_eq!(
+            bad_length.build(),
+            Err(DescriptorError::InvalidLength { offset: 18 })
+        );
+
+        // Interface 1 before interface 0
+        let numbering = ConfigurationBuilder::new(buffer(64), 1, 0x80, 100)
+            .interface(1, 0, 0xff, 0, 0, 0)
+            .interface(0, 0, 0xff, 0, 0, 0);
+        assert_eq!(
+            numbering.build(),
+            Err(DescriptorError::InvalidInterfaceNumbering { offset: 9 })
+        );
+    }
+
+    #[test]
+    fn configuration_lengths() {
+        use self::DescriptorError::*;
+
+        let configuration = composite().build().unwrap();
+        assert_eq!(validate_configuration(configuration), Ok(()));
+        assert_eq!(
+            validate_configuration(&configuration[..8]),
+            Err(InvalidLength { offset: 0 })
+        );
+        assert_eq!(
+            validate_configuration(&configuration[..configuration.len() - 1]),
+            Err(InvalidTotalLength)
+        );
+
+        // A descriptor running past the end
+        let configuration = modified(configuration, |c| {
+            c.extend_from_slice(&[4, 0x24, 0]);
+            c[2] += 3;
+        });
+        let offset = configuration.len() - 3;
+        assert_eq!(
+            validate_configuration(&configuration),
+            Err(InvalidLength { offset })
+        );
+    }
+
+    #[test]
+    fn configuration_numbering() {
+        use self::DescriptorError::*;
+
+        let configuration = composite().build().unwrap();
+        let too_many_interfaces = modified(configuration, |c| c[4] = 4);
+        assert_eq!(
+            validate_configuration(&too_many_interfaces),
+            Err(InvalidInterfaceNumbering { offset: 4 })
+        );
+
+        // The communication interface claims two endpoints but has one
+        let missing_endpoint = modified(configuration, |c| c[9 + 8 + 4] = 2);
+        assert_eq!(
+            validate_configuration(&missing_endpoint),
+            Err(InvalidInterfaceNumbering {
+                offset: 9 + 8 + 9 + 5 + 7
+            })
+        );
+
+        // The alternate setting of an interface which does not exist yet
+        let alternate = 9 + 8 + 9 + 5 + 7 + 9 + 7 + 7;
+        let early_alternate = modified(configuration, |c| c[alternate + 2] = 2);
+        assert_eq!(
+            validate_configuration(&early_alternate),
+            Err(InvalidInterfaceNumbering { offset: alternate })
+        );
+    }
+
+    #[test]
+    fn configuration_endpoints() {
+        use self::DescriptorError::*;
+
+        let configuration = composite().build().unwrap();
+        let notification = 9 + 8 + 9 + 5;
+        for &address in &[0x80, 0x84, 0xa2] {
+            let invalid = modified(configuration, |c| c[notification + 2] = address);
+            assert_eq!(
+                validate_configuration(&invalid),
+                Err(InvalidEndpoint {
+                    offset: notification
+                }),
+                "{:#x}",
+                address
+            );
+        }
+
+        let too_large = modified(configuration, |c| c[notification + 4] = 65);
+        assert_eq!(
+            validate_configuration(&too_large),
+            Err(InvalidEndpoint {
+                offset: notification
+            })
+        );
+
+        // Endpoint 1 IN used by both the communication and the data interface
+        let reused = modified(configuration, |c| c[notification + 2] = 0x81);
+        let data_in = notification + 7 + 9 + 7;
+        assert_eq!(
+            validate_configuration(&reused),
+            Err(InvalidEndpoint { offset: data_in })
+        );
+
+        // Endpoint 2 OUT is fine next to endpoint 2 IN
+        let out = modified(configuration, |c| c[notification + 2] = 0x02);
+        assert_eq!(validate_configuration(&out), Ok(()));
+    }
+
+    #[test]
+    fn configuration_associations() {
+        use self::DescriptorError::*;
+
+        let configuration = composite().build().unwrap();
+        let past_the_end = modified(configuration, |c| c[9 + 3] = 4);
+        assert_eq!(
+            validate_configuration(&past_the_end),
+            Err(InvalidAssociation { offset: 9 })
+        );
+
+        let wrong_first = modified(configuration, |c| c[9 + 2] = 1);
+        assert_eq!(
+            validate_configuration(&wrong_first),
+            Err(InvalidAssociation { offset: 9 })
+        );
+
+        let empty = modified(configuration, |c| c[9 + 3] = 0);
+        assert_eq!(
+            validate_configuration(&empty),
+            Err(InvalidAssociation { offset: 9 })
+        );
+    }
+
+    #[test]
+    fn bos() {
+        use self::DescriptorError::*;
+
+        let bos = BosBuilder::new(buffer(64))
+            .capability(0x05, &[0; 21])
+            .capability(0x02, &[0; 4])
+            .build()
+            .unwrap();
+        assert_eq!(&bos[..5], &[5, descriptor_type::BOS, 5 + 24 + 7, 0, 2]);
+        assert_eq!(validate_bos(bos), Ok(()));
+        assert_eq!(validate_bos(&bos[..4]), Err(InvalidLength { offset: 0 }));
+        assert_eq!(validate_bos(&bos[..bos.len() - 1]), Err(InvalidTotalLength));
+
+        let wrong_count = modified(bos, |b| b[4] = 3);
+        assert_eq!(validate_bos(&wrong_count), Err(InvalidField { offset: 4 }));
+
+        let wrong_type = modified(bos, |b| b[5 + 24 + 1] = descriptor_type::DEVICE);
+        assert_eq!(
+            validate_bos(&wrong_type),
+            Err(InvalidField { offset: 5 + 24 + 1 })
+        );
+
+        let too_long = modified(bos, |b| b[5 + 24] = 8);
+        assert_eq!(
+            validate_bos(&too_long),
+            Err(InvalidLength { offset: 5 + 24 })
+        );
+
+        let too_small = BosBuilder::new(buffer(16))
+            .capability(0x05, &[0; 21])
+            .build();
+        assert_eq!(too_small, Err(BufferTooSmall));
+    }
+
+    #[test]
+    fn strings() {
+        static DEVICE: [u8; 18] = TESTBOARD_DEVICE.bytes();
+        let missing_product = Descriptors {
+            device: &DEVICE,
+            configuration: &CONFIGURATION,
+            strings: &["Idolf"],
+        };
+        assert_eq!(
+            missing_product.validate(),
+            Err(DescriptorError::InvalidString { index: 2 })
+        );
+
+        let mut buffer = [0; 8];
+        let descriptor = string_descriptor("testboard".encode_utf16(), &mut buffer);
+        assert_eq!(
+            descriptor,
+            &[8, descriptor_type::STRING, b't', 0, b'e', 0, b's', 0]
+        );
+    }
+}
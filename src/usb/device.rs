@@ -0,0 +1,911 @@
+//! `UsbDevice`, which drives the core and handles the control transfers on endpoint 0.
+
+use super::descriptors;
+use super::fifo::{FifoAllocator, FifoLayout, ENDPOINTS};
+use super::{descriptor_type, endpoint, feature, request, Class, ControlResult, Descriptors};
+use super::{Recipient, RequestKind, SetupPacket, EP0_MAX_PACKET_SIZE};
+use cmu;
+use core::cmp;
+use core::marker::PhantomData;
+use core::mem;
+use cortex_m;
+use device_information;
+use efm32hg309f64;
+use emu;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ControlState {
+    WaitSetup,
+    InData,
+    OutData,
+    LastInData,
+    WaitStatusIn,
+    WaitStatusOut,
+}
+
+enum Response {
+    In(&'static [u8]),
+    OutData,
+    Status,
+    Stall,
+}
+
+// Room for the 3 back-to-back SETUP packets allowed by the `SUPCNT` subfield in
+// `USB_DOEP0TSIZ`.
+static mut SETUP_BUFFER: [u32; 6] = [0; 6];
+static mut EP0_IN_BUFFER: [u32; EP0_MAX_PACKET_SIZE / 4] = [0; EP0_MAX_PACKET_SIZE / 4];
+// Room for a data packet, followed by the SETUP packets of a host aborting the transfer with new
+// requests, which are written wherever `USB_DOEP0DMAADDR` points at the time
+static mut EP0_OUT_BUFFER: [u32; EP0_MAX_PACKET_SIZE / 4 + 6] =
+    [0; EP0_MAX_PACKET_SIZE / 4 + 6];
+// Responses which are generated at runtime, such as string descriptors
+static mut CONTROL_BUFFER: [u8; 2 + 2 * descriptors::MAX_STRING_LENGTH] =
+    [0; 2 + 2 * descriptors::MAX_STRING_LENGTH];
+
+// Between 2.4 ms at 25 MHz and 8.6 ms at 7 MHz, while remote wakeup signalling has to last from
+// 1 ms to 15 ms.
+const REMOTE_WAKEUP_CYCLES: u32 = 60_000;
+
+pub(super) fn init_usb(usb: &efm32hg309f64::usb::RegisterBlock, fifo_layout: &FifoLayout) {
+    usb.ctrl.write(|w| {
+        w.lemoscctrl()
+            .gate()
+            .lemidleen()
+            .set_bit()
+            .lemphyctrl()
+            .set_bit()
+    });
+
+    usb.route.write(|w| w.phypen().set_bit());
+
+    usb.pcgcctl.modify(|_, w| {
+        w.stoppclk()
+            .clear_bit()
+            .pwrclmp()
+            .clear_bit()
+            .rstpdwnmodule()
+            .clear_bit()
+    });
+
+    usb.grstctl.modify(|_, w| w.csftrst().set_bit());
+    while usb.grstctl.read().csftrst().bit_is_set() {}
+    while usb.grstctl.read().ahbidle().bit_is_clear() {}
+
+    usb.dcfg.modify(|_, w| {
+        w.devspd()
+            .fs()
+            .nzstsouthshk()
+            .set_bit()
+            .perfrint()
+            ._80pcnt()
+    });
+
+    usb.gahbcfg
+        .modify(|_, w| w.hbstlen().single().dmaen().set_bit());
+
+    configure_fifos(usb, fifo_layout);
+    flush_fifos(usb);
+
+    usb.dctl.modify(|_, w| {
+        w.cgoutnak()
+            .set_bit()
+            .cgnpinnak()
+            .set_bit()
+            .ignrfrmnum()
+            .set_bit()
+            .sftdiscon()
+            .clear_bit()
+    });
+
+    usb.dcfg.modify(|_, w| unsafe { w.devaddr().bits(0) });
+
+    usb.gintmsk.write(|w| {
+        w.usbrstmsk()
+            .set_bit()
+            .enumdonemsk()
+            .set_bit()
+            .iepintmsk()
+            .set_bit()
+            .oepintmsk()
+            .set_bit()
+            .usbsuspmsk()
+            .set_bit()
+            .wkupintmsk()
+            .set_bit()
+    });
+    usb.daintmsk
+        .write(|w| w.inepmsk0().set_bit().outepmsk0().set_bit());
+    usb.doepmsk.write(|w| {
+        w.setupmsk()
+            .set_bit()
+            .xfercomplmsk()
+            .set_bit()
+            .stsphsercvdmsk()
+            .set_bit()
+    });
+    usb.diepmsk.write(|w| w.xfercomplmsk().set_bit());
+    usb.gahbcfg.modify(|_, w| w.glblintrmsk().set_bit());
+}
+
+/// Sets the sizes and start addresses of the FIFOs in `USB_GRXFSIZ`, `USB_GNPTXFSIZ` and
+/// `USB_DIEPTXF1` to `USB_DIEPTXF3`. The FIFOs have to be flushed afterwards.
+fn configure_fifos(usb: &efm32hg309f64::usb::RegisterBlock, fifo_layout: &FifoLayout) {
+    // The transmit FIFOs are placed right after the receive FIFO, in the order of the endpoints
+    let mut start = fifo_layout.rx;
+    usb.grxfsiz
+        .write(|w| unsafe { w.rxfdep().bits(fifo_layout.rx) });
+    usb.gnptxfsiz.write(|w| unsafe {
+        w.nptxfstaddr()
+            .bits(start)
+            .nptxfineptxf0dep()
+            .bits(fifo_layout.tx[0])
+    });
+    start += fifo_layout.tx[0];
+    usb.dieptxf1.write(|w| unsafe {
+        w.inepntxfstaddr()
+            .bits(start)
+            .inepntxfdep()
+            .bits(fifo_layout.tx[1])
+    });
+    start += fifo_layout.tx[1];
+    usb.dieptxf2.write(|w| unsafe {
+        w.inepntxfstaddr()
+            .bits(start)
+            .inepntxfdep()
+            .bits(fifo_layout.tx[2])
+    });
+    start += fifo_layout.tx[2];
+    usb.dieptxf3.write(|w| unsafe {
+        w.inepntxfstaddr()
+            .bits(start)
+            .inepntxfdep()
+            .bits(fifo_layout.tx[3])
+    });
+}
+
+fn flush_tx_fifos(usb: &efm32hg309f64::usb::RegisterBlock) {
+    // A TXFNUM of 0x10 flushes all of the transmit FIFOs
+    usb.grstctl
+        .write(|w| unsafe { w.txfnum().bits(0x10).txfflsh().set_bit() });
+    while usb.grstctl.read().txfflsh().bit_is_set() {}
+}
+
+pub(super) fn flush_fifos(usb: &efm32hg309f64::usb::RegisterBlock) {
+    flush_tx_fifos(usb);
+    usb.grstctl.write(|w| w.rxfflsh().set_bit());
+    while usb.grstctl.read().rxfflsh().bit_is_set() {}
+}
+
+fn prepare_ep0_setup() {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    usb.doep0dmaaddr
+        .write(|w| unsafe { w.bits(SETUP_BUFFER.as_ptr() as u32) });
+    usb.doep0tsiz.write(|w| unsafe {
+        w.supcnt()
+            .bits(3)
+            .pktcnt()
+            .set_bit()
+            .xfersize()
+            .bits(3 * 8)
+    });
+    // The endpoint is left NAKing OUT packets, but SETUP packets are always accepted
+    usb.doep0ctl.modify(|_, w| w.epena().set_bit());
+}
+
+/// Returns the most recently received SETUP packet. The core writes SETUP packets one after
+/// another, starting at the address in `USB_DOEP0DMAADDR`, so the newest one is right before
+/// where it points now. That is in `SETUP_BUFFER` while waiting for a request, but in
+/// `EP0_OUT_BUFFER` if the host aborted an OUT data or status stage with a new request.
+fn received_setup() -> SetupPacket {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    let address = usb.doep0dmaaddr.read().bits() - 8;
+    SetupPacket::parse(unsafe { &*(address as *const [u8; 8]) })
+}
+
+/// Prepares endpoint 0 for receiving `len` bytes into `EP0_OUT_BUFFER`, by setting the `EPENA`
+/// and `CNAK` bits in `USB_DOEP0CTL`.
+fn ep0_prepare_out(len: usize) {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    usb.doep0dmaaddr
+        .write(|w| unsafe { w.bits(EP0_OUT_BUFFER.as_ptr() as u32) });
+    usb.doep0tsiz.write(|w| unsafe {
+        w.supcnt()
+            .bits(3)
+            .pktcnt()
+            .set_bit()
+            .xfersize()
+            .bits(len as u8)
+    });
+    usb.doep0ctl
+        .modify(|_, w| w.epena().set_bit().cnak().set_bit());
+}
+
+/// Sends a single packet on endpoint 0, by copying it to `EP0_IN_BUFFER` and setting the `EPENA`
+/// and `CNAK` bits in `USB_DIEP0CTL`.
+fn ep0_prepare_in(packet: &[u8]) {
+    debug_assert!(packet.len() <= EP0_MAX_PACKET_SIZE);
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    unsafe {
+        let buffer = &mut *(&mut EP0_IN_BUFFER as *mut [u32; 16] as *mut [u8; 64]);
+        buffer[..packet.len()].copy_from_slice(packet);
+        usb.diep0dmaaddr
+            .write(|w| w.bits(EP0_IN_BUFFER.as_ptr() as u32));
+    }
+    usb.diep0tsiz.write(|w| unsafe {
+        w.pktcnt()
+            .bits(1)
+            .xfersize()
+            .bits(packet.len() as u8)
+    });
+    usb.diep0ctl
+        .modify(|_, w| w.epena().set_bit().cnak().set_bit());
+}
+
+/// Stalls endpoint 0 in the OUT direction by setting the `STALL` bit in `USB_DOEP0CTL`. The core
+/// clears it again when the next SETUP packet arrives.
+fn ep0_out_stall() {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    usb.doep0ctl.modify(|_, w| w.stall().set_bit());
+}
+
+/// Stalls endpoint 0 in the IN direction by setting the `STALL` bit in `USB_DIEP0CTL`. The core
+/// clears it again when the next SETUP packet arrives.
+fn ep0_in_stall() {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    usb.diep0ctl.modify(|_, w| w.stall().set_bit());
+}
+
+/// Whether `HFCLK` was running from `USHFRCODIV2` when the core entered its low energy mode.
+/// Waking up from EM2 always restarts `HFCLK` from the `HFRCO`.
+struct LowEnergyState {
+    hfclk_ushfrco: bool,
+}
+
+/// Stops the PHY clock by setting the `STOPPCLK` bit in `USB_PCGCCTL`, and switches the
+/// `HFCORECLKUSBC` to the `LFXO` if it is running, or the `LFRCO` otherwise. The `USHFRCO` is
+/// stopped in EM2, so the core has to run from one of them to detect when the bus is resumed.
+fn enter_low_energy() -> LowEnergyState {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    let cmu = unsafe { &*efm32hg309f64::CMU::ptr() };
+    let status = cmu.status.read();
+    let state = LowEnergyState {
+        hfclk_ushfrco: status.ushfrcodiv2sel().bit_is_set(),
+    };
+
+    usb.pcgcctl.modify(|_, w| w.stoppclk().set_bit());
+    if status.lfxoens().bit_is_set() {
+        cmu.cmd.write(|w| w.usbcclksel().lfxo());
+        while cmu.status.read().usbclfxosel().bit_is_clear() {}
+    } else {
+        cmu.cmd.write(|w| w.usbcclksel().lfrco());
+        while cmu.status.read().usbclfrcosel().bit_is_clear() {}
+    }
+    state
+}
+
+/// Undoes `enter_low_energy` by restarting the `USHFRCO`, switching the `HFCORECLKUSBC` and, if
+/// needed, `HFCLK` back to it, and restarting the PHY clock.
+fn exit_low_energy(state: LowEnergyState) {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    let cmu = unsafe { &*efm32hg309f64::CMU::ptr() };
+
+    cmu.oscencmd.write(|w| w.ushfrcoen().set_bit());
+    while cmu.status.read().ushfrcordy().bit_is_clear() {}
+    if state.hfclk_ushfrco {
+        cmu.cmd.write(|w| w.hfclksel().ushfrcodiv2());
+    }
+    cmu.cmd.write(|w| w.usbcclksel().ushfrco());
+    while cmu.status.read().usbcushfrcosel().bit_is_clear() {}
+    usb.pcgcctl.modify(|_, w| w.stoppclk().clear_bit());
+}
+
+// Runs `$body` with `$ctl` bound to the `CTL` register of the endpoint with the given address,
+// or evaluates to `None` if there is no such endpoint.
+macro_rules! with_endpoint_ctl {
+    ($usb:expr, $address:expr, | $ctl:ident | $body:expr) => {
+        match $address {
+            0x01 => { let $ctl = &$usb.doep0_ctl; Some($body) }
+            0x02 => { let $ctl = &$usb.doep1_ctl; Some($body) }
+            0x03 => { let $ctl = &$usb.doep2_ctl; Some($body) }
+            0x81 => { let $ctl = &$usb.diep0_ctl; Some($body) }
+            0x82 => { let $ctl = &$usb.diep1_ctl; Some($body) }
+            0x83 => { let $ctl = &$usb.diep2_ctl; Some($body) }
+            _ => None,
+        }
+    };
+}
+
+/// Iterates over the endpoint descriptors in a configuration descriptor.
+struct EndpointDescriptors {
+    configuration: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for EndpointDescriptors {
+    type Item = &'static [u8];
+
+    fn next(&mut self) -> Option<&'static [u8]> {
+        while self.offset + 6 < self.configuration.len() {
+            let descriptor = &self.configuration[self.offset..];
+            self.offset += cmp::max(descriptor[0] as usize, 1);
+            if descriptor[1] == descriptor_type::ENDPOINT {
+                return Some(descriptor);
+            }
+        }
+        None
+    }
+}
+
+fn endpoint_descriptors(configuration: &'static [u8]) -> EndpointDescriptors {
+    EndpointDescriptors {
+        configuration,
+        offset: 0,
+    }
+}
+
+/// The FIFO layout for endpoint 0 and, unless `configuration` is `None`, the IN endpoints of a
+/// configuration descriptor. Returns `None` if the FIFOs do not fit in the FIFO RAM.
+fn fifo_layout(configuration: Option<&'static [u8]>) -> Option<FifoLayout> {
+    let mut allocator = FifoAllocator::new();
+    if !allocator.allocate_in(0, EP0_MAX_PACKET_SIZE as u16) {
+        return None;
+    }
+    for descriptor in configuration.into_iter().flat_map(endpoint_descriptors) {
+        let max_packet_size = u16::from(descriptor[4]) | u16::from(descriptor[5]) << 8;
+        if descriptor[2] & 0x80 != 0
+            && !allocator.allocate_in((descriptor[2] & 0x7f) as usize, max_packet_size)
+        {
+            return None;
+        }
+    }
+    Some(allocator.layout())
+}
+
+/// The standard device framework for the USB peripheral. It owns endpoint 0 and takes care of
+/// enumeration.
+pub struct UsbDevice<'devices> {
+    descriptors: Descriptors,
+    control_state: ControlState,
+    setup: SetupPacket,
+    in_data: &'static [u8],
+    in_zlp: bool,
+    configuration: u8,
+    remote_wakeup: bool,
+    suspended: bool,
+    devices: PhantomData<&'devices ()>,
+    non_send: PhantomData<*mut ()>,
+}
+
+impl<'devices> UsbDevice<'devices> {
+    /// Initializes the USB core and connects to the bus by clearing the `SFTDISCON` bit in
+    /// `USB_DCTL`. Both the core and its bus interface have to be clocked, which is why the
+    /// clocks are given as proof.
+    ///
+    /// The `USHFRCO` is tuned to the SOF packets sent by the host by setting the `EN` bit in
+    /// `CMU_USBCRCTRL`, which is what makes crystal-less operation possible.
+    ///
+    /// Panics if `descriptors` do not pass `Descriptors::validate`, as the host would refuse
+    /// the device anyway.
+    pub fn new<UsbSource, UsbCSource>(
+        usb: efm32hg309f64::USB,
+        usb_clk: &'devices cmu::HfCoreClkUsb<'devices, UsbSource>,
+        usbc_clk: &'devices cmu::HfCoreClkUsbC<'devices, UsbCSource>,
+        descriptors: Descriptors,
+    ) -> UsbDevice<'devices>
+    where
+        cmu::HfCoreClkUsb<'devices, UsbSource>: cmu::Clock,
+        cmu::HfCoreClkUsbC<'devices, UsbCSource>: cmu::Clock,
+    {
+        let _ = usb_clk;
+        let _ = usbc_clk;
+        descriptors.validate().expect("Invalid USB descriptors");
+
+        let cmu = unsafe { &*efm32hg309f64::CMU::ptr() };
+        cmu.usbcrctrl.write(|w| w.en().set_bit());
+
+        // The FIFOs of the other endpoints are allocated once the host selects the configuration
+        let fifo_layout = fifo_layout(None).expect("Endpoint 0 does not fit in the FIFO RAM");
+        init_usb(&usb, &fifo_layout);
+
+        UsbDevice {
+            descriptors,
+            control_state: ControlState::WaitSetup,
+            setup: SetupPacket::parse(&[0; 8]),
+            in_data: &[],
+            in_zlp: false,
+            configuration: 0,
+            remote_wakeup: false,
+            suspended: false,
+            devices: PhantomData,
+            non_send: PhantomData,
+        }
+    }
+
+    /// The currently selected configuration, or 0 if the device has not been configured yet.
+    #[inline]
+    pub fn configuration(&self) -> u8 {
+        self.configuration
+    }
+
+    /// Whether the host has allowed the device to wake it up with remote wakeup signalling.
+    #[inline]
+    pub fn remote_wakeup_enabled(&self) -> bool {
+        self.remote_wakeup
+    }
+
+    /// Whether the host has suspended the bus.
+    #[inline]
+    pub fn suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// Puts the chip into EM2 for as long as the bus is suspended, with the core in its low
+    /// energy mode. Returns immediately if the bus is not suspended.
+    ///
+    /// The chip wakes up on every interrupt which becomes pending, after which `wakeup` is asked
+    /// whether the host should be woken up. If remote wakeup is enabled and `wakeup` returns
+    /// true, the bus is resumed with `remote_wakeup`. Either way, the clocks are restored before
+    /// returning, and `Class::resume` has been called.
+    ///
+    /// The `LFRCO` or the `LFXO` has to be running, as the core runs from one of them in EM2.
+    pub fn sleep_while_suspended<F: FnMut() -> bool>(
+        &mut self,
+        classes: &mut [&mut dyn Class],
+        mut wakeup: F,
+    ) {
+        if !self.suspended {
+            return;
+        }
+
+        // The interrupt has to go from not pending to pending to wake the chip, which it cannot
+        // if it is still pending from the suspend
+        let mut nvic = unsafe { mem::transmute::<(), efm32hg309f64::NVIC>(()) };
+        nvic.clear_pending(efm32hg309f64::Interrupt::USB);
+
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        let remote_wakeup = self.remote_wakeup;
+        let mut woken_by_application = false;
+
+        let state = enter_low_energy();
+        emu::sleep_em2_until(efm32hg309f64::Interrupt::USB, || {
+            woken_by_application = remote_wakeup && wakeup();
+            let intsts = usb.gintsts.read();
+            woken_by_application || intsts.wkupint().bit_is_set() || intsts.usbrst().bit_is_set()
+        });
+        exit_low_energy(state);
+
+        if woken_by_application {
+            self.remote_wakeup(classes);
+        } else {
+            // The `WKUPINT` or `USBRST` bit is left for `poll`, which then calls `Class::resume`
+            // or `Class::reset`
+            self.poll(classes);
+        }
+    }
+
+    /// Wakes up the host by sending remote wakeup signalling, which is done by setting the
+    /// `RMTWKUPSIG` bit in `USB_DCTL` for a few milliseconds. This blocks while signalling.
+    ///
+    /// Returns false without doing anything if the bus is not suspended, or if the host has not
+    /// enabled remote wakeup with `SET_FEATURE`.
+    pub fn remote_wakeup(&mut self, classes: &mut [&mut dyn Class]) -> bool {
+        if !self.suspended || !self.remote_wakeup {
+            return false;
+        }
+
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        usb.dctl.modify(|_, w| w.rmtwkupsig().set_bit());
+        cortex_m::asm::delay(REMOTE_WAKEUP_CYCLES);
+        usb.dctl.modify(|_, w| w.rmtwkupsig().clear_bit());
+
+        self.resume(classes);
+        true
+    }
+
+    /// Handles all pending events of the USB core. This should be called whenever the `USB`
+    /// interrupt fires, or regularly from the main loop.
+    pub fn poll(&mut self, classes: &mut [&mut dyn Class]) {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        let intsts = usb.gintsts.read();
+
+        if intsts.usbrst().bit_is_set() {
+            usb.gintsts.write(|w| w.usbrst().set_bit());
+            self.reset(classes);
+        }
+
+        if intsts.usbsusp().bit_is_set() {
+            usb.gintsts.write(|w| w.usbsusp().set_bit());
+            if !self.suspended {
+                self.suspended = true;
+                for class in classes.iter_mut() {
+                    class.suspend();
+                }
+            }
+        }
+
+        if intsts.wkupint().bit_is_set() {
+            usb.gintsts.write(|w| w.wkupint().set_bit());
+            self.resume(classes);
+        }
+
+        if intsts.enumdone().bit_is_set() {
+            usb.gintsts.write(|w| w.enumdone().set_bit());
+            // A value of 0 in the `MPS` subfield means 64 bytes
+            usb.diep0ctl.modify(|_, w| unsafe { w.mps().bits(0) });
+            prepare_ep0_setup();
+            self.control_state = ControlState::WaitSetup;
+        }
+
+        if intsts.oepint().bit_is_set() && usb.daint.read().outepint0().bit_is_set() {
+            let doep0int = usb.doep0int.read();
+            usb.doep0int.write(|w| unsafe { w.bits(doep0int.bits()) });
+            if doep0int.setup().bit_is_set() {
+                self.handle_setup(classes);
+            } else if doep0int.xfercompl().bit_is_set() {
+                self.handle_out0(classes);
+            }
+        }
+
+        if intsts.iepint().bit_is_set() && usb.daint.read().inepint0().bit_is_set() {
+            if usb.diep0int.read().xfercompl().bit_is_set() {
+                usb.diep0int.write(|w| w.xfercompl().set_bit());
+                self.handle_in0(classes);
+            }
+        }
+
+        if intsts.oepint().bit_is_set() || intsts.iepint().bit_is_set() {
+            endpoint::handle_interrupts();
+        }
+    }
+
+    /// Activates the endpoints of the configuration descriptor, or deactivates them if
+    /// `configuration` is 0. The FIFO RAM is divided up again to give every IN endpoint its own
+    /// transmit FIFO.
+    ///
+    /// Returns false, leaving the device deconfigured, if the FIFOs do not fit.
+    fn set_configuration(&mut self, configuration: u8) -> bool {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        endpoint::deactivate_all();
+        self.configuration = 0;
+
+        let descriptor = if configuration != 0 {
+            Some(self.descriptors.configuration)
+        } else {
+            None
+        };
+        let fifo_layout = match fifo_layout(descriptor) {
+            Some(fifo_layout) => fifo_layout,
+            None => return false,
+        };
+        configure_fifos(usb, &fifo_layout);
+        flush_tx_fifos(usb);
+
+        if configuration != 0 {
+            for descriptor in endpoint_descriptors(self.descriptors.configuration) {
+                let index = (descriptor[2] & 0x7f) as usize;
+                let ep_type = descriptor[3] & 0b11;
+                let max_packet_size = u16::from(descriptor[4]) | u16::from(descriptor[5]) << 8;
+                if index == 0 || index >= ENDPOINTS {
+                    continue;
+                }
+                if descriptor[2] & 0x80 != 0 {
+                    endpoint::activate_in(index, ep_type, max_packet_size);
+                } else {
+                    endpoint::activate_out(index, ep_type, max_packet_size);
+                }
+            }
+        }
+        self.configuration = configuration;
+        true
+    }
+
+    fn resume(&mut self, classes: &mut [&mut dyn Class]) {
+        if self.suspended {
+            self.suspended = false;
+            for class in classes.iter_mut() {
+                class.resume();
+            }
+        }
+    }
+
+    fn reset(&mut self, classes: &mut [&mut dyn Class]) {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        usb.dcfg.modify(|_, w| unsafe { w.devaddr().bits(0) });
+        flush_fifos(usb);
+
+        endpoint::deactivate_all();
+
+        self.control_state = ControlState::WaitSetup;
+        self.configuration = 0;
+        self.remote_wakeup = false;
+        self.suspended = false;
+        for class in classes.iter_mut() {
+            class.reset();
+        }
+    }
+
+    fn handle_setup(&mut self, classes: &mut [&mut dyn Class]) {
+        let setup = received_setup();
+        self.setup = setup;
+
+        let response = match setup.kind() {
+            RequestKind::Standard => self.standard_request(&setup, classes),
+            RequestKind::Class | RequestKind::Vendor => class_request(&setup, classes),
+            RequestKind::Reserved => Response::Stall,
+        };
+        self.respond(response);
+    }
+
+    fn respond(&mut self, response: Response) {
+        match response {
+            Response::In(data) => {
+                let length = self.setup.length as usize;
+                self.in_data = &data[..cmp::min(data.len(), length)];
+                self.in_zlp = self.in_data.len() < length;
+                self.handle_datastage_in0();
+            }
+            Response::OutData => {
+                self.control_state = ControlState::OutData;
+                ep0_prepare_out(self.setup.length as usize);
+            }
+            Response::Status => {
+                self.control_state = ControlState::WaitStatusIn;
+                ep0_prepare_in(&[]);
+            }
+            Response::Stall => {
+                // The core clears the stalls itself when the next SETUP packet arrives
+                ep0_out_stall();
+                ep0_in_stall();
+                prepare_ep0_setup();
+                self.control_state = ControlState::WaitSetup;
+            }
+        }
+    }
+
+    /// Sends the next packet of the IN data stage. A transfer which ends with a full packet but
+    /// is shorter than what the host asked for is terminated by a zero-length packet.
+    fn handle_datastage_in0(&mut self) {
+        let len = cmp::min(self.in_data.len(), EP0_MAX_PACKET_SIZE);
+        let (packet, rest) = self.in_data.split_at(len);
+        self.in_data = rest;
+
+        self.control_state = if !rest.is_empty() || (len == EP0_MAX_PACKET_SIZE && self.in_zlp) {
+            ControlState::InData
+        } else {
+            ControlState::LastInData
+        };
+        ep0_prepare_in(packet);
+    }
+
+    fn handle_in0(&mut self, classes: &mut [&mut dyn Class]) {
+        match self.control_state {
+            ControlState::InData => {
+                self.handle_datastage_in0();
+            }
+            ControlState::LastInData => {
+                self.control_state = ControlState::WaitStatusOut;
+                ep0_prepare_out(0);
+            }
+            ControlState::WaitStatusIn => {
+                prepare_ep0_setup();
+                self.control_state = ControlState::WaitSetup;
+                self.control_complete(classes);
+            }
+            _ => self.respond(Response::Stall),
+        }
+    }
+
+    fn handle_out0(&mut self, classes: &mut [&mut dyn Class]) {
+        match self.control_state {
+            ControlState::OutData => {
+                let setup = self.setup;
+                let data = unsafe {
+                    &mut (&mut *(&mut EP0_OUT_BUFFER as *mut [u32; 22] as *mut [u8; 88]))
+                        [..setup.length as usize]
+                };
+                let response = classes
+                    .iter_mut()
+                    .map(|class| class.control(&setup, data))
+                    .find(|&result| result != ControlResult::Ignored);
+                match response {
+                    Some(ControlResult::Accepted(_)) => self.respond(Response::Status),
+                    _ => self.respond(Response::Stall),
+                }
+            }
+            ControlState::WaitStatusOut => {
+                prepare_ep0_setup();
+                self.control_state = ControlState::WaitSetup;
+                self.control_complete(classes);
+            }
+            _ => self.respond(Response::Stall),
+        }
+    }
+
+    fn control_complete(&self, classes: &mut [&mut dyn Class]) {
+        for class in classes.iter_mut() {
+            class.control_complete(&self.setup);
+        }
+    }
+
+    fn standard_request(
+        &mut self,
+        setup: &SetupPacket,
+        classes: &mut [&mut dyn Class],
+    ) -> Response {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+
+        match (setup.recipient(), setup.request) {
+            (_, request::GET_DESCRIPTOR) if setup.is_in() => self.get_descriptor(setup, classes),
+            (Recipient::Device, request::SET_ADDRESS) if setup.value < 128 => {
+                // The core takes care of answering the status stage from the old address
+                usb.dcfg
+                    .modify(|_, w| unsafe { w.devaddr().bits(setup.value as u8) });
+                Response::Status
+            }
+            (Recipient::Device, request::GET_CONFIGURATION) => {
+                control_response(&[self.configuration])
+            }
+            (Recipient::Device, request::SET_CONFIGURATION) => {
+                let configuration = setup.value as u8;
+                if setup.value != 0 && configuration != self.descriptors.configuration_value() {
+                    return Response::Stall;
+                }
+                let accepted = self.set_configuration(configuration);
+                for class in classes.iter_mut() {
+                    class.set_configuration(self.configuration);
+                }
+                if accepted {
+                    Response::Status
+                } else {
+                    Response::Stall
+                }
+            }
+            (Recipient::Device, request::GET_STATUS) => {
+                let status = self.descriptors.self_powered() as u8
+                    | (self.remote_wakeup as u8) << 1;
+                control_response(&[status, 0])
+            }
+            (Recipient::Device, request::CLEAR_FEATURE)
+                if setup.value == feature::DEVICE_REMOTE_WAKEUP =>
+            {
+                self.remote_wakeup = false;
+                Response::Status
+            }
+            (Recipient::Device, request::SET_FEATURE)
+                if setup.value == feature::DEVICE_REMOTE_WAKEUP =>
+            {
+                self.remote_wakeup = true;
+                Response::Status
+            }
+            (Recipient::Endpoint, _) => self.endpoint_request(setup),
+            (Recipient::Interface, _) => match class_request(setup, classes) {
+                Response::Stall => default_interface_request(setup, self.configuration),
+                response => response,
+            },
+            _ => Response::Stall,
+        }
+    }
+
+    fn endpoint_request(&mut self, setup: &SetupPacket) -> Response {
+        let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+        let address = setup.index as u8;
+
+        if address & 0x7f == 0 {
+            return match setup.request {
+                request::GET_STATUS => control_response(&[0, 0]),
+                request::CLEAR_FEATURE | request::SET_FEATURE => Response::Status,
+                _ => Response::Stall,
+            };
+        }
+
+        // Only endpoints of the active configuration may be addressed
+        let exists = self.configuration != 0
+            && endpoint_descriptors(self.descriptors.configuration).any(|d| d[2] == address);
+        if !exists {
+            return Response::Stall;
+        }
+
+        let result = match setup.request {
+            request::GET_STATUS => {
+                with_endpoint_ctl!(usb, address, |ctl| ctl.read().stall().bit_is_set())
+                    .map(|halted| control_response(&[halted as u8, 0]))
+            }
+            request::SET_FEATURE if setup.value == feature::ENDPOINT_HALT => {
+                with_endpoint_ctl!(usb, address, |ctl| {
+                    ctl.modify(|_, w| w.stall().set_bit())
+                }).map(|_| Response::Status)
+            }
+            // Clearing a halt also resets the data toggle to DATA0
+            request::CLEAR_FEATURE if setup.value == feature::ENDPOINT_HALT => {
+                with_endpoint_ctl!(usb, address, |ctl| {
+                    ctl.modify(|_, w| w.stall().clear_bit().setd0pidef().set_bit())
+                }).map(|_| Response::Status)
+            }
+            _ => None,
+        };
+        result.unwrap_or(Response::Stall)
+    }
+
+    fn get_descriptor(&mut self, setup: &SetupPacket, classes: &mut [&mut dyn Class]) -> Response {
+        let kind = (setup.value >> 8) as u8;
+        let index = setup.value as u8;
+
+        match kind {
+            descriptor_type::DEVICE if index == 0 => Response::In(self.descriptors.device),
+            descriptor_type::CONFIGURATION if index == 0 => {
+                Response::In(self.descriptors.configuration)
+            }
+            descriptor_type::STRING if index == 0 => Response::In(&descriptors::LANGUAGE_IDS),
+            descriptor_type::STRING if index == self.descriptors.serial_number_index() => {
+                let id = device_information::get_unique_id();
+                let digits = (0..16).rev().map(|nibble| {
+                    let digit = ((id >> (4 * nibble)) & 0xf) as u8;
+                    u16::from(b"0123456789abcdef"[digit as usize])
+                });
+                string_response(digits)
+            }
+            descriptor_type::STRING => match self.descriptors.strings.get(index as usize - 1) {
+                Some(string) => string_response(string.encode_utf16()),
+                None => Response::Stall,
+            },
+            _ => classes
+                .iter_mut()
+                .filter_map(|class| class.get_descriptor(setup))
+                .next()
+                .map_or(Response::Stall, Response::In),
+        }
+    }
+}
+
+/// Passes a request on to the classes, stopping at the first one which does not ignore it.
+fn class_request(setup: &SetupPacket, classes: &mut [&mut dyn Class]) -> Response {
+    if !setup.is_in() && setup.length != 0 {
+        // The data stage has to be received before the classes can look at it
+        return if setup.length as usize <= EP0_MAX_PACKET_SIZE {
+            Response::OutData
+        } else {
+            Response::Stall
+        };
+    }
+
+    let buffer = unsafe { &mut CONTROL_BUFFER };
+    let length = cmp::min(setup.length as usize, buffer.len());
+    for class in classes.iter_mut() {
+        match class.control(setup, &mut buffer[..length]) {
+            ControlResult::Ignored => continue,
+            ControlResult::Accepted(len) if setup.is_in() => {
+                return Response::In(unsafe { &CONTROL_BUFFER[..cmp::min(len, length)] })
+            }
+            ControlResult::Accepted(_) => return Response::Status,
+            ControlResult::Rejected => return Response::Stall,
+        }
+    }
+    Response::Stall
+}
+
+/// The answers to the standard interface requests for interfaces which only have the default
+/// alternate setting.
+fn default_interface_request(setup: &SetupPacket, configuration: u8) -> Response {
+    if configuration == 0 {
+        return Response::Stall;
+    }
+    match setup.request {
+        request::GET_STATUS => control_response(&[0, 0]),
+        request::GET_INTERFACE => control_response(&[0]),
+        request::SET_INTERFACE if setup.value == 0 => Response::Status,
+        _ => Response::Stall,
+    }
+}
+
+/// Copies a response generated at runtime to `CONTROL_BUFFER`.
+fn control_response(data: &[u8]) -> Response {
+    unsafe {
+        CONTROL_BUFFER[..data.len()].copy_from_slice(data);
+        Response::In(&CONTROL_BUFFER[..data.len()])
+    }
+}
+
+/// Builds a string descriptor in `CONTROL_BUFFER`, truncating it if it does not fit.
+fn string_response<I: Iterator<Item = u16>>(chars: I) -> Response {
+    Response::In(descriptors::string_descriptor(chars, unsafe { &mut CONTROL_BUFFER }))
+}
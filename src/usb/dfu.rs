@@ -0,0 +1,85 @@
+//! The run-time part of the Device Firmware Upgrade class. See chapter 4.1 in the USB DFU 1.1
+//! specification.
+//!
+//! The interface only understands `DFU_DETACH`, which makes the application reboot into Toboot.
+//! Toboot then enumerates as the actual DFU device, so `dfu-util` can flash a running board
+//! without anyone having to enter the bootloader by hand.
+
+use super::{Class, ControlResult, Recipient, RequestKind, SetupPacket};
+use toboot;
+
+pub use super::descriptors::DFU_FUNCTIONAL;
+
+/// The class-specific requests available in run-time mode. See table 3.2 in the DFU
+/// specification.
+pub mod request {
+    pub const DFU_DETACH: u8 = 0;
+    pub const DFU_GETSTATUS: u8 = 3;
+    pub const DFU_GETSTATE: u8 = 5;
+}
+
+// The states reported by `DFU_GETSTATUS` and `DFU_GETSTATE`
+const APP_IDLE: u8 = 0;
+const APP_DETACH: u8 = 1;
+
+/// A run-time DFU interface. It has to be given to `UsbDevice::poll`, and the interface has to be
+/// part of the configuration descriptor, as added by `ConfigurationBuilder::dfu_runtime`.
+///
+/// The functional descriptor added there has the `bitWillDetach` bit set, since the device
+/// reboots into the bootloader by itself instead of waiting for the host to reset the bus.
+pub struct DfuRuntime {
+    interface: u8,
+    detaching: bool,
+}
+
+impl DfuRuntime {
+    pub fn new(interface: u8) -> DfuRuntime {
+        DfuRuntime {
+            interface,
+            detaching: false,
+        }
+    }
+
+    fn is_for_us(&self, setup: &SetupPacket) -> bool {
+        setup.kind() == RequestKind::Class
+            && setup.recipient() == Recipient::Interface
+            && setup.index == u16::from(self.interface)
+    }
+}
+
+impl Class for DfuRuntime {
+    fn reset(&mut self) {
+        self.detaching = false;
+    }
+
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> ControlResult {
+        if !self.is_for_us(setup) {
+            return ControlResult::Ignored;
+        }
+
+        let state = if self.detaching { APP_DETACH } else { APP_IDLE };
+        match setup.request {
+            request::DFU_DETACH => {
+                self.detaching = true;
+                ControlResult::Accepted(0)
+            }
+            request::DFU_GETSTATUS if data.len() >= 6 => {
+                // bStatus OK, a poll timeout of 0 ms, the state and no status string
+                data[..6].copy_from_slice(&[0, 0, 0, 0, state, 0]);
+                ControlResult::Accepted(6)
+            }
+            request::DFU_GETSTATE if !data.is_empty() => {
+                data[0] = state;
+                ControlResult::Accepted(1)
+            }
+            _ => ControlResult::Rejected,
+        }
+    }
+
+    /// Reboots into the bootloader once the host has seen `DFU_DETACH` succeed.
+    fn control_complete(&mut self, setup: &SetupPacket) {
+        if self.detaching && self.is_for_us(setup) && setup.request == request::DFU_DETACH {
+            toboot::reboot_to_bootloader();
+        }
+    }
+}
@@ -0,0 +1,410 @@
+//! The data endpoints 1 to 3 in both directions, which are used by the classes once the device
+//! has been configured.
+//!
+//! Every endpoint has a packet buffer of 64 bytes in RAM, which the core reads and writes using
+//! DMA. Data written to an IN endpoint is copied to its buffer before the transfer is started, and
+//! OUT endpoints are re-armed as soon as the received packet has been read.
+//!
+//! Besides the buffers in RAM, every IN endpoint has a dedicated transmit FIFO in the FIFO RAM of
+//! the core, which is divided up by `fifo::FifoAllocator` when the endpoints are known.
+
+use super::fifo::{ENDPOINTS, MAX_PACKET_SIZE};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use efm32hg309f64;
+use nb;
+
+static mut IN_BUFFERS: [[u32; 16]; ENDPOINTS] = [[0; 16]; ENDPOINTS];
+static mut OUT_BUFFERS: [[u32; 16]; ENDPOINTS] = [[0; 16]; ENDPOINTS];
+
+// A bit mask of the OUT endpoints which have received a packet which has not been read yet
+static OUT_READY: AtomicUsize = AtomicUsize::new(0);
+
+// Runs `$body` with the `CTL`, `INT`, `TSIZ` and `DMAADDR` registers of IN endpoint 1 to 3
+// bound to the given names.
+macro_rules! in_endpoint {
+    ($usb:expr, $index:expr, |$ctl:ident, $int:ident, $tsiz:ident, $dmaaddr:ident| $body:expr) => {
+        match $index {
+            1 => {
+                let ($ctl, $int) = (&$usb.diep0_ctl, &$usb.diep0_int);
+                let ($tsiz, $dmaaddr) = (&$usb.diep0_tsiz, &$usb.diep0_dmaaddr);
+                $body
+            }
+            2 => {
+                let ($ctl, $int) = (&$usb.diep1_ctl, &$usb.diep1_int);
+                let ($tsiz, $dmaaddr) = (&$usb.diep1_tsiz, &$usb.diep1_dmaaddr);
+                $body
+            }
+            _ => {
+                let ($ctl, $int) = (&$usb.diep2_ctl, &$usb.diep2_int);
+                let ($tsiz, $dmaaddr) = (&$usb.diep2_tsiz, &$usb.diep2_dmaaddr);
+                $body
+            }
+        }
+    };
+}
+
+// Runs `$body` with the `CTL`, `INT`, `TSIZ` and `DMAADDR` registers of OUT endpoint 1 to 3
+// bound to the given names.
+macro_rules! out_endpoint {
+    ($usb:expr, $index:expr, |$ctl:ident, $int:ident, $tsiz:ident, $dmaaddr:ident| $body:expr) => {
+        match $index {
+            1 => {
+                let ($ctl, $int) = (&$usb.doep0_ctl, &$usb.doep0_int);
+                let ($tsiz, $dmaaddr) = (&$usb.doep0_tsiz, &$usb.doep0_dmaaddr);
+                $body
+            }
+            2 => {
+                let ($ctl, $int) = (&$usb.doep1_ctl, &$usb.doep1_int);
+                let ($tsiz, $dmaaddr) = (&$usb.doep1_tsiz, &$usb.doep1_dmaaddr);
+                $body
+            }
+            _ => {
+                let ($ctl, $int) = (&$usb.doep2_ctl, &$usb.doep2_int);
+                let ($tsiz, $dmaaddr) = (&$usb.doep2_tsiz, &$usb.doep2_dmaaddr);
+                $body
+            }
+        }
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The address does not belong to an active endpoint in the right direction.
+    InvalidEndpoint,
+    /// The data does not fit in a single packet, or the buffer is too small for the packet.
+    BufferOverflow,
+}
+
+/// Activates IN endpoint `index` with its own transmit FIFO, by writing `USB_DIEPx_CTL`. The
+/// endpoint type is given as the lower 2 bits of `bmAttributes` in the endpoint descriptor.
+pub(crate) fn activate_in(index: usize, ep_type: u8, max_packet_size: u16) {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    in_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+        ctl.write(|w| unsafe {
+            w.mps()
+                .bits(max_packet_size)
+                .eptype()
+                .bits(ep_type)
+                .txfnum()
+                .bits(index as u8)
+                .usbactep()
+                .set_bit()
+                .setd0pidef()
+                .set_bit()
+                .snak()
+                .set_bit()
+        })
+    });
+    usb.daintmsk
+        .modify(|r, w| unsafe { w.bits(r.bits() | 1 << index) });
+}
+
+/// Activates OUT endpoint `index` by writing `USB_DOEPx_CTL`, and starts receiving the first
+/// packet.
+pub(crate) fn activate_out(index: usize, ep_type: u8, max_packet_size: u16) {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    out_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+        ctl.write(|w| unsafe {
+            w.mps()
+                .bits(max_packet_size)
+                .eptype()
+                .bits(ep_type)
+                .usbactep()
+                .set_bit()
+                .setd0pidef()
+                .set_bit()
+        })
+    });
+    usb.daintmsk
+        .modify(|r, w| unsafe { w.bits(r.bits() | 1 << (16 + index)) });
+    OUT_READY.fetch_and(!(1 << index), Ordering::Release);
+    arm_out(index, max_packet_size);
+}
+
+/// Deactivates endpoint 1 to 3 in both directions by clearing the `USBACTEP` bits in
+/// `USB_DIEPx_CTL` and `USB_DOEPx_CTL`, and disabling any transfers in progress.
+pub(crate) fn deactivate_all() {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    for index in 1..ENDPOINTS {
+        in_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+            ctl.modify(|r, w| {
+                w.usbactep()
+                    .clear_bit()
+                    .snak()
+                    .set_bit()
+                    .epdis()
+                    .bit(r.epena().bit_is_set())
+            })
+        });
+        out_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+            ctl.modify(|r, w| {
+                w.usbactep()
+                    .clear_bit()
+                    .snak()
+                    .set_bit()
+                    .epdis()
+                    .bit(r.epena().bit_is_set())
+            })
+        });
+    }
+    usb.daintmsk
+        .modify(|r, w| unsafe { w.bits(r.bits() & 0x0001_0001) });
+    OUT_READY.store(0, Ordering::Release);
+}
+
+/// Starts receiving a packet on OUT endpoint `index` into its buffer, by setting the `EPENA` and
+/// `CNAK` bits in `USB_DOEPx_CTL`.
+pub(crate) fn arm_out(index: usize, max_packet_size: u16) {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    let buffer = unsafe { OUT_BUFFERS[index].as_ptr() as u32 };
+    if index == 0 {
+        usb.doep0dmaaddr.write(|w| unsafe { w.bits(buffer) });
+        usb.doep0tsiz.write(|w| unsafe {
+            w.supcnt()
+                .bits(3)
+                .pktcnt()
+                .set_bit()
+                .xfersize()
+                .bits(max_packet_size as u8)
+        });
+        usb.doep0ctl
+            .modify(|_, w| w.epena().set_bit().cnak().set_bit());
+    } else {
+        out_endpoint!(usb, index, |ctl, _int, tsiz, dmaaddr| {
+            dmaaddr.write(|w| unsafe { w.bits(buffer) });
+            tsiz.write(|w| unsafe {
+                w.pktcnt()
+                    .bits(1)
+                    .xfersize()
+                    .bits(max_packet_size.into())
+            });
+            ctl.modify(|_, w| w.epena().set_bit().cnak().set_bit());
+        })
+    }
+}
+
+/// Whether a transfer is still in progress on IN endpoint `index`, which is the case while the
+/// `EPENA` bit in `USB_DIEPx_CTL` is set.
+pub(crate) fn in_busy(index: usize) -> bool {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    match index {
+        0 => usb.diep0ctl.read().epena().bit_is_set(),
+        _ => in_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+            ctl.read().epena().bit_is_set()
+        }),
+    }
+}
+
+/// Copies `data` to the buffer of IN endpoint `index` and starts sending it as a single packet.
+/// The endpoint must not be busy.
+pub(crate) fn start_in(index: usize, data: &[u8]) {
+    debug_assert!(data.len() <= MAX_PACKET_SIZE as usize);
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    let buffer = unsafe {
+        let buffer = &mut *(&mut IN_BUFFERS[index] as *mut [u32; 16] as *mut [u8; 64]);
+        buffer[..data.len()].copy_from_slice(data);
+        buffer.as_ptr() as u32
+    };
+    if index == 0 {
+        usb.diep0dmaaddr.write(|w| unsafe { w.bits(buffer) });
+        usb.diep0tsiz.write(|w| unsafe {
+            w.pktcnt()
+                .bits(1)
+                .xfersize()
+                .bits(data.len() as u8)
+        });
+        usb.diep0ctl
+            .modify(|_, w| w.epena().set_bit().cnak().set_bit());
+    } else {
+        in_endpoint!(usb, index, |ctl, _int, tsiz, dmaaddr| {
+            dmaaddr.write(|w| unsafe { w.bits(buffer) });
+            tsiz.write(|w| unsafe {
+                w.pktcnt()
+                    .bits(1)
+                    .xfersize()
+                    .bits(data.len() as u32)
+            });
+            ctl.modify(|_, w| w.epena().set_bit().cnak().set_bit());
+        });
+    }
+}
+
+/// Whether OUT endpoint `index` has received a packet which has not been read yet.
+pub(crate) fn out_ready(index: usize) -> bool {
+    OUT_READY.load(Ordering::Acquire) & (1 << index) != 0
+}
+
+/// A bit mask of the OUT endpoints which have received a packet which has not been read yet.
+pub(crate) fn out_ready_mask() -> u16 {
+    OUT_READY.load(Ordering::Acquire) as u16
+}
+
+pub(crate) fn set_out_ready(index: usize) {
+    OUT_READY.fetch_or(1 << index, Ordering::Release);
+}
+
+/// Copies the packet received on OUT endpoint `index` to `buf` and re-arms the endpoint. The
+/// endpoint must have received a packet.
+pub(crate) fn take_out(
+    index: usize,
+    max_packet_size: u16,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+
+    // The `XFERSIZE` subfield counts down as data is received
+    let remaining = match index {
+        0 => usb.doep0tsiz.read().xfersize().bits() as usize,
+        _ => out_endpoint!(usb, index, |_ctl, _int, tsiz, _dmaaddr| {
+            tsiz.read().xfersize().bits() as usize
+        }),
+    };
+    let len = max_packet_size as usize - remaining;
+    if buf.len() < len {
+        return Err(Error::BufferOverflow);
+    }
+
+    let buffer = unsafe { &*(&OUT_BUFFERS[index] as *const [u32; 16] as *const [u8; 64]) };
+    buf[..len].copy_from_slice(&buffer[..len]);
+    OUT_READY.fetch_and(!(1 << index), Ordering::Release);
+    arm_out(index, max_packet_size);
+    Ok(len)
+}
+
+/// Clears the interrupt flags of endpoint 1 to 3 in `USB_DIEPx_INT` and `USB_DOEPx_INT`, and
+/// marks the OUT endpoints which have received a packet. Returns a bit mask of the IN endpoints
+/// which have finished sending a packet.
+pub(crate) fn handle_interrupts() -> u16 {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    let daint = usb.daint.read().bits();
+    let mut in_complete = 0;
+
+    for index in 1..ENDPOINTS {
+        if daint & (1 << index) != 0 {
+            let complete = in_endpoint!(usb, index, |_ctl, int, _tsiz, _dmaaddr| {
+                let r = int.read();
+                int.write(|w| unsafe { w.bits(r.bits()) });
+                r.xfercompl().bit_is_set()
+            });
+            if complete {
+                in_complete |= 1 << index;
+            }
+        }
+        if daint & (1 << (16 + index)) != 0 {
+            let complete = out_endpoint!(usb, index, |_ctl, int, _tsiz, _dmaaddr| {
+                let r = int.read();
+                int.write(|w| unsafe { w.bits(r.bits()) });
+                r.xfercompl().bit_is_set()
+            });
+            if complete {
+                set_out_ready(index);
+            }
+        }
+    }
+    in_complete
+}
+
+fn active_index(address: u8, is_in: bool) -> Result<usize, Error> {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    let index = (address & 0x7f) as usize;
+    if (address & 0x80 != 0) != is_in || index == 0 || index >= ENDPOINTS {
+        return Err(Error::InvalidEndpoint);
+    }
+    let active = if is_in {
+        in_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+            ctl.read().usbactep().bit_is_set()
+        })
+    } else {
+        out_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+            ctl.read().usbactep().bit_is_set()
+        })
+    };
+    if active {
+        Ok(index)
+    } else {
+        Err(Error::InvalidEndpoint)
+    }
+}
+
+fn max_packet_size(address: u8) -> u16 {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    let index = (address & 0x7f) as usize;
+    if address & 0x80 != 0 {
+        in_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| ctl.read().mps().bits())
+    } else {
+        out_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| ctl.read().mps().bits())
+    }
+}
+
+/// Sends `data` as a single packet on the IN endpoint with the given address. Fails with
+/// `WouldBlock` while the previous packet is still being sent.
+///
+/// An empty `data` sends a zero-length packet, which is used to end a transfer which is a
+/// multiple of the packet size.
+pub fn write(address: u8, data: &[u8]) -> nb::Result<(), Error> {
+    let index = active_index(address, true)?;
+    if data.len() > max_packet_size(address) as usize {
+        return Err(nb::Error::Other(Error::BufferOverflow));
+    }
+    if in_busy(index) {
+        return Err(nb::Error::WouldBlock);
+    }
+    start_in(index, data);
+    Ok(())
+}
+
+/// Reads a packet from the OUT endpoint with the given address into `buf`, returning its length.
+/// Fails with `WouldBlock` if no packet has been received since the last read.
+///
+/// Packets are only marked as received by `UsbDevice::poll`, so it has to be called regularly.
+pub fn read(address: u8, buf: &mut [u8]) -> nb::Result<usize, Error> {
+    let index = active_index(address, false)?;
+    if !out_ready(index) {
+        return Err(nb::Error::WouldBlock);
+    }
+    Ok(take_out(index, max_packet_size(address), buf)?)
+}
+
+/// Stalls the endpoint with the given address, which is how some classes signal an error on a
+/// data endpoint. The stall lasts until the host clears it with `CLEAR_FEATURE(ENDPOINT_HALT)`,
+/// and `is_stalled` tells when that has happened.
+pub fn stall(address: u8) -> Result<(), Error> {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    if address & 0x80 != 0 {
+        let index = active_index(address, true)?;
+        in_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+            ctl.modify(|_, w| w.stall().set_bit())
+        });
+    } else {
+        let index = active_index(address, false)?;
+        out_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+            ctl.modify(|_, w| w.stall().set_bit())
+        });
+    }
+    Ok(())
+}
+
+/// Whether the endpoint with the given address is stalled.
+pub fn is_stalled(address: u8) -> bool {
+    let usb = unsafe { &*efm32hg309f64::USB::ptr() };
+    let index = (address & 0x7f) as usize;
+    if index == 0 || index >= ENDPOINTS {
+        return false;
+    }
+    if address & 0x80 != 0 {
+        in_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+            ctl.read().stall().bit_is_set()
+        })
+    } else {
+        out_endpoint!(usb, index, |ctl, _int, _tsiz, _dmaaddr| {
+            ctl.read().stall().bit_is_set()
+        })
+    }
+}
+
+impl From<Error> for nb::Error<Error> {
+    #[inline]
+    fn from(error: Error) -> nb::Error<Error> {
+        nb::Error::Other(error)
+    }
+}
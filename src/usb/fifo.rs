@@ -0,0 +1,127 @@
+//! The division of the 2 KB of FIFO RAM in the core between the receive FIFO and the transmit
+//! FIFOs of the IN endpoints. This is shared by `UsbDevice`, `bus::UsbBus` and the validation of
+//! configuration descriptors, and does not touch the hardware.
+
+use core::cmp;
+
+pub(crate) const ENDPOINTS: usize = 4;
+pub(crate) const MAX_PACKET_SIZE: u16 = 64;
+
+// The USB core has 2 KB of FIFO RAM, shared by all of the FIFOs. This is counted in words.
+const FIFO_RAM_SIZE: u16 = 512;
+// Room for the SETUP packets, the status information of each packet and one packet of the
+// largest allowed size. See the description of `USB_GRXFSIZ` in EFM32HG-RM.pdf.
+const RX_FIFO_SIZE: u16 = 10 + 2 * (MAX_PACKET_SIZE / 4 + 1) + 1;
+// The transmit FIFOs must be at least 16 words deep
+const MIN_TX_FIFO_SIZE: u16 = 16;
+
+/// The sizes in words of the receive FIFO shared by all OUT endpoints, and of the transmit FIFO of
+/// each IN endpoint. Endpoint 0 uses the non-periodic transmit FIFO set up in `USB_GNPTXFSIZ`,
+/// while endpoint 1 to 3 use the ones in `USB_DIEPTXF1` to `USB_DIEPTXF3`. A size of 0 means
+/// that the endpoint is not used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FifoLayout {
+    pub rx: u16,
+    pub tx: [u16; ENDPOINTS],
+}
+
+/// Divides the FIFO RAM between the receive FIFO and a transmit FIFO for every IN endpoint in
+/// use, including endpoint 0. The FIFOs are placed in the order of the endpoints, right after the
+/// receive FIFO.
+///
+/// As long as no packet is larger than `MAX_PACKET_SIZE`, which `validate_configuration` and
+/// `bus::UsbBus` make sure of, every endpoint fits: the worst case takes 45 + 4 * 16 of the 512
+/// words.
+pub(crate) struct FifoAllocator {
+    layout: FifoLayout,
+}
+
+impl FifoAllocator {
+    pub(crate) fn new() -> FifoAllocator {
+        FifoAllocator {
+            layout: FifoLayout {
+                rx: RX_FIFO_SIZE,
+                tx: [0; ENDPOINTS],
+            },
+        }
+    }
+
+    /// Makes sure IN endpoint `index` has a transmit FIFO which fits a packet of
+    /// `max_packet_size` bytes. An endpoint used with several packet sizes, such as by alternate
+    /// settings, gets a FIFO for the largest of them.
+    ///
+    /// Returns false, and leaves the layout as it was, if the FIFOs would no longer fit in the
+    /// FIFO RAM.
+    pub(crate) fn allocate_in(&mut self, index: usize, max_packet_size: u16) -> bool {
+        if index >= ENDPOINTS {
+            return false;
+        }
+        let size = cmp::max(MIN_TX_FIFO_SIZE, (max_packet_size + 3) / 4);
+        let size = cmp::max(size, self.layout.tx[index]);
+        let used = self.used() - self.layout.tx[index] + size;
+        if used > FIFO_RAM_SIZE {
+            return false;
+        }
+        self.layout.tx[index] = size;
+        true
+    }
+
+    /// The number of words taken by the FIFOs allocated so far.
+    fn used(&self) -> u16 {
+        self.layout.rx + self.layout.tx.iter().sum::<u16>()
+    }
+
+    #[inline]
+    pub(crate) fn layout(&self) -> FifoLayout {
+        self.layout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_in() {
+        let mut allocator = FifoAllocator::new();
+        assert_eq!(
+            allocator.layout(),
+            FifoLayout {
+                rx: RX_FIFO_SIZE,
+                tx: [0; ENDPOINTS],
+            }
+        );
+        assert!(allocator.allocate_in(0, 64));
+        assert!(allocator.allocate_in(2, 8));
+        assert_eq!(allocator.layout().tx, [16, 0, 16, 0]);
+        assert!(!allocator.allocate_in(ENDPOINTS, 64));
+        assert_eq!(allocator.layout().tx, [16, 0, 16, 0]);
+    }
+
+    #[test]
+    fn largest_size() {
+        let mut allocator = FifoAllocator::new();
+        assert!(allocator.allocate_in(1, 128));
+        assert!(allocator.allocate_in(1, 64));
+        assert_eq!(allocator.layout().tx, [0, 32, 0, 0]);
+        assert!(allocator.allocate_in(1, 130));
+        assert_eq!(allocator.layout().tx, [0, 33, 0, 0]);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut allocator = FifoAllocator::new();
+        assert!(allocator.allocate_in(0, 64));
+        assert!(allocator.allocate_in(1, 1024));
+        let layout = allocator.layout();
+        assert_eq!(layout.tx, [16, 256, 0, 0]);
+        // 45 + 16 + 256 + 200 words would be more than the 512 there are
+        assert!(!allocator.allocate_in(2, 800));
+        assert_eq!(allocator.layout(), layout);
+        // Growing an existing FIFO is checked in the same way
+        assert!(!allocator.allocate_in(1, 1860));
+        assert_eq!(allocator.layout(), layout);
+        assert!(allocator.allocate_in(1, 1804));
+        assert_eq!(allocator.used(), FIFO_RAM_SIZE);
+    }
+}
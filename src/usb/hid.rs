@@ -0,0 +1,944 @@
+//! Human interface devices, such as keyboards and mice. See the Device Class Definition for HID
+//! 1.11 and the HID Usage Tables.
+//!
+//! `Hid` implements the class requests for a single HID interface with an interrupt IN endpoint,
+//! and works with any report descriptor. `Keyboard` and `Mouse` build on it with reports in the
+//! boot protocol format, so they also work in BIOS setup screens and other hosts without a
+//! report descriptor parser.
+
+#[cfg(not(test))]
+use super::{descriptor_type, endpoint, Class, ControlResult, Recipient, RequestKind, SetupPacket};
+use super::{ConfigurationBuilder, DescriptorError, Descriptors, STRINGS, TESTBOARD_DEVICE};
+#[cfg(not(test))]
+use core::cmp;
+#[cfg(not(test))]
+use nb;
+
+/// The class-specific requests. See chapter 7.2 in the HID specification.
+pub mod request {
+    pub const GET_REPORT: u8 = 0x01;
+    pub const GET_IDLE: u8 = 0x02;
+    pub const GET_PROTOCOL: u8 = 0x03;
+    pub const SET_REPORT: u8 = 0x09;
+    pub const SET_IDLE: u8 = 0x0a;
+    pub const SET_PROTOCOL: u8 = 0x0b;
+}
+
+/// The class-specific descriptor types. See chapter 7.1 in the HID specification.
+pub mod hid_descriptor_type {
+    pub const HID: u8 = 0x21;
+    pub const REPORT: u8 = 0x22;
+}
+
+/// The values used with `GET_PROTOCOL` and `SET_PROTOCOL`.
+pub mod protocol {
+    pub const BOOT: u8 = 0;
+    pub const REPORT: u8 = 1;
+}
+
+/// The data bits of `Input`, `Output` and `Feature` items. See chapter 6.2.2.5 in the HID
+/// specification.
+pub mod flags {
+    pub const DATA: u8 = 0x00;
+    pub const CONSTANT: u8 = 0x01;
+    pub const ARRAY: u8 = 0x00;
+    pub const VARIABLE: u8 = 0x02;
+    pub const ABSOLUTE: u8 = 0x00;
+    pub const RELATIVE: u8 = 0x04;
+}
+
+/// The data of `Collection` items.
+pub mod collection {
+    pub const PHYSICAL: u8 = 0x00;
+    pub const APPLICATION: u8 = 0x01;
+    pub const LOGICAL: u8 = 0x02;
+}
+
+/// Writes a report descriptor, one item at a time, into a buffer. Each item is encoded as a
+/// short item with the smallest data size which fits the value.
+///
+/// ```ignore
+/// let descriptor = ReportDescriptorBuilder::new(unsafe { &mut BUFFER })
+///     .usage_page(0x01)
+///     .usage(0x05)
+///     .collection(collection::APPLICATION)
+///     ...
+///     .end_collection()
+///     .build();
+/// ```
+pub struct ReportDescriptorBuilder {
+    buffer: &'static mut [u8],
+    len: usize,
+    depth: u8,
+    overflow: bool,
+}
+
+impl ReportDescriptorBuilder {
+    pub fn new(buffer: &'static mut [u8]) -> ReportDescriptorBuilder {
+        ReportDescriptorBuilder {
+            buffer,
+            len: 0,
+            depth: 0,
+            overflow: false,
+        }
+    }
+
+    fn item(mut self, prefix: u8, data: &[u8]) -> Self {
+        let size = match data.len() {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            _ => 3,
+        };
+        if self.len + 1 + data.len() > self.buffer.len() {
+            self.overflow = true;
+            return self;
+        }
+        self.buffer[self.len] = prefix | size;
+        self.buffer[self.len + 1..self.len + 1 + data.len()].copy_from_slice(data);
+        self.len += 1 + data.len();
+        self
+    }
+
+    fn unsigned(self, prefix: u8, value: u32) -> Self {
+        let bytes = [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8];
+        let len = match value {
+            0..=0xff => 1,
+            0x100..=0xffff => 2,
+            _ => 4,
+        };
+        self.item(prefix, &bytes[..len])
+    }
+
+    fn signed(self, prefix: u8, value: i32) -> Self {
+        let bytes = [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8];
+        let len = match value {
+            -0x80..=0x7f => 1,
+            -0x8000..=0x7fff => 2,
+            _ => 4,
+        };
+        self.item(prefix, &bytes[..len])
+    }
+
+    pub fn usage_page(self, page: u16) -> Self {
+        self.unsigned(0x04, page.into())
+    }
+
+    pub fn usage(self, usage: u16) -> Self {
+        self.unsigned(0x08, usage.into())
+    }
+
+    pub fn usage_minimum(self, usage: u16) -> Self {
+        self.unsigned(0x18, usage.into())
+    }
+
+    pub fn usage_maximum(self, usage: u16) -> Self {
+        self.unsigned(0x28, usage.into())
+    }
+
+    pub fn logical_minimum(self, value: i32) -> Self {
+        self.signed(0x14, value)
+    }
+
+    pub fn logical_maximum(self, value: i32) -> Self {
+        self.signed(0x24, value)
+    }
+
+    /// The size of each field in bits.
+    pub fn report_size(self, bits: u8) -> Self {
+        self.unsigned(0x74, bits.into())
+    }
+
+    pub fn report_count(self, count: u8) -> Self {
+        self.unsigned(0x94, count.into())
+    }
+
+    /// Prefixes the following reports with the given id, which must not be 0.
+    pub fn report_id(self, id: u8) -> Self {
+        self.unsigned(0x84, id.into())
+    }
+
+    /// `flags` is a combination of the values in `flags`.
+    pub fn input(self, flags: u8) -> Self {
+        self.unsigned(0x80, flags.into())
+    }
+
+    pub fn output(self, flags: u8) -> Self {
+        self.unsigned(0x90, flags.into())
+    }
+
+    pub fn feature(self, flags: u8) -> Self {
+        self.unsigned(0xb0, flags.into())
+    }
+
+    /// `kind` is one of the values in `collection`.
+    pub fn collection(mut self, kind: u8) -> Self {
+        self.depth += 1;
+        self.unsigned(0xa0, kind.into())
+    }
+
+    pub fn end_collection(mut self) -> Self {
+        if self.depth == 0 {
+            self.overflow = true;
+        }
+        self.depth = self.depth.saturating_sub(1);
+        self.item(0xc0, &[])
+    }
+
+    /// The finished descriptor, or `None` if the buffer was too small or the collections are
+    /// not balanced.
+    pub fn build(self) -> Option<&'static [u8]> {
+        if self.overflow || self.depth != 0 {
+            return None;
+        }
+        let len = self.len;
+        let buffer: &'static [u8] = self.buffer;
+        Some(&buffer[..len])
+    }
+}
+
+/// A single HID interface. The configuration descriptor has to contain the interface descriptor,
+/// followed by the HID descriptor and an interrupt IN endpoint.
+///
+/// Reports are only sent when they are written, which corresponds to an idle rate of 0. Hosts
+/// asking for another idle rate get it reported back, but reports are not repeated.
+#[cfg(not(test))]
+pub struct Hid {
+    interface: u8,
+    endpoint: u8,
+    hid_descriptor: &'static [u8],
+    report_descriptor: &'static [u8],
+    configured: bool,
+    protocol: u8,
+    idle: u8,
+    last_report: [u8; 8],
+    last_report_len: usize,
+    output: u8,
+}
+
+#[cfg(not(test))]
+impl Hid {
+    /// Looks up interface `interface` in the configuration descriptor of `descriptors`. Returns
+    /// `None` if the interface, its HID descriptor or its IN endpoint is missing.
+    pub fn new(
+        descriptors: &Descriptors,
+        interface: u8,
+        report_descriptor: &'static [u8],
+    ) -> Option<Hid> {
+        let configuration = descriptors.configuration;
+        let mut in_interface = false;
+        let mut hid_descriptor = None;
+        let mut endpoint = None;
+
+        let mut offset = 0;
+        while offset + 2 <= configuration.len() {
+            let length = cmp::max(configuration[offset] as usize, 2);
+            let descriptor = &configuration[offset..cmp::min(offset + length, configuration.len())];
+            offset += length;
+
+            match descriptor[1] {
+                descriptor_type::INTERFACE if descriptor.len() >= 4 => {
+                    in_interface = descriptor[2] == interface && descriptor[3] == 0;
+                }
+                hid_descriptor_type::HID if in_interface => hid_descriptor = Some(descriptor),
+                descriptor_type::ENDPOINT if in_interface && descriptor.len() >= 7 => {
+                    if descriptor[2] & 0x80 != 0 && endpoint.is_none() {
+                        endpoint = Some(descriptor[2]);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Some(Hid {
+            interface,
+            endpoint: endpoint?,
+            hid_descriptor: hid_descriptor?,
+            report_descriptor,
+            configured: false,
+            protocol: protocol::REPORT,
+            idle: 0,
+            last_report: [0; 8],
+            last_report_len: 0,
+            output: 0,
+        })
+    }
+
+    /// Whether the host has selected the boot protocol with `SET_PROTOCOL`.
+    #[inline]
+    pub fn boot_protocol(&self) -> bool {
+        self.protocol == protocol::BOOT
+    }
+
+    /// The first byte of the last output report sent by the host, which holds the LED states for
+    /// keyboards.
+    #[inline]
+    pub fn output(&self) -> u8 {
+        self.output
+    }
+
+    /// Sends a report on the IN endpoint. Fails with `WouldBlock` while the previous report is
+    /// still waiting for the host to pick it up.
+    pub fn send_report(&mut self, report: &[u8]) -> nb::Result<(), endpoint::Error> {
+        if !self.configured {
+            return Err(nb::Error::Other(endpoint::Error::InvalidEndpoint));
+        }
+        endpoint::write(self.endpoint, report)?;
+        let len = cmp::min(report.len(), self.last_report.len());
+        self.last_report[..len].copy_from_slice(&report[..len]);
+        self.last_report_len = len;
+        Ok(())
+    }
+}
+
+#[cfg(not(test))]
+impl Class for Hid {
+    fn reset(&mut self) {
+        self.set_configuration(0);
+    }
+
+    fn set_configuration(&mut self, configuration: u8) {
+        self.configured = configuration != 0;
+        self.protocol = protocol::REPORT;
+        self.idle = 0;
+        self.last_report_len = 0;
+    }
+
+    fn get_descriptor(&mut self, setup: &SetupPacket) -> Option<&'static [u8]> {
+        if setup.recipient() != Recipient::Interface || setup.index != u16::from(self.interface) {
+            return None;
+        }
+        match (setup.value >> 8) as u8 {
+            hid_descriptor_type::HID => Some(self.hid_descriptor),
+            hid_descriptor_type::REPORT => Some(self.report_descriptor),
+            _ => None,
+        }
+    }
+
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> ControlResult {
+        if setup.kind() != RequestKind::Class
+            || setup.recipient() != Recipient::Interface
+            || setup.index != u16::from(self.interface)
+        {
+            return ControlResult::Ignored;
+        }
+
+        match setup.request {
+            request::GET_REPORT => {
+                let len = cmp::min(self.last_report_len, data.len());
+                data[..len].copy_from_slice(&self.last_report[..len]);
+                ControlResult::Accepted(len)
+            }
+            request::SET_REPORT => {
+                if let Some(&output) = data.first() {
+                    self.output = output;
+                }
+                ControlResult::Accepted(0)
+            }
+            request::GET_IDLE if !data.is_empty() => {
+                data[0] = self.idle;
+                ControlResult::Accepted(1)
+            }
+            request::SET_IDLE => {
+                self.idle = (setup.value >> 8) as u8;
+                ControlResult::Accepted(0)
+            }
+            request::GET_PROTOCOL if !data.is_empty() => {
+                data[0] = self.protocol;
+                ControlResult::Accepted(1)
+            }
+            request::SET_PROTOCOL if setup.value <= 1 => {
+                self.protocol = setup.value as u8;
+                ControlResult::Accepted(0)
+            }
+            _ => ControlResult::Rejected,
+        }
+    }
+}
+
+/// The bits of the modifier byte in keyboard reports.
+pub mod modifier {
+    pub const LEFT_CTRL: u8 = 0x01;
+    pub const LEFT_SHIFT: u8 = 0x02;
+    pub const LEFT_ALT: u8 = 0x04;
+    pub const LEFT_GUI: u8 = 0x08;
+    pub const RIGHT_CTRL: u8 = 0x10;
+    pub const RIGHT_SHIFT: u8 = 0x20;
+    pub const RIGHT_ALT: u8 = 0x40;
+    pub const RIGHT_GUI: u8 = 0x80;
+}
+
+/// Some of the key codes from the Keyboard/Keypad usage page. See chapter 10 in the HID Usage
+/// Tables.
+pub mod key_code {
+    pub const A: u8 = 0x04;
+    pub const Z: u8 = 0x1d;
+    pub const N1: u8 = 0x1e;
+    pub const N0: u8 = 0x27;
+    pub const ENTER: u8 = 0x28;
+    pub const ESCAPE: u8 = 0x29;
+    pub const BACKSPACE: u8 = 0x2a;
+    pub const TAB: u8 = 0x2b;
+    pub const SPACE: u8 = 0x2c;
+    pub const F1: u8 = 0x3a;
+    pub const F12: u8 = 0x45;
+    pub const RIGHT_ARROW: u8 = 0x4f;
+    pub const LEFT_ARROW: u8 = 0x50;
+    pub const DOWN_ARROW: u8 = 0x51;
+    pub const UP_ARROW: u8 = 0x52;
+}
+
+/// A key together with the modifiers held while pressing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub modifiers: u8,
+    pub code: u8,
+}
+
+impl Key {
+    #[inline]
+    pub fn new(code: u8) -> Key {
+        Key { modifiers: 0, code }
+    }
+
+    #[inline]
+    pub fn with_modifiers(self, modifiers: u8) -> Key {
+        Key {
+            modifiers: self.modifiers | modifiers,
+            code: self.code,
+        }
+    }
+
+    /// The key typing `c` on a US keyboard layout, if there is one.
+    pub fn from_ascii(c: u8) -> Option<Key> {
+        // The unshifted and shifted characters of the punctuation keys, with their key codes
+        const SYMBOLS: [(u8, u8, u8); 10] = [
+            (b'-', b'_', 0x2d),
+            (b'=', b'+', 0x2e),
+            (b'[', b'{', 0x2f),
+            (b']', b'}', 0x30),
+            (b'\\', b'|', 0x31),
+            (b';', b':', 0x33),
+            (b'\'', b'"', 0x34),
+            (b'`', b'~', 0x35),
+            (b',', b'<', 0x36),
+            (b'.', b'>', 0x37),
+        ];
+        const SHIFTED_DIGITS: &[u8; 10] = b"!@#$%^&*()";
+        let shift = modifier::LEFT_SHIFT;
+
+        let key = match c {
+            b'a'..=b'z' => Key::new(key_code::A + (c - b'a')),
+            b'A'..=b'Z' => Key::new(key_code::A + (c - b'A')).with_modifiers(shift),
+            b'1'..=b'9' => Key::new(key_code::N1 + (c - b'1')),
+            b'0' => Key::new(key_code::N0),
+            b'\n' => Key::new(key_code::ENTER),
+            b'\t' => Key::new(key_code::TAB),
+            b' ' => Key::new(key_code::SPACE),
+            b'/' => Key::new(0x38),
+            b'?' => Key::new(0x38).with_modifiers(shift),
+            _ => {
+                if let Some(i) = SHIFTED_DIGITS.iter().position(|&s| s == c) {
+                    Key::new(key_code::N1 + i as u8).with_modifiers(shift)
+                } else if let Some(&(_, _, code)) = SYMBOLS.iter().find(|s| s.0 == c) {
+                    Key::new(code)
+                } else if let Some(&(_, _, code)) = SYMBOLS.iter().find(|s| s.1 == c) {
+                    Key::new(code).with_modifiers(shift)
+                } else {
+                    return None;
+                }
+            }
+        };
+        Some(key)
+    }
+}
+
+/// The size of the report descriptor built by `keyboard_report_descriptor`, which is the smallest
+/// buffer it accepts.
+pub const KEYBOARD_REPORT_DESCRIPTOR_LENGTH: usize = 63;
+
+/// Builds the report descriptor of a boot protocol compatible keyboard into `buffer`: a modifier
+/// byte, a reserved byte and up to 6 pressed keys, plus 5 LEDs in the output report. Returns
+/// `None` if `buffer` is too small.
+pub fn keyboard_report_descriptor(buffer: &'static mut [u8]) -> Option<&'static [u8]> {
+    ReportDescriptorBuilder::new(buffer)
+        .usage_page(0x01) // Generic Desktop
+        .usage(0x06) // Keyboard
+        .collection(collection::APPLICATION)
+        // Modifier byte, from Left Control to Right GUI
+        .usage_page(0x07) // Keyboard/Keypad
+        .usage_minimum(0xe0)
+        .usage_maximum(0xe7)
+        .logical_minimum(0)
+        .logical_maximum(1)
+        .report_size(1)
+        .report_count(8)
+        .input(flags::DATA | flags::VARIABLE | flags::ABSOLUTE)
+        // Reserved byte
+        .report_count(1)
+        .report_size(8)
+        .input(flags::CONSTANT)
+        // LEDs, from Num Lock to Kana, and their padding
+        .report_count(5)
+        .report_size(1)
+        .usage_page(0x08) // LEDs
+        .usage_minimum(0x01)
+        .usage_maximum(0x05)
+        .output(flags::DATA | flags::VARIABLE | flags::ABSOLUTE)
+        .report_count(1)
+        .report_size(3)
+        .output(flags::CONSTANT)
+        // Pressed keys
+        .report_count(6)
+        .report_size(8)
+        .logical_minimum(0)
+        .logical_maximum(101)
+        .usage_page(0x07) // Keyboard/Keypad
+        .usage_minimum(0)
+        .usage_maximum(101)
+        .input(flags::DATA | flags::ARRAY)
+        .end_collection()
+        .build()
+}
+
+/// The size of the report descriptor built by `mouse_report_descriptor`, which is the smallest
+/// buffer it accepts.
+pub const MOUSE_REPORT_DESCRIPTOR_LENGTH: usize = 52;
+
+/// Builds the report descriptor of a boot protocol compatible mouse into `buffer`: 3 buttons and
+/// relative X and Y, followed by a wheel which is left out in the boot protocol. Returns `None` if
+/// `buffer` is too small.
+pub fn mouse_report_descriptor(buffer: &'static mut [u8]) -> Option<&'static [u8]> {
+    ReportDescriptorBuilder::new(buffer)
+        .usage_page(0x01) // Generic Desktop
+        .usage(0x02) // Mouse
+        .collection(collection::APPLICATION)
+        .usage(0x01) // Pointer
+        .collection(collection::PHYSICAL)
+        // Buttons and their padding
+        .usage_page(0x09) // Button
+        .usage_minimum(1)
+        .usage_maximum(3)
+        .logical_minimum(0)
+        .logical_maximum(1)
+        .report_count(3)
+        .report_size(1)
+        .input(flags::DATA | flags::VARIABLE | flags::ABSOLUTE)
+        .report_count(1)
+        .report_size(5)
+        .input(flags::CONSTANT)
+        // X, Y and the wheel
+        .usage_page(0x01) // Generic Desktop
+        .usage(0x30)
+        .usage(0x31)
+        .usage(0x38)
+        .logical_minimum(-127)
+        .logical_maximum(127)
+        .report_size(8)
+        .report_count(3)
+        .input(flags::DATA | flags::VARIABLE | flags::RELATIVE)
+        .end_collection()
+        .end_collection()
+        .build()
+}
+
+pub const KEYBOARD_ENDPOINT: u8 = 0x81;
+pub const MOUSE_ENDPOINT: u8 = 0x82;
+
+const KEYBOARD_INTERFACE: u8 = 0;
+const MOUSE_INTERFACE: u8 = 1;
+
+/// The size of the configuration descriptor built by `descriptors`, which is the smallest buffer
+/// it accepts.
+pub const CONFIGURATION_LENGTH: usize = 77;
+
+/// The interface number of the DFU run-time interface in `descriptors`, to be given to
+/// `dfu::DfuRuntime::new`.
+pub const DFU_INTERFACE: u8 = 2;
+
+static DEVICE: [u8; 18] = TESTBOARD_DEVICE.bytes();
+
+/// The HID descriptor of an interface with the given report descriptor. See chapter 6.2.1 in the
+/// HID specification.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+fn hid_descriptor(report_descriptor: &[u8]) -> [u8; 9] {
+    let length = report_descriptor.len();
+    [
+        9,                              // bLength
+        hid_descriptor_type::HID,       // bDescriptorType
+        0x11, 0x01,                     // bcdHID: 1.11
+        0,                              // bCountryCode: Not supported
+        1,                              // bNumDescriptors
+        hid_descriptor_type::REPORT,    // bDescriptorType
+        length as u8, (length >> 8) as u8, // wDescriptorLength
+    ]
+}
+
+/// The descriptors of a device with a keyboard on interface 0 and a mouse on interface 1, and a
+/// DFU run-time interface so `flash.sh` can put the board into the bootloader. The configuration
+/// descriptor is built into `buffer`, which has to hold at least `CONFIGURATION_LENGTH` bytes.
+///
+/// The HID descriptors give the lengths of the report descriptors, so these have to be the ones
+/// later given to `Keyboard::new` and `Mouse::new`.
+///
+/// ```ignore
+/// let keyboard_report = hid::keyboard_report_descriptor(unsafe { &mut KEYBOARD_REPORT })?;
+/// let mouse_report = hid::mouse_report_descriptor(unsafe { &mut MOUSE_REPORT })?;
+/// let configuration = unsafe { &mut CONFIGURATION };
+/// let descriptors = hid::descriptors(configuration, keyboard_report, mouse_report)?;
+/// let keyboard = Keyboard::new(&descriptors, keyboard_report);
+/// let mouse = Mouse::new(&descriptors, mouse_report);
+/// ```
+#[cfg_attr(rustfmt, rustfmt_skip)]
+pub fn descriptors(
+    buffer: &'static mut [u8],
+    keyboard_report: &[u8],
+    mouse_report: &[u8],
+) -> Result<Descriptors, DescriptorError> {
+    let configuration = ConfigurationBuilder::new(buffer, 1, 0x80, 100)
+        // Keyboard with the boot interface subclass
+        .interface(KEYBOARD_INTERFACE, 0, 0x03, 0x01, 0x01, 0)
+        .class_specific(&hid_descriptor(keyboard_report))
+        .endpoint(KEYBOARD_ENDPOINT, 0x03, 8, 10)
+        // Mouse with the boot interface subclass
+        .interface(MOUSE_INTERFACE, 0, 0x03, 0x01, 0x02, 0)
+        .class_specific(&hid_descriptor(mouse_report))
+        .endpoint(MOUSE_ENDPOINT, 0x03, 8, 10)
+        .dfu_runtime(DFU_INTERFACE)
+        .build()?;
+
+    Ok(Descriptors {
+        device: &DEVICE,
+        configuration,
+        strings: STRINGS,
+    })
+}
+
+/// The keyboard on interface 0 of `descriptors`. It has to be given to `UsbDevice::poll`.
+///
+/// Nothing here waits for the host: the methods sending reports fail with `WouldBlock` while the
+/// previous report has not been picked up yet, and `UsbDevice::poll` has to be called before
+/// trying again.
+#[cfg(not(test))]
+pub struct Keyboard {
+    hid: Hid,
+    // Whether the key sent by `press` still has to be released
+    release_pending: bool,
+}
+
+#[cfg(not(test))]
+impl Keyboard {
+    /// `report_descriptor` has to be the one given to `descriptors`.
+    pub fn new(descriptors: &Descriptors, report_descriptor: &'static [u8]) -> Keyboard {
+        let hid = Hid::new(descriptors, KEYBOARD_INTERFACE, report_descriptor);
+        Keyboard {
+            hid: hid.expect("Keyboard interface missing from the descriptors"),
+            release_pending: false,
+        }
+    }
+
+    /// The LED states last set by the host, with Num Lock in bit 0, Caps Lock in bit 1 and
+    /// Scroll Lock in bit 2.
+    #[inline]
+    pub fn leds(&self) -> u8 {
+        self.hid.output()
+    }
+
+    /// Sends a report with the given modifiers and up to 6 keys held down.
+    pub fn set_keys(&mut self, modifiers: u8, keys: &[u8]) -> nb::Result<(), endpoint::Error> {
+        let mut report = [0; 8];
+        report[0] = modifiers;
+        let len = cmp::min(keys.len(), 6);
+        report[2..2 + len].copy_from_slice(&keys[..len]);
+        self.hid.send_report(&report)?;
+        self.release_pending = false;
+        Ok(())
+    }
+
+    /// Presses `key`, after releasing the key of the previous call. The key stays pressed until
+    /// the next call or `flush`, so repeated keys are seen as separate presses.
+    pub fn press(&mut self, key: Key) -> nb::Result<(), endpoint::Error> {
+        self.flush()?;
+        let report = [key.modifiers, 0, key.code, 0, 0, 0, 0, 0];
+        self.hid.send_report(&report)?;
+        self.release_pending = true;
+        Ok(())
+    }
+
+    /// Releases the key of the last `press`, if it has not been released yet.
+    pub fn flush(&mut self) -> nb::Result<(), endpoint::Error> {
+        if self.release_pending {
+            self.hid.send_report(&[0; 8])?;
+            self.release_pending = false;
+        }
+        Ok(())
+    }
+
+    /// Types as much of `s` on a US keyboard layout as can be sent without waiting for the host,
+    /// returning how many bytes of `s` were typed. Characters without a key are skipped. Call
+    /// `flush` after the last one to release its key.
+    pub fn type_str(&mut self, s: &str) -> Result<usize, endpoint::Error> {
+        for (i, c) in s.bytes().enumerate() {
+            if let Some(key) = Key::from_ascii(c) {
+                match self.press(key) {
+                    Ok(()) => (),
+                    Err(nb::Error::WouldBlock) => return Ok(i),
+                    Err(nb::Error::Other(error)) => return Err(error),
+                }
+            }
+        }
+        Ok(s.len())
+    }
+}
+
+/// The mouse on interface 1 of `descriptors`. It has to be given to `UsbDevice::poll`.
+///
+/// Like `Keyboard`, this never waits for the host.
+#[cfg(not(test))]
+pub struct Mouse {
+    hid: Hid,
+    buttons: u8,
+    // Whether the buttons pressed by `click` still have to be released
+    release_pending: bool,
+}
+
+#[cfg(not(test))]
+impl Mouse {
+    /// `report_descriptor` has to be the one given to `descriptors`.
+    pub fn new(descriptors: &Descriptors, report_descriptor: &'static [u8]) -> Mouse {
+        let hid = Hid::new(descriptors, MOUSE_INTERFACE, report_descriptor);
+        Mouse {
+            hid: hid.expect("Mouse interface missing from the descriptors"),
+            buttons: 0,
+            release_pending: false,
+        }
+    }
+
+    /// Sends a report. The buttons are in the lower 3 bits of `buttons`, with the left button
+    /// in bit 0. The wheel is left out if the host has selected the boot protocol.
+    pub fn report(
+        &mut self,
+        buttons: u8,
+        x: i8,
+        y: i8,
+        wheel: i8,
+    ) -> nb::Result<(), endpoint::Error> {
+        let report = [buttons & 0b111, x as u8, y as u8, wheel as u8];
+        let len = if self.hid.boot_protocol() { 3 } else { 4 };
+        self.hid.send_report(&report[..len])?;
+        self.buttons = buttons;
+        self.release_pending = false;
+        Ok(())
+    }
+
+    /// Moves the pointer, after releasing the buttons of the last `click`.
+    pub fn move_by(&mut self, x: i8, y: i8) -> nb::Result<(), endpoint::Error> {
+        self.flush()?;
+        let buttons = self.buttons;
+        self.report(buttons, x, y, 0)
+    }
+
+    /// Presses the buttons in `buttons` on top of the ones already held. They are released by
+    /// the next `click`, `move_by` or `flush`.
+    pub fn click(&mut self, buttons: u8) -> nb::Result<(), endpoint::Error> {
+        self.flush()?;
+        let held = self.buttons;
+        self.report(held | buttons, 0, 0, 0)?;
+        self.buttons = held;
+        self.release_pending = true;
+        Ok(())
+    }
+
+    /// Releases the buttons of the last `click`, if they have not been released yet.
+    pub fn flush(&mut self) -> nb::Result<(), endpoint::Error> {
+        if self.release_pending {
+            let buttons = self.buttons;
+            self.report(buttons, 0, 0, 0)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(test))]
+macro_rules! forward_class {
+    ($type:ident) => {
+        impl Class for $type {
+            fn reset(&mut self) {
+                self.release_pending = false;
+                self.hid.reset();
+            }
+
+            fn set_configuration(&mut self, configuration: u8) {
+                self.release_pending = false;
+                self.hid.set_configuration(configuration);
+            }
+
+            fn get_descriptor(&mut self, setup: &SetupPacket) -> Option<&'static [u8]> {
+                self.hid.get_descriptor(setup)
+            }
+
+            fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> ControlResult {
+                self.hid.control(setup, data)
+            }
+        }
+    };
+}
+
+#[cfg(not(test))]
+forward_class!(Keyboard);
+#[cfg(not(test))]
+forward_class!(Mouse);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usb::validate_configuration;
+
+    fn buffer(len: usize) -> &'static mut [u8] {
+        Box::leak(vec![0; len].into_boxed_slice())
+    }
+
+    #[test]
+    fn item_sizes() {
+        let descriptor = ReportDescriptorBuilder::new(buffer(64))
+            .usage_page(0x01)
+            .usage_page(0xff00)
+            .usage(0xffff)
+            .logical_minimum(-1)
+            .logical_minimum(-129)
+            .logical_maximum(0x7fff)
+            .logical_maximum(0x8000)
+            .logical_maximum(-0x8001)
+            .build()
+            .unwrap();
+        assert_eq!(
+            descriptor,
+            &[
+                0x05, 0x01, //
+                0x06, 0x00, 0xff, //
+                0x0a, 0xff, 0xff, //
+                0x15, 0xff, //
+                0x16, 0x7f, 0xff, //
+                0x26, 0xff, 0x7f, //
+                0x27, 0x00, 0x80, 0x00, 0x00, //
+                0x27, 0xff, 0x7f, 0xff, 0xff,
+            ][..]
+        );
+    }
+
+    #[test]
+    fn item_prefixes() {
+        let descriptor = ReportDescriptorBuilder::new(buffer(64))
+            .collection(collection::LOGICAL)
+            .usage_minimum(1)
+            .usage_maximum(2)
+            .report_size(3)
+            .report_count(4)
+            .report_id(5)
+            .input(flags::CONSTANT)
+            .output(flags::VARIABLE)
+            .feature(flags::RELATIVE)
+            .end_collection()
+            .build()
+            .unwrap();
+        assert_eq!(
+            descriptor,
+            &[
+                0xa1, 0x02, 0x19, 0x01, 0x29, 0x02, 0x75, 0x03, 0x95, 0x04, 0x85, 0x05, 0x81, 0x01,
+                0x91, 0x02, 0xb1, 0x04, 0xc0,
+            ][..]
+        );
+    }
+
+    #[test]
+    fn builder_errors() {
+        let too_small = ReportDescriptorBuilder::new(buffer(3))
+            .usage_page(0x01)
+            .usage(0x100);
+        assert_eq!(too_small.build(), None);
+        let unclosed = ReportDescriptorBuilder::new(buffer(8)).collection(collection::APPLICATION);
+        assert_eq!(unclosed.build(), None);
+        let unopened = ReportDescriptorBuilder::new(buffer(8)).end_collection();
+        assert_eq!(unopened.build(), None);
+    }
+
+    #[test]
+    fn report_descriptors() {
+        let keyboard = keyboard_report_descriptor(buffer(KEYBOARD_REPORT_DESCRIPTOR_LENGTH));
+        let keyboard = keyboard.unwrap();
+        assert_eq!(keyboard.len(), KEYBOARD_REPORT_DESCRIPTOR_LENGTH);
+        assert_eq!(&keyboard[..6], &[0x05, 0x01, 0x09, 0x06, 0xa1, 0x01]);
+        // The pressed keys, which go from 0 to 101
+        assert_eq!(
+            &keyboard[50..],
+            &[0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xc0][..]
+        );
+        assert_eq!(keyboard_report_descriptor(buffer(62)), None);
+
+        let mouse = mouse_report_descriptor(buffer(MOUSE_REPORT_DESCRIPTOR_LENGTH)).unwrap();
+        assert_eq!(mouse.len(), MOUSE_REPORT_DESCRIPTOR_LENGTH);
+        // X, Y and the wheel, which go from -127 to 127
+        assert_eq!(
+            &mouse[40..],
+            &[0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95, 0x03, 0x81, 0x06, 0xc0, 0xc0][..]
+        );
+        assert_eq!(mouse_report_descriptor(buffer(51)), None);
+    }
+
+    #[test]
+    fn configuration() {
+        let keyboard = keyboard_report_descriptor(buffer(KEYBOARD_REPORT_DESCRIPTOR_LENGTH));
+        let mouse = mouse_report_descriptor(buffer(MOUSE_REPORT_DESCRIPTOR_LENGTH));
+        let device = descriptors(
+            buffer(CONFIGURATION_LENGTH),
+            keyboard.unwrap(),
+            mouse.unwrap(),
+        )
+        .unwrap();
+        assert_eq!(device.validate(), Ok(()));
+
+        let configuration = device.configuration;
+        assert_eq!(configuration.len(), CONFIGURATION_LENGTH);
+        assert_eq!(configuration[4], DFU_INTERFACE + 1);
+        // wDescriptorLength in the HID descriptors of both interfaces
+        assert_eq!(&configuration[9 + 9 + 7..9 + 9 + 9], &[63, 0]);
+        assert_eq!(&configuration[9 + 25 + 9 + 7..9 + 25 + 9 + 9], &[52, 0]);
+        assert_eq!(validate_configuration(configuration), Ok(()));
+
+        assert!(descriptors(buffer(CONFIGURATION_LENGTH - 1), &[], &[]).is_err());
+    }
+
+    #[test]
+    fn keys() {
+        let shift = modifier::LEFT_SHIFT;
+        assert_eq!(Key::from_ascii(b'a'), Some(Key::new(key_code::A)));
+        assert_eq!(Key::from_ascii(b'z'), Some(Key::new(key_code::Z)));
+        assert_eq!(
+            Key::from_ascii(b'Q'),
+            Some(Key::new(0x14).with_modifiers(shift))
+        );
+        assert_eq!(Key::from_ascii(b'1'), Some(Key::new(key_code::N1)));
+        assert_eq!(Key::from_ascii(b'0'), Some(Key::new(key_code::N0)));
+        assert_eq!(
+            Key::from_ascii(b'!'),
+            Some(Key::new(key_code::N1).with_modifiers(shift))
+        );
+        assert_eq!(
+            Key::from_ascii(b')'),
+            Some(Key::new(key_code::N0).with_modifiers(shift))
+        );
+        assert_eq!(Key::from_ascii(b'\n'), Some(Key::new(key_code::ENTER)));
+        assert_eq!(Key::from_ascii(b' '), Some(Key::new(key_code::SPACE)));
+        assert_eq!(Key::from_ascii(b'-'), Some(Key::new(0x2d)));
+        assert_eq!(
+            Key::from_ascii(b'_'),
+            Some(Key::new(0x2d).with_modifiers(shift))
+        );
+        assert_eq!(
+            Key::from_ascii(b'?'),
+            Some(Key::new(0x38).with_modifiers(shift))
+        );
+        assert_eq!(Key::from_ascii(b'\r'), None);
+        assert_eq!(Key::from_ascii(0x80), None);
+    }
+}
@@ -0,0 +1,534 @@
+//! A tiny FAT12 volume, most of which is stored in the last 8 KB of flash.
+//!
+//! The volume has sectors of 512 bytes and clusters of a single sector. It is laid out as:
+//!
+//! * Sector 0: The boot sector, which is generated and can not be changed.
+//! * Sector 1: The only FAT.
+//! * Sector 2: The root directory, which has room for 16 entries.
+//! * Sector 3 to 14: The data of cluster 2 to 13.
+//! * Sector 15: The data of cluster 14, which is `STATUS.TXT` and is generated.
+//!
+//! Sector 1 to 14 are stored in the first 7 pages of the disk, two sectors to a page. The last
+//! page holds a copy of the first one, with the FAT and the root directory, so the volume
+//! survives a reset while these are rewritten. A page is only erased and written once the host
+//! moves on to another page, or `flush` is called. The entry of `STATUS.TXT` in the root
+//! directory and its cluster in the FAT are filled in whenever the page holding them is read, so
+//! the file comes back with the right size even if the host deletes it.
+//!
+//! The flash is formatted the first time the volume is used, with `STATUS.TXT` and an almost
+//! empty `CONFIG.TXT`.
+
+#[cfg(not(test))]
+use core::fmt::{self, Write};
+#[cfg(not(test))]
+use device_information;
+#[cfg(not(test))]
+use msc::{self, Msc};
+use msc::{DISK_SIZE, DISK_START, PAGE_SIZE};
+
+pub const SECTOR_SIZE: usize = 512;
+/// The number of sectors in the volume.
+pub const SECTORS: u32 = STATUS_SECTOR + 1;
+
+const SECTORS_PER_PAGE: u32 = (PAGE_SIZE / SECTOR_SIZE) as u32;
+const PAGES: u32 = DISK_SIZE / PAGE_SIZE as u32;
+// The copy of page 0, see `Volume::flush`
+const BACKUP_PAGE: u32 = PAGES - 1;
+
+const BOOT_SECTOR: u32 = 0;
+const FAT_SECTOR: u32 = 1;
+const DATA_SECTOR: u32 = 3;
+const STATUS_SECTOR: u32 = FAT_SECTOR + BACKUP_PAGE * SECTORS_PER_PAGE;
+
+const CONFIG_CLUSTER: u16 = 2;
+const STATUS_CLUSTER: u16 = (STATUS_SECTOR - DATA_SECTOR) as u16 + 2;
+const END_OF_CHAIN: u16 = 0xfff;
+
+const MEDIA: u8 = 0xf8;
+
+// The attributes of directory entries
+const READ_ONLY: u8 = 0x01;
+const VOLUME_ID: u8 = 0x08;
+const DIRECTORY: u8 = 0x10;
+const ARCHIVE: u8 = 0x20;
+
+// The entries made when formatting. `STATUS.TXT` has to stay in the same one.
+const LABEL_ENTRY: usize = 0;
+const STATUS_ENTRY: usize = 1;
+const CONFIG_ENTRY: usize = 2;
+
+const LABEL: &[u8; 11] = b"TESTBOARD  ";
+const STATUS_NAME: &[u8; 11] = b"STATUS  TXT";
+const CONFIG_NAME: &[u8; 11] = b"CONFIG  TXT";
+
+/// What `CONFIG.TXT` holds after formatting.
+const DEFAULT_CONFIG: &[u8] = b"# testboard configuration\r\n";
+
+// 2018-01-01, as the date fields of a directory entry
+const DATE: u16 = (2018 - 1980) << 9 | 1 << 5 | 1;
+
+// The part of the boot sector before the boot code. See the FAT specification from Microsoft.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const BOOT_SECTOR_HEADER: [u8; 62] = [
+    0xeb, 0x3c, 0x90,                   // BS_jmpBoot
+    b'M', b'S', b'W', b'I', b'N', b'4', b'.', b'1', // BS_OEMName
+    0x00, 0x02,                         // BPB_BytsPerSec: 512
+    1,                                  // BPB_SecPerClus
+    1, 0,                               // BPB_RsvdSecCnt
+    1,                                  // BPB_NumFATs
+    16, 0,                              // BPB_RootEntCnt
+    SECTORS as u8, 0,                   // BPB_TotSec16
+    MEDIA,                              // BPB_Media
+    1, 0,                               // BPB_FATSz16
+    1, 0,                               // BPB_SecPerTrk
+    1, 0,                               // BPB_NumHeads
+    0, 0, 0, 0,                         // BPB_HiddSec
+    0, 0, 0, 0,                         // BPB_TotSec32
+    0x80,                               // BS_DrvNum
+    0,                                  // BS_Reserved1
+    0x29,                               // BS_BootSig
+    0, 0, 0, 0,                         // BS_VolID: Filled in from the unique id
+    b'T', b'E', b'S', b'T', b'B', b'O', b'A', b'R', b'D', b' ', b' ', // BS_VolLab
+    b'F', b'A', b'T', b'1', b'2', b' ', b' ', b' ', // BS_FilSysType
+];
+
+/// Where the data of a sector comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Boot,
+    /// The index of the sector in the flash.
+    Flash(u32),
+    Status,
+}
+
+fn locate(sector: u32) -> Option<Location> {
+    match sector {
+        BOOT_SECTOR => Some(Location::Boot),
+        STATUS_SECTOR => Some(Location::Status),
+        s if s < STATUS_SECTOR => Some(Location::Flash(s - FAT_SECTOR)),
+        _ => None,
+    }
+}
+
+/// The sector holding the data of a cluster.
+fn cluster_sector(cluster: u16) -> u32 {
+    u32::from(cluster - 2) + DATA_SECTOR
+}
+
+/// The address of a page of the disk.
+fn page_address(page: u32) -> u32 {
+    DISK_START + page * PAGE_SIZE as u32
+}
+
+/// What the page buffer holds.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Buffered {
+    Nothing,
+    /// A page of the flash, counted from the start of the disk.
+    Page(u32),
+    /// A generated sector, in the first half of the buffer.
+    Generated(u32),
+}
+
+#[cfg(not(test))]
+pub struct Volume {
+    flash: Msc,
+    buffer: [u32; PAGE_SIZE / 4],
+    buffered: Buffered,
+    // Whether the page in the buffer has been written to
+    dirty: bool,
+    status_len: u32,
+}
+
+#[cfg(not(test))]
+impl Volume {
+    /// Creates the volume, formatting the flash if it does not hold a volume yet. If a reset cut
+    /// short rewriting page 0, it is restored from its copy.
+    pub fn new(flash: Msc) -> Result<Volume, msc::Error> {
+        let mut volume = Volume {
+            flash,
+            buffer: [0; PAGE_SIZE / 4],
+            buffered: Buffered::Nothing,
+            dirty: false,
+            status_len: 0,
+        };
+        volume.status_len = status(&mut as_bytes_mut(&mut volume.buffer)[..SECTOR_SIZE]) as u32;
+
+        if !volume.holds_fat(0)? {
+            if volume.holds_fat(BACKUP_PAGE)? {
+                volume.restore()?;
+            } else {
+                volume.format()?;
+            }
+        }
+        Ok(volume)
+    }
+
+    /// The contents of a sector.
+    pub fn read_sector(&mut self, sector: u32) -> Result<&[u8], msc::Error> {
+        let offset = self.load(sector)?;
+        Ok(&as_bytes(&self.buffer)[offset..offset + SECTOR_SIZE])
+    }
+
+    /// The contents of a sector, for the host to change. The generated sectors can be written,
+    /// but the changes are thrown away.
+    pub fn write_sector(&mut self, sector: u32) -> Result<&mut [u8], msc::Error> {
+        let offset = self.load(sector)?;
+        match self.buffered {
+            Buffered::Page(_) => self.dirty = true,
+            // Generate the sector again the next time it is read
+            _ => self.buffered = Buffered::Nothing,
+        }
+        Ok(&mut as_bytes_mut(&mut self.buffer)[offset..offset + SECTOR_SIZE])
+    }
+
+    /// Writes the buffered page to the flash if it was changed. Pages are only erased if their
+    /// contents differ from what is already in the flash.
+    ///
+    /// Page 0 is written to `BACKUP_PAGE` first, as a reset while it is erased or written would
+    /// otherwise lose the FAT and the root directory, and with them the whole volume. A reset
+    /// while writing any other page only loses the changes to the sectors in it.
+    ///
+    /// If this fails, the changes are lost.
+    pub fn flush(&mut self) -> Result<(), msc::Error> {
+        let page = match self.buffered {
+            Buffered::Page(page) if self.dirty => page,
+            _ => return Ok(()),
+        };
+        self.dirty = false;
+
+        if self.flash.read(page_address(page), PAGE_SIZE)? == &as_bytes(&self.buffer)[..] {
+            return Ok(());
+        }
+        let mut result = Ok(());
+        if page == 0 {
+            result = self.write_page(BACKUP_PAGE);
+        }
+        if result.is_ok() {
+            result = self.write_page(page);
+        }
+        if result.is_err() {
+            self.buffered = Buffered::Nothing;
+        }
+        result
+    }
+
+    /// Copies the contents of `CONFIG.TXT` to `data`, for as far as it goes, and returns how many
+    /// bytes were copied. Returns 0 if the file does not exist.
+    pub fn read_config(&mut self, data: &mut [u8]) -> Result<usize, msc::Error> {
+        self.flush()?;
+        let fat = self.flash.read(DISK_START, SECTOR_SIZE)?;
+        let root = self.flash.read(DISK_START + SECTOR_SIZE as u32, SECTOR_SIZE)?;
+
+        let entry = root
+            .chunks(32)
+            .take_while(|entry| entry[0] != 0)
+            .find(|entry| {
+                let attributes = entry[11];
+                entry[..11] == CONFIG_NAME[..] && attributes & (VOLUME_ID | DIRECTORY) == 0
+            });
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return Ok(0),
+        };
+        let mut cluster = u16::from(entry[26]) | u16::from(entry[27]) << 8;
+        let size = u32::from(entry[28])
+            | u32::from(entry[29]) << 8
+            | u32::from(entry[30]) << 16
+            | u32::from(entry[31]) << 24;
+        let len = data.len().min(size as usize);
+
+        // The chain is followed for no more than the number of clusters, in case it loops
+        let mut copied = 0;
+        for _ in CONFIG_CLUSTER..STATUS_CLUSTER {
+            if copied == len || cluster < CONFIG_CLUSTER || cluster >= STATUS_CLUSTER {
+                break;
+            }
+            let sector = cluster_sector(cluster) - FAT_SECTOR;
+            let contents = self.flash
+                .read(DISK_START + sector * SECTOR_SIZE as u32, SECTOR_SIZE)?;
+            let n = (len - copied).min(SECTOR_SIZE);
+            data[copied..copied + n].copy_from_slice(&contents[..n]);
+            copied += n;
+            cluster = fat_entry(fat, cluster);
+        }
+        Ok(copied)
+    }
+
+    /// Puts the sector into the buffer, and returns its offset in the buffer.
+    fn load(&mut self, sector: u32) -> Result<usize, msc::Error> {
+        let location = locate(sector).ok_or(msc::Error::InvalidAddress)?;
+        let wanted = match location {
+            Location::Flash(index) => Buffered::Page(index / SECTORS_PER_PAGE),
+            _ => Buffered::Generated(sector),
+        };
+        if self.buffered != wanted {
+            self.flush()?;
+            let status_entry = self.status_entry();
+            let buffer = as_bytes_mut(&mut self.buffer);
+            match location {
+                Location::Flash(index) => {
+                    let page = index / SECTORS_PER_PAGE;
+                    buffer.copy_from_slice(self.flash.read(page_address(page), PAGE_SIZE)?);
+                    if page == 0 {
+                        let (fat, root) = buffer.split_at_mut(SECTOR_SIZE);
+                        set_fat_entry(fat, STATUS_CLUSTER, END_OF_CHAIN);
+                        root[32 * STATUS_ENTRY..32 * (STATUS_ENTRY + 1)]
+                            .copy_from_slice(&status_entry);
+                    }
+                }
+                Location::Boot => boot_sector(&mut buffer[..SECTOR_SIZE]),
+                Location::Status => {
+                    status(&mut buffer[..SECTOR_SIZE]);
+                }
+            }
+            self.buffered = wanted;
+        }
+        Ok(match location {
+            Location::Flash(index) => (index % SECTORS_PER_PAGE) as usize * SECTOR_SIZE,
+            _ => 0,
+        })
+    }
+
+    fn status_entry(&self) -> [u8; 32] {
+        directory_entry(STATUS_NAME, READ_ONLY | ARCHIVE, STATUS_CLUSTER, self.status_len)
+    }
+
+    /// Whether a page starts with the media byte of the FAT, which `write_page` writes last.
+    fn holds_fat(&self, page: u32) -> Result<bool, msc::Error> {
+        Ok(self.flash.read(page_address(page), 1)?[0] == MEDIA)
+    }
+
+    /// Erases a page and writes the buffer to it. The first word is written last, so a copy of
+    /// page 0 cut short by a reset does not start with the media byte.
+    fn write_page(&mut self, page: u32) -> Result<(), msc::Error> {
+        let address = page_address(page);
+        self.flash.erase_page(address)?;
+        self.flash.write(address + 4, &self.buffer[1..])?;
+        self.flash.write(address, &self.buffer[..1])
+    }
+
+    /// Writes page 0 back from its copy.
+    fn restore(&mut self) -> Result<(), msc::Error> {
+        self.buffered = Buffered::Nothing;
+        self.dirty = false;
+        as_bytes_mut(&mut self.buffer)
+            .copy_from_slice(self.flash.read(page_address(BACKUP_PAGE), PAGE_SIZE)?);
+        self.write_page(0)
+    }
+
+    /// Erases the flash, and writes a FAT and root directory with `STATUS.TXT` and `CONFIG.TXT`.
+    fn format(&mut self) -> Result<(), msc::Error> {
+        self.buffered = Buffered::Nothing;
+        self.dirty = false;
+        // Page 0 is erased by `write_page`
+        for page in 1..PAGES {
+            self.flash.erase_page(page_address(page))?;
+        }
+
+        // `CONFIG.TXT` is in the first half of page 1
+        {
+            let buffer = as_bytes_mut(&mut self.buffer);
+            for b in buffer.iter_mut() {
+                *b = 0;
+            }
+            buffer[..DEFAULT_CONFIG.len()].copy_from_slice(DEFAULT_CONFIG);
+        }
+        self.flash.write(page_address(1), &self.buffer)?;
+
+        // Page 0 comes last, so the flash is formatted again if a reset cuts this short
+        let status_entry = self.status_entry();
+        {
+            let buffer = as_bytes_mut(&mut self.buffer);
+            for b in buffer.iter_mut() {
+                *b = 0;
+            }
+            let (fat, root) = buffer.split_at_mut(SECTOR_SIZE);
+            fat[..3].copy_from_slice(&[MEDIA, 0xff, 0xff]);
+            set_fat_entry(fat, CONFIG_CLUSTER, END_OF_CHAIN);
+            set_fat_entry(fat, STATUS_CLUSTER, END_OF_CHAIN);
+            let config_entry = directory_entry(
+                CONFIG_NAME,
+                ARCHIVE,
+                CONFIG_CLUSTER,
+                DEFAULT_CONFIG.len() as u32,
+            );
+            let entries = [
+                (LABEL_ENTRY, directory_entry(LABEL, VOLUME_ID, 0, 0)),
+                (STATUS_ENTRY, status_entry),
+                (CONFIG_ENTRY, config_entry),
+            ];
+            for &(index, ref entry) in &entries {
+                root[32 * index..32 * (index + 1)].copy_from_slice(entry);
+            }
+        }
+        self.write_page(0)
+    }
+}
+
+fn as_bytes(words: &[u32; PAGE_SIZE / 4]) -> &[u8; PAGE_SIZE] {
+    unsafe { &*(words as *const [u32; PAGE_SIZE / 4] as *const [u8; PAGE_SIZE]) }
+}
+
+fn as_bytes_mut(words: &mut [u32; PAGE_SIZE / 4]) -> &mut [u8; PAGE_SIZE] {
+    unsafe { &mut *(words as *mut [u32; PAGE_SIZE / 4] as *mut [u8; PAGE_SIZE]) }
+}
+
+/// The FAT entry of a cluster, which is the next cluster of the file.
+fn fat_entry(fat: &[u8], cluster: u16) -> u16 {
+    // The 12-bit entries are packed, so every two entries share 3 bytes
+    let offset = cluster as usize * 3 / 2;
+    let pair = u16::from(fat[offset]) | u16::from(fat[offset + 1]) << 8;
+    if cluster % 2 == 0 {
+        pair & 0xfff
+    } else {
+        pair >> 4
+    }
+}
+
+fn set_fat_entry(fat: &mut [u8], cluster: u16, value: u16) {
+    let offset = cluster as usize * 3 / 2;
+    if cluster % 2 == 0 {
+        fat[offset] = value as u8;
+        fat[offset + 1] = (fat[offset + 1] & 0xf0) | (value >> 8) as u8 & 0x0f;
+    } else {
+        fat[offset] = (fat[offset] & 0x0f) | (value << 4) as u8;
+        fat[offset + 1] = (value >> 4) as u8;
+    }
+}
+
+fn directory_entry(name: &[u8; 11], attributes: u8, cluster: u16, size: u32) -> [u8; 32] {
+    let mut entry = [0; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    // The creation, access and modification dates
+    for &offset in &[16, 18, 24] {
+        entry[offset..offset + 2].copy_from_slice(&[DATE as u8, (DATE >> 8) as u8]);
+    }
+    entry[26..28].copy_from_slice(&[cluster as u8, (cluster >> 8) as u8]);
+    entry[28..32].copy_from_slice(&le_bytes(size));
+    entry
+}
+
+fn le_bytes(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+#[cfg(not(test))]
+fn boot_sector(sector: &mut [u8]) {
+    for b in sector.iter_mut() {
+        *b = 0;
+    }
+    sector[..BOOT_SECTOR_HEADER.len()].copy_from_slice(&BOOT_SECTOR_HEADER);
+    let id = device_information::get_unique_id() as u32;
+    sector[39..43].copy_from_slice(&le_bytes(id));
+    sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+}
+
+/// Writes the contents of `STATUS.TXT` to `sector`, and returns its length.
+#[cfg(not(test))]
+fn status(sector: &mut [u8]) -> usize {
+    for b in sector.iter_mut() {
+        *b = 0;
+    }
+    let mut cursor = Cursor { data: sector, len: 0 };
+    // Writing to the cursor never fails, as it truncates instead
+    let _ = write!(
+        cursor,
+        "testboard {}\r\n\
+         Unique id: {:016x}\r\n\
+         Part: family {}, number {}, revision {}\r\n\
+         Flash: {} KB\r\n\
+         RAM: {} KB\r\n",
+        env!("CARGO_PKG_VERSION"),
+        device_information::get_unique_id(),
+        device_information::get_part_family(),
+        device_information::get_part_number(),
+        device_information::get_prod_rev(),
+        device_information::get_flash_size(),
+        device_information::get_sram_size(),
+    );
+    cursor.len
+}
+
+/// Formats text into a slice, cutting it off at the end.
+#[cfg(not(test))]
+struct Cursor<'a> {
+    data: &'a mut [u8],
+    len: usize,
+}
+
+#[cfg(not(test))]
+impl<'a> fmt::Write for Cursor<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.data.len() - self.len);
+        self.data[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        assert_eq!(SECTORS, 16);
+        assert_eq!(u32::from(BOOT_SECTOR_HEADER[19]), SECTORS);
+        // The root directory fills its sector
+        assert_eq!(usize::from(BOOT_SECTOR_HEADER[17]) * 32, SECTOR_SIZE);
+
+        assert_eq!(cluster_sector(CONFIG_CLUSTER), DATA_SECTOR);
+        assert_eq!(cluster_sector(STATUS_CLUSTER), STATUS_SECTOR);
+        assert_eq!(STATUS_CLUSTER, 14);
+
+        // The sectors in flash end right before the copy of page 0, which is the last page
+        assert_eq!(locate(STATUS_SECTOR - 1), Some(Location::Flash(13)));
+        assert_eq!(13 / SECTORS_PER_PAGE + 1, BACKUP_PAGE);
+        assert_eq!(page_address(BACKUP_PAGE + 1), DISK_START + DISK_SIZE);
+    }
+
+    #[test]
+    fn locations() {
+        assert_eq!(locate(BOOT_SECTOR), Some(Location::Boot));
+        assert_eq!(locate(FAT_SECTOR), Some(Location::Flash(0)));
+        assert_eq!(locate(FAT_SECTOR + 1), Some(Location::Flash(1)));
+        assert_eq!(locate(DATA_SECTOR), Some(Location::Flash(2)));
+        assert_eq!(locate(STATUS_SECTOR), Some(Location::Status));
+        assert_eq!(locate(SECTORS), None);
+        assert_eq!(locate(u32::max_value()), None);
+    }
+
+    #[test]
+    fn fat_entries() {
+        let mut fat = [0; SECTOR_SIZE];
+        fat[..3].copy_from_slice(&[MEDIA, 0xff, 0xff]);
+        set_fat_entry(&mut fat, 2, END_OF_CHAIN);
+        set_fat_entry(&mut fat, 3, 0x123);
+        assert_eq!(&fat[..6], &[MEDIA, 0xff, 0xff, 0xff, 0x3f, 0x12]);
+        assert_eq!(fat_entry(&fat, 2), END_OF_CHAIN);
+        assert_eq!(fat_entry(&fat, 3), 0x123);
+
+        // Changing an entry leaves the one sharing its middle byte alone
+        set_fat_entry(&mut fat, 2, 0x456);
+        set_fat_entry(&mut fat, 3, 0);
+        assert_eq!(fat_entry(&fat, 2), 0x456);
+        assert_eq!(fat_entry(&fat, 3), 0);
+        assert_eq!(fat_entry(&fat, 1), 0xfff);
+
+        set_fat_entry(&mut fat, STATUS_CLUSTER, END_OF_CHAIN);
+        assert_eq!(fat_entry(&fat, STATUS_CLUSTER), END_OF_CHAIN);
+        assert_eq!(fat_entry(&fat, STATUS_CLUSTER - 1), 0);
+        assert_eq!(fat_entry(&fat, STATUS_CLUSTER + 1), 0);
+    }
+
+    #[test]
+    fn directory_entries() {
+        let entry = directory_entry(CONFIG_NAME, ARCHIVE, CONFIG_CLUSTER, 0x0102_0304);
+        assert_eq!(&entry[..11], CONFIG_NAME);
+        assert_eq!(entry[11], ARCHIVE);
+        assert_eq!(&entry[24..26], &[0x21, 0x4c]);
+        assert_eq!(&entry[26..], &[2, 0, 0x04, 0x03, 0x02, 0x01]);
+    }
+}
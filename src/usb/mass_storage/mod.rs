@@ -0,0 +1,738 @@
+//! A USB flash drive using the Bulk-Only Transport of the Mass Storage class, with the SCSI
+//! transparent command set. See the USB Mass Storage Class Bulk-Only Transport 1.0 specification,
+//! and SPC-2 and SBC-2 for the commands.
+//!
+//! The host sees a removable disk holding the FAT12 volume in `fat`, so config files can be
+//! dropped onto the board without any tools. Every command arrives in a Command Block Wrapper
+//! (CBW) on the OUT endpoint, is followed by its data, if any, and is answered by a Command Status
+//! Wrapper (CSW) on the IN endpoint.
+
+mod fat;
+
+#[cfg(not(test))]
+pub use self::fat::Volume;
+pub use self::fat::{SECTORS, SECTOR_SIZE};
+
+#[cfg(not(test))]
+use super::{endpoint, Class, ControlResult, RequestKind, SetupPacket};
+use super::{ConfigurationBuilder, DescriptorError, Descriptors, STRINGS, TESTBOARD_DEVICE};
+use core::cmp;
+
+/// The bulk endpoint carrying commands and data from the host.
+pub const DATA_OUT: u8 = 0x01;
+/// The bulk endpoint carrying data and status to the host.
+pub const DATA_IN: u8 = 0x81;
+
+const MASS_STORAGE_INTERFACE: u8 = 0;
+const PACKET_SIZE: usize = 64;
+
+/// The class-specific requests of the Bulk-Only Transport. See section 3 in the specification.
+pub mod request {
+    pub const BULK_ONLY_RESET: u8 = 0xff;
+    pub const GET_MAX_LUN: u8 = 0xfe;
+}
+
+/// The operation codes of the supported SCSI commands.
+mod command {
+    pub const TEST_UNIT_READY: u8 = 0x00;
+    pub const REQUEST_SENSE: u8 = 0x03;
+    pub const INQUIRY: u8 = 0x12;
+    pub const MODE_SENSE_6: u8 = 0x1a;
+    pub const START_STOP_UNIT: u8 = 0x1b;
+    pub const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+    pub const READ_FORMAT_CAPACITIES: u8 = 0x23;
+    pub const READ_CAPACITY_10: u8 = 0x25;
+    pub const READ_10: u8 = 0x28;
+    pub const WRITE_10: u8 = 0x2a;
+    pub const VERIFY_10: u8 = 0x2f;
+    pub const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+    pub const MODE_SENSE_10: u8 = 0x5a;
+}
+
+const CBW_SIGNATURE: &[u8; 4] = b"USBC";
+const CBW_LENGTH: usize = 31;
+const CSW_SIGNATURE: &[u8; 4] = b"USBS";
+const CSW_LENGTH: usize = 13;
+
+// The values of `bCSWStatus`
+const COMMAND_PASSED: u8 = 0x00;
+const COMMAND_FAILED: u8 = 0x01;
+const PHASE_ERROR: u8 = 0x02;
+
+/// The sense key and additional sense code reported by `REQUEST SENSE`, which tell why the last
+/// command failed. See section 4.5.6 in SPC-2.
+#[derive(Clone, Copy)]
+struct Sense {
+    key: u8,
+    code: u8,
+}
+
+const NO_SENSE: Sense = Sense { key: 0x00, code: 0x00 };
+const READ_ERROR: Sense = Sense { key: 0x03, code: 0x11 };
+const WRITE_ERROR: Sense = Sense { key: 0x03, code: 0x0c };
+const INVALID_COMMAND: Sense = Sense { key: 0x05, code: 0x20 };
+const LBA_OUT_OF_RANGE: Sense = Sense { key: 0x05, code: 0x21 };
+const INVALID_FIELD: Sense = Sense { key: 0x05, code: 0x24 };
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const INQUIRY_DATA: [u8; 36] = [
+    0x00,                           // Peripheral device type: Direct access block device
+    0x80,                           // RMB: Removable
+    0x04,                           // Version: SPC-2
+    0x02,                           // Response data format
+    31,                             // Additional length
+    0, 0, 0,                        // Flags
+    b'I', b'd', b'o', b'l', b'f', b' ', b' ', b' ', // T10 vendor identification
+    b't', b'e', b's', b't', b'b', b'o', b'a', b'r', // Product identification
+    b'd', b' ', b' ', b' ', b' ', b' ', b' ', b' ',
+    b'0', b'.', b'1', b' ',         // Product revision level
+];
+
+/// The size of the configuration descriptor built by `descriptors`, which is the smallest buffer
+/// it accepts.
+pub const CONFIGURATION_LENGTH: usize = 50;
+
+/// The interface number of the DFU run-time interface in `descriptors`, to be given to
+/// `dfu::DfuRuntime::new`.
+pub const DFU_INTERFACE: u8 = 1;
+
+static DEVICE: [u8; 18] = TESTBOARD_DEVICE.bytes();
+
+/// The descriptors of a device with the disk, and a DFU run-time interface so `flash.sh` can put
+/// the board into the bootloader. The configuration descriptor is built into `buffer`, which has
+/// to hold at least `CONFIGURATION_LENGTH` bytes.
+///
+/// The Bulk-Only Transport requires a serial number, which `TESTBOARD_DEVICE` has.
+pub fn descriptors(buffer: &'static mut [u8]) -> Result<Descriptors, DescriptorError> {
+    let configuration = ConfigurationBuilder::new(buffer, 1, 0x80, 100)
+        // SCSI transparent command set over the Bulk-Only Transport
+        .interface(MASS_STORAGE_INTERFACE, 0, 0x08, 0x06, 0x50, 0)
+        .endpoint(DATA_OUT, 0x02, PACKET_SIZE as u16, 0)
+        .endpoint(DATA_IN, 0x02, PACKET_SIZE as u16, 0)
+        .dfu_runtime(DFU_INTERFACE)
+        .build()?;
+
+    Ok(Descriptors {
+        device: &DEVICE,
+        configuration,
+        strings: STRINGS,
+    })
+}
+
+/// The Bulk-Only Transport side of a command: what the host asked for in the CBW, how much of it
+/// has been transferred, and how the command ends. This is where the 13 cases of section 6.7 in
+/// the specification are decided, leaving the endpoints to `MassStorage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Transfer {
+    tag: u32,
+    expected: u32,
+    host_in: bool,
+    transferred: u32,
+    status: u8,
+    // Whether the data endpoint has to be stalled before sending the CSW
+    stall: bool,
+}
+
+impl Transfer {
+    /// Parses a CBW, returning its command block along with it. Returns `None` if the CBW is
+    /// not valid or not meaningful, which the host has to be told with a reset recovery. See
+    /// section 6.2 in the specification.
+    fn parse(cbw: &[u8]) -> Option<(Transfer, [u8; 16])> {
+        // There is only a single logical unit, and a command block is 1 to 16 bytes long
+        if cbw.len() != CBW_LENGTH
+            || cbw[..4] != CBW_SIGNATURE[..]
+            || cbw[13] != 0
+            || cbw[14] == 0
+            || cbw[14] > 16
+        {
+            return None;
+        }
+        let transfer = Transfer {
+            tag: le32(&cbw[4..8]),
+            expected: le32(&cbw[8..12]),
+            host_in: cbw[12] & 0x80 != 0,
+            transferred: 0,
+            status: COMMAND_PASSED,
+            stall: false,
+        };
+        let mut block = [0; 16];
+        block.copy_from_slice(&cbw[15..31]);
+        Some((transfer, block))
+    }
+
+    /// Starts a data phase of `len` bytes, to the host if `to_host`. Returns false, with the
+    /// command finished, if there is no data, or if the host expects less data or data in the
+    /// other direction.
+    fn start(&mut self, to_host: bool, len: u32) -> bool {
+        if len > self.expected || (len != 0 && self.host_in != to_host) {
+            self.finish(PHASE_ERROR);
+            return false;
+        }
+        if len == 0 {
+            self.finish(COMMAND_PASSED);
+            return false;
+        }
+        true
+    }
+
+    /// Starts sending a response of `len` bytes, cut short to what the host expects. Returns
+    /// the length to send, or `None` with the command finished if the host expects no data or
+    /// data from it.
+    fn respond(&mut self, len: usize) -> Option<usize> {
+        if self.expected == 0 {
+            self.finish(PHASE_ERROR);
+            return None;
+        }
+        let len = cmp::min(len as u32, self.expected);
+        if self.start(true, len) {
+            Some(len as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Ends the data phase. If the host expected more data than was transferred, the data
+    /// endpoint is stalled, unless the data to the host already ended with a short packet. See
+    /// section 6.7 in the specification.
+    fn finish(&mut self, status: u8) {
+        let short = self.host_in && self.transferred % PACKET_SIZE as u32 != 0;
+        self.stall = self.transferred < self.expected && !short;
+        self.status = status;
+    }
+
+    /// The endpoint of the data phase the host expects.
+    #[inline]
+    fn data_endpoint(&self) -> u8 {
+        if self.host_in {
+            DATA_IN
+        } else {
+            DATA_OUT
+        }
+    }
+
+    /// The CSW ending the command. See section 5.2 in the specification.
+    fn csw(&self) -> [u8; CSW_LENGTH] {
+        let residue = self.expected - self.transferred;
+        let mut csw = [0; CSW_LENGTH];
+        csw[..4].copy_from_slice(CSW_SIGNATURE);
+        csw[4..8].copy_from_slice(&le_bytes(self.tag));
+        csw[8..12].copy_from_slice(&le_bytes(residue));
+        csw[12] = self.status;
+        csw
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for a CBW.
+    Command,
+    /// Sending the response to a command, which is in the packet buffer.
+    Response,
+    /// Sending sectors to the host.
+    Read,
+    /// Receiving sectors from the host.
+    Write,
+    /// Waiting to send the CSW.
+    Status,
+    /// Both endpoints stay stalled after an invalid CBW, until the host does a reset recovery.
+    Reset,
+}
+
+/// The disk. It has to be given to `UsbDevice::poll` to see the control requests sent by the
+/// host, and its endpoints have to be part of the configuration descriptor, as they are in
+/// `descriptors`. The commands themselves are carried out by `poll`.
+#[cfg(not(test))]
+pub struct MassStorage {
+    volume: Volume,
+    configured: bool,
+    state: State,
+    packet: [u8; PACKET_SIZE],
+    response_len: usize,
+    sense: Sense,
+    transfer: Transfer,
+    // The progress of the current command
+    sector: u32,
+    sectors: u32,
+    offset: usize,
+}
+
+#[cfg(not(test))]
+impl MassStorage {
+    pub fn new(volume: Volume) -> MassStorage {
+        MassStorage {
+            volume,
+            configured: false,
+            state: State::Command,
+            packet: [0; PACKET_SIZE],
+            response_len: 0,
+            sense: NO_SENSE,
+            transfer: Transfer {
+                tag: 0,
+                expected: 0,
+                host_in: false,
+                transferred: 0,
+                status: COMMAND_PASSED,
+                stall: false,
+            },
+            sector: 0,
+            sectors: 0,
+            offset: 0,
+        }
+    }
+
+    /// The volume, for reading `CONFIG.TXT`.
+    #[inline]
+    pub fn volume(&mut self) -> &mut Volume {
+        &mut self.volume
+    }
+
+    /// Receives commands and carries them out, for as long as the endpoints keep up. Writing to
+    /// the flash blocks for up to 25 ms per page.
+    pub fn poll(&mut self) {
+        if !self.configured {
+            return;
+        }
+        while self.step() {}
+    }
+
+    /// Takes the next step of the current command, and returns whether anything happened.
+    fn step(&mut self) -> bool {
+        match self.state {
+            State::Command => match endpoint::read(DATA_OUT, &mut self.packet) {
+                Ok(len) => {
+                    self.command(len);
+                    true
+                }
+                Err(_) => false,
+            },
+            State::Response => match endpoint::write(DATA_IN, &self.packet[..self.response_len]) {
+                Ok(()) => {
+                    self.transfer.transferred = self.response_len as u32;
+                    self.finish(COMMAND_PASSED);
+                    true
+                }
+                Err(_) => false,
+            },
+            State::Read => self.read_packet(),
+            State::Write => self.write_packet(),
+            State::Status => self.send_status(),
+            State::Reset => {
+                for &address in &[DATA_IN, DATA_OUT] {
+                    if !endpoint::is_stalled(address) {
+                        let _ = endpoint::stall(address);
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    /// Parses a CBW and starts carrying out its command. See section 6.2 in the specification.
+    fn command(&mut self, len: usize) {
+        let block = match Transfer::parse(&self.packet[..len]) {
+            Some((transfer, block)) => {
+                self.transfer = transfer;
+                block
+            }
+            None => {
+                self.state = State::Reset;
+                return;
+            }
+        };
+        if block[0] != command::REQUEST_SENSE {
+            self.sense = NO_SENSE;
+        }
+
+        match block[0] {
+            command::TEST_UNIT_READY
+            | command::START_STOP_UNIT
+            | command::PREVENT_ALLOW_MEDIUM_REMOVAL
+            | command::VERIFY_10 => self.finish(COMMAND_PASSED),
+            command::SYNCHRONIZE_CACHE_10 => match self.volume.flush() {
+                Ok(()) => self.finish(COMMAND_PASSED),
+                Err(_) => self.fail(WRITE_ERROR),
+            },
+            command::REQUEST_SENSE => {
+                let sense = self.sense;
+                self.sense = NO_SENSE;
+                #[cfg_attr(rustfmt, rustfmt_skip)]
+                let data = [
+                    0x70, 0, sense.key, 0, 0, 0, 0, 10, 0, 0, 0, 0, sense.code, 0, 0, 0, 0, 0,
+                ];
+                self.respond(&data);
+            }
+            command::INQUIRY if block[1] & 0x01 != 0 => {
+                // Vital product data pages are not supported
+                self.fail(INVALID_FIELD);
+            }
+            command::INQUIRY => self.respond(&INQUIRY_DATA),
+            // Just the header, which says the disk is not write protected
+            command::MODE_SENSE_6 => self.respond(&[3, 0, 0, 0]),
+            command::MODE_SENSE_10 => self.respond(&[0, 6, 0, 0, 0, 0, 0, 0]),
+            command::READ_CAPACITY_10 => {
+                let mut data = [0; 8];
+                data[..4].copy_from_slice(&be_bytes(SECTORS - 1));
+                data[4..].copy_from_slice(&be_bytes(SECTOR_SIZE as u32));
+                self.respond(&data);
+            }
+            command::READ_FORMAT_CAPACITIES => {
+                // A single descriptor, for the formatted media
+                let mut data = [0, 0, 0, 8, 0, 0, 0, 0, 0x02, 0, 0, 0];
+                data[4..8].copy_from_slice(&be_bytes(SECTORS));
+                data[9..12].copy_from_slice(&be_bytes(SECTOR_SIZE as u32)[1..]);
+                self.respond(&data);
+            }
+            command::READ_10 | command::WRITE_10 => {
+                let sector = be32(&block[2..6]);
+                let sectors = u32::from(block[7]) << 8 | u32::from(block[8]);
+                self.start_transfer(block[0] == command::READ_10, sector, sectors);
+            }
+            _ => self.fail(INVALID_COMMAND),
+        }
+    }
+
+    /// Starts reading or writing sectors, after checking that the host expects the same.
+    fn start_transfer(&mut self, read: bool, sector: u32, sectors: u32) {
+        if sector.checked_add(sectors).map_or(true, |end| end > SECTORS) {
+            return self.fail(LBA_OUT_OF_RANGE);
+        }
+        if !self.transfer.start(read, sectors * SECTOR_SIZE as u32) {
+            self.state = State::Status;
+            return;
+        }
+        self.sector = sector;
+        self.sectors = sectors;
+        self.offset = 0;
+        self.state = if read { State::Read } else { State::Write };
+    }
+
+    /// Sends the next packet of the sector being read.
+    fn read_packet(&mut self) -> bool {
+        let result = match self.volume.read_sector(self.sector) {
+            Ok(data) => Ok(endpoint::write(
+                DATA_IN,
+                &data[self.offset..self.offset + PACKET_SIZE],
+            )),
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(Ok(())) => {
+                self.next_packet();
+                if self.sectors == 0 {
+                    self.finish(COMMAND_PASSED);
+                }
+                true
+            }
+            Ok(Err(_)) => false,
+            Err(_) => {
+                self.fail(READ_ERROR);
+                true
+            }
+        }
+    }
+
+    /// Receives the next packet of the sector being written.
+    fn write_packet(&mut self) -> bool {
+        if !endpoint::out_ready((DATA_OUT & 0x7f) as usize) {
+            return false;
+        }
+        let result = match self.volume.write_sector(self.sector) {
+            Ok(data) => Ok(endpoint::read(
+                DATA_OUT,
+                &mut data[self.offset..self.offset + PACKET_SIZE],
+            )),
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(Ok(PACKET_SIZE)) => {
+                self.next_packet();
+                if self.sectors == 0 {
+                    match self.volume.flush() {
+                        Ok(()) => self.finish(COMMAND_PASSED),
+                        Err(_) => self.fail(WRITE_ERROR),
+                    }
+                }
+                true
+            }
+            Ok(Ok(len)) => {
+                // The host ended the transfer early, which it should not have
+                self.transfer.transferred += len as u32;
+                self.finish(PHASE_ERROR);
+                true
+            }
+            Ok(Err(_)) => false,
+            Err(_) => {
+                self.fail(WRITE_ERROR);
+                true
+            }
+        }
+    }
+
+    fn next_packet(&mut self) {
+        self.transfer.transferred += PACKET_SIZE as u32;
+        self.offset += PACKET_SIZE;
+        if self.offset == SECTOR_SIZE {
+            self.offset = 0;
+            self.sector += 1;
+            self.sectors -= 1;
+        }
+    }
+
+    /// Sends `data` as the response to the current command, cut short to what the host expects.
+    fn respond(&mut self, data: &[u8]) {
+        match self.transfer.respond(data.len()) {
+            Some(len) => {
+                self.packet[..len].copy_from_slice(&data[..len]);
+                self.response_len = len;
+                self.state = State::Response;
+            }
+            None => self.state = State::Status,
+        }
+    }
+
+    fn fail(&mut self, sense: Sense) {
+        self.sense = sense;
+        self.finish(COMMAND_FAILED);
+    }
+
+    fn finish(&mut self, status: u8) {
+        self.transfer.finish(status);
+        self.state = State::Status;
+    }
+
+    /// Sends the CSW, once the host has cleared the stall of the data endpoint.
+    fn send_status(&mut self) -> bool {
+        if self.transfer.stall {
+            if self.transfer.host_in && endpoint::in_busy((DATA_IN & 0x7f) as usize) {
+                return false;
+            }
+            let _ = endpoint::stall(self.transfer.data_endpoint());
+            self.transfer.stall = false;
+        }
+        if endpoint::is_stalled(DATA_IN) {
+            return false;
+        }
+
+        match endpoint::write(DATA_IN, &self.transfer.csw()) {
+            Ok(()) => {
+                self.state = State::Command;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(not(test))]
+impl Class for MassStorage {
+    fn reset(&mut self) {
+        self.set_configuration(0);
+    }
+
+    fn set_configuration(&mut self, configuration: u8) {
+        self.configured = configuration != 0;
+        self.state = State::Command;
+    }
+
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> ControlResult {
+        if setup.kind() != RequestKind::Class || setup.index != u16::from(MASS_STORAGE_INTERFACE) {
+            return ControlResult::Ignored;
+        }
+
+        match setup.request {
+            // There is only a single logical unit
+            request::GET_MAX_LUN if data.len() == 1 => {
+                data[0] = 0;
+                ControlResult::Accepted(1)
+            }
+            // The host clears the stalls of the endpoints afterwards
+            request::BULK_ONLY_RESET if data.is_empty() => {
+                self.state = State::Command;
+                ControlResult::Accepted(0)
+            }
+            _ => ControlResult::Rejected,
+        }
+    }
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0])
+        | u32::from(bytes[1]) << 8
+        | u32::from(bytes[2]) << 16
+        | u32::from(bytes[3]) << 24
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0]) << 24
+        | u32::from(bytes[1]) << 16
+        | u32::from(bytes[2]) << 8
+        | u32::from(bytes[3])
+}
+
+fn le_bytes(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+fn be_bytes(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cbw(expected: u32, host_in: bool) -> [u8; CBW_LENGTH] {
+        let mut cbw = [0; CBW_LENGTH];
+        cbw[..4].copy_from_slice(CBW_SIGNATURE);
+        cbw[4..8].copy_from_slice(&le_bytes(0x1234_5678));
+        cbw[8..12].copy_from_slice(&le_bytes(expected));
+        cbw[12] = if host_in { 0x80 } else { 0 };
+        cbw[14] = 10;
+        cbw[15] = command::READ_10;
+        cbw[30] = 0xff;
+        cbw
+    }
+
+    fn transfer(expected: u32, host_in: bool) -> Transfer {
+        Transfer::parse(&cbw(expected, host_in)).unwrap().0
+    }
+
+    // The status, the residue and the endpoint stalled before the CSW, if any
+    fn outcome(transfer: &Transfer) -> (u8, u32, Option<u8>) {
+        let csw = transfer.csw();
+        assert_eq!(&csw[..8], b"USBS\x78\x56\x34\x12");
+        let stalled = if transfer.stall {
+            Some(transfer.data_endpoint())
+        } else {
+            None
+        };
+        (csw[12], le32(&csw[8..12]), stalled)
+    }
+
+    #[test]
+    fn parse() {
+        let (transfer, block) = Transfer::parse(&cbw(512, true)).unwrap();
+        assert_eq!(
+            transfer,
+            Transfer {
+                tag: 0x1234_5678,
+                expected: 512,
+                host_in: true,
+                transferred: 0,
+                status: COMMAND_PASSED,
+                stall: false,
+            }
+        );
+        assert_eq!(block[0], command::READ_10);
+        assert_eq!(block[15], 0xff);
+        assert_eq!(Transfer::parse(&cbw(512, false)).unwrap().0.host_in, false);
+
+        let valid = cbw(0, false);
+        assert!(Transfer::parse(&valid[..30]).is_none());
+        let mut with_byte = [0; 32];
+        with_byte[..31].copy_from_slice(&valid);
+        assert!(Transfer::parse(&with_byte).is_none());
+        let invalid = [
+            (0, b'C'),
+            (13, 1),
+            (13, 0x10),
+            (14, 0),
+            (14, 17),
+            (14, 0x2a),
+        ];
+        for &(offset, value) in &invalid {
+            let mut cbw = valid;
+            cbw[offset] = value;
+            assert!(Transfer::parse(&cbw).is_none(), "{} {}", offset, value);
+        }
+        for &len in &[1, 6, 16] {
+            let mut cbw = valid;
+            cbw[14] = len;
+            assert!(Transfer::parse(&cbw).is_some());
+        }
+    }
+
+    // Cases 1 to 13 in section 6.7 of the specification, where the host expects no data (Hn),
+    // data from the device (Hi) or data to the device (Ho), and the device intends to transfer
+    // no data (Dn), data to the host (Di) or data from the host (Do)
+    #[test]
+    fn cases() {
+        // Hn = Dn, Hn < Di, Hn < Do
+        let mut hn = transfer(0, false);
+        assert!(!hn.start(false, 0));
+        assert_eq!(outcome(&hn), (COMMAND_PASSED, 0, None));
+        for &to_host in &[true, false] {
+            let mut hn = transfer(0, false);
+            assert!(!hn.start(to_host, 512));
+            assert_eq!(outcome(&hn), (PHASE_ERROR, 0, None));
+        }
+        let mut hn = transfer(0, true);
+        assert_eq!(hn.respond(36), None);
+        assert_eq!(outcome(&hn), (PHASE_ERROR, 0, None));
+
+        // Hi > Dn
+        let mut hi = transfer(512, true);
+        assert!(!hi.start(false, 0));
+        assert_eq!(outcome(&hi), (COMMAND_PASSED, 512, Some(DATA_IN)));
+        // Hi > Di, ending with a full packet and then with a short one
+        let mut hi = transfer(1024, true);
+        assert!(hi.start(true, 512));
+        hi.transferred = 512;
+        hi.finish(COMMAND_PASSED);
+        assert_eq!(outcome(&hi), (COMMAND_PASSED, 512, Some(DATA_IN)));
+        let mut hi = transfer(255, true);
+        assert_eq!(hi.respond(36), Some(36));
+        hi.transferred = 36;
+        hi.finish(COMMAND_PASSED);
+        assert_eq!(outcome(&hi), (COMMAND_PASSED, 219, None));
+        // Hi = Di
+        let mut hi = transfer(512, true);
+        assert!(hi.start(true, 512));
+        hi.transferred = 512;
+        hi.finish(COMMAND_PASSED);
+        assert_eq!(outcome(&hi), (COMMAND_PASSED, 0, None));
+        // Hi < Di, where a response is cut short instead
+        let mut hi = transfer(512, true);
+        assert!(!hi.start(true, 1024));
+        assert_eq!(outcome(&hi), (PHASE_ERROR, 512, Some(DATA_IN)));
+        let mut hi = transfer(18, true);
+        assert_eq!(hi.respond(36), Some(18));
+        // Hi <> Do
+        let mut hi = transfer(512, true);
+        assert!(!hi.start(false, 512));
+        assert_eq!(outcome(&hi), (PHASE_ERROR, 512, Some(DATA_IN)));
+
+        // Ho > Dn
+        let mut ho = transfer(512, false);
+        assert!(!ho.start(true, 0));
+        assert_eq!(outcome(&ho), (COMMAND_PASSED, 512, Some(DATA_OUT)));
+        // Ho <> Di
+        let mut ho = transfer(512, false);
+        assert!(!ho.start(true, 512));
+        assert_eq!(outcome(&ho), (PHASE_ERROR, 512, Some(DATA_OUT)));
+        let mut ho = transfer(512, false);
+        assert_eq!(ho.respond(36), None);
+        assert_eq!(outcome(&ho), (PHASE_ERROR, 512, Some(DATA_OUT)));
+        // Ho > Do, including the host ending the data early with a short packet
+        let mut ho = transfer(1024, false);
+        assert!(ho.start(false, 512));
+        ho.transferred = 512;
+        ho.finish(COMMAND_PASSED);
+        assert_eq!(outcome(&ho), (COMMAND_PASSED, 512, Some(DATA_OUT)));
+        let mut ho = transfer(512, false);
+        assert!(ho.start(false, 512));
+        ho.transferred = 100;
+        ho.finish(PHASE_ERROR);
+        assert_eq!(outcome(&ho), (PHASE_ERROR, 412, Some(DATA_OUT)));
+        // Ho = Do
+        let mut ho = transfer(512, false);
+        assert!(ho.start(false, 512));
+        ho.transferred = 512;
+        ho.finish(COMMAND_PASSED);
+        assert_eq!(outcome(&ho), (COMMAND_PASSED, 0, None));
+        // Ho < Do
+        let mut ho = transfer(512, false);
+        assert!(!ho.start(false, 1024));
+        assert_eq!(outcome(&ho), (PHASE_ERROR, 512, Some(DATA_OUT)));
+    }
+}
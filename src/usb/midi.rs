@@ -0,0 +1,555 @@
+//! A MIDI interface using the MIDI Streaming subclass of the Audio class. See the USB Device Class
+//! Definition for MIDI Devices 1.0.
+//!
+//! The host sees a MIDI port with one input and one output (an ALSA sequencer port on Linux),
+//! without needing a driver. MIDI messages travel over the bulk endpoints as 4-byte event
+//! packets, several of which are sent together in one USB packet.
+
+#[cfg(not(test))]
+use super::{endpoint, Class};
+use super::{ConfigurationBuilder, DescriptorError, Descriptors, STRINGS, TESTBOARD_DEVICE};
+#[cfg(not(test))]
+use nb;
+
+/// The bulk endpoint carrying event packets from the host.
+pub const DATA_OUT: u8 = 0x01;
+/// The bulk endpoint carrying event packets to the host.
+pub const DATA_IN: u8 = 0x81;
+
+const PACKET_SIZE: usize = 64;
+
+const AUDIO_CONTROL_INTERFACE: u8 = 0;
+const MIDI_STREAMING_INTERFACE: u8 = 1;
+
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+
+// The ids of the MIDI jacks in `descriptors`. The embedded jacks are the ends of the endpoints,
+// while the external ones stand for the MIDI connectors of the device.
+const EMBEDDED_IN_JACK: u8 = 1;
+const EXTERNAL_IN_JACK: u8 = 2;
+const EMBEDDED_OUT_JACK: u8 = 3;
+const EXTERNAL_OUT_JACK: u8 = 4;
+
+/// The size of the configuration descriptor built by `descriptors`, which is the smallest buffer
+/// it accepts.
+pub const CONFIGURATION_LENGTH: usize = 119;
+
+/// The interface number of the DFU run-time interface in `descriptors`, to be given to
+/// `dfu::DfuRuntime::new`.
+pub const DFU_INTERFACE: u8 = 2;
+
+static DEVICE: [u8; 18] = TESTBOARD_DEVICE.bytes();
+
+/// The descriptors of a device with the MIDI port, and a DFU run-time interface so `flash.sh` can
+/// put the board into the bootloader. The configuration descriptor is built into `buffer`, which
+/// has to hold at least `CONFIGURATION_LENGTH` bytes.
+///
+/// The layout follows appendix B of the MIDI specification.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+pub fn descriptors(buffer: &'static mut [u8]) -> Result<Descriptors, DescriptorError> {
+    let configuration = ConfigurationBuilder::new(buffer, 1, 0x80, 100)
+        // Audio control interface, which is required but has nothing to control
+        .interface(AUDIO_CONTROL_INTERFACE, 0, 0x01, 0x01, 0x00, 0)
+        .class_specific(&[
+            9,                          // bLength
+            CS_INTERFACE,               // bDescriptorType
+            0x01,                       // bDescriptorSubtype: Header
+            0x00, 0x01,                 // bcdADC: 1.0
+            9, 0,                       // wTotalLength
+            1,                          // bInCollection
+            MIDI_STREAMING_INTERFACE,   // baInterfaceNr(1)
+        ])
+        // MIDI streaming interface
+        .interface(MIDI_STREAMING_INTERFACE, 0, 0x01, 0x03, 0x00, 0)
+        .class_specific(&[
+            7,                          // bLength
+            CS_INTERFACE,               // bDescriptorType
+            0x01,                       // bDescriptorSubtype: MS Header
+            0x00, 0x01,                 // bcdMSC: 1.0
+            65, 0,                      // wTotalLength: Up to the end of the streaming interface
+        ])
+        // Embedded MIDI IN jack, fed by `DATA_OUT`
+        .class_specific(&[
+            6,                          // bLength
+            CS_INTERFACE,               // bDescriptorType
+            0x02,                       // bDescriptorSubtype: MIDI IN Jack
+            0x01,                       // bJackType: Embedded
+            EMBEDDED_IN_JACK,           // bJackID
+            0,                          // iJack
+        ])
+        // External MIDI IN jack
+        .class_specific(&[
+            6,                          // bLength
+            CS_INTERFACE,               // bDescriptorType
+            0x02,                       // bDescriptorSubtype: MIDI IN Jack
+            0x02,                       // bJackType: External
+            EXTERNAL_IN_JACK,           // bJackID
+            0,                          // iJack
+        ])
+        // Embedded MIDI OUT jack, feeding `DATA_IN`
+        .class_specific(&[
+            9,                          // bLength
+            CS_INTERFACE,               // bDescriptorType
+            0x03,                       // bDescriptorSubtype: MIDI OUT Jack
+            0x01,                       // bJackType: Embedded
+            EMBEDDED_OUT_JACK,          // bJackID
+            1,                          // bNrInputPins
+            EXTERNAL_IN_JACK,           // baSourceID(1)
+            1,                          // baSourcePin(1)
+            0,                          // iJack
+        ])
+        // External MIDI OUT jack
+        .class_specific(&[
+            9,                          // bLength
+            CS_INTERFACE,               // bDescriptorType
+            0x03,                       // bDescriptorSubtype: MIDI OUT Jack
+            0x02,                       // bJackType: External
+            EXTERNAL_OUT_JACK,          // bJackID
+            1,                          // bNrInputPins
+            EMBEDDED_IN_JACK,           // baSourceID(1)
+            1,                          // baSourcePin(1)
+            0,                          // iJack
+        ])
+        .audio_endpoint(DATA_OUT, 0x02, PACKET_SIZE as u16, 0)
+        .class_specific(&[
+            5,                          // bLength
+            CS_ENDPOINT,                // bDescriptorType
+            0x01,                       // bDescriptorSubtype: MS General
+            1,                          // bNumEmbMIDIJack
+            EMBEDDED_IN_JACK,           // baAssocJackID(1)
+        ])
+        .audio_endpoint(DATA_IN, 0x02, PACKET_SIZE as u16, 0)
+        .class_specific(&[
+            5,                          // bLength
+            CS_ENDPOINT,                // bDescriptorType
+            0x01,                       // bDescriptorSubtype: MS General
+            1,                          // bNumEmbMIDIJack
+            EMBEDDED_OUT_JACK,          // baAssocJackID(1)
+        ])
+        .dfu_runtime(DFU_INTERFACE)
+        .build()?;
+
+    Ok(Descriptors {
+        device: &DEVICE,
+        configuration,
+        strings: STRINGS,
+    })
+}
+
+/// The Code Index Numbers, which tell how many of the 3 MIDI bytes in an event packet are used.
+/// See table 4-1 in the MIDI specification.
+pub mod code_index {
+    pub const SYSTEM_COMMON_2: u8 = 0x2;
+    pub const SYSTEM_COMMON_3: u8 = 0x3;
+    pub const SYSEX_START: u8 = 0x4;
+    pub const SYSEX_END_1: u8 = 0x5;
+    pub const SYSEX_END_2: u8 = 0x6;
+    pub const SYSEX_END_3: u8 = 0x7;
+    pub const NOTE_OFF: u8 = 0x8;
+    pub const NOTE_ON: u8 = 0x9;
+    pub const POLY_KEY_PRESSURE: u8 = 0xa;
+    pub const CONTROL_CHANGE: u8 = 0xb;
+    pub const PROGRAM_CHANGE: u8 = 0xc;
+    pub const CHANNEL_PRESSURE: u8 = 0xd;
+    pub const PITCH_BEND: u8 = 0xe;
+    pub const SINGLE_BYTE: u8 = 0xf;
+}
+
+/// A channel voice message. Channels are numbered from 0, and the data bytes are 7 bits, except
+/// for the 14 bit pitch bend value, which is centered at 0x2000.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyKeyPressure { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    PitchBend { channel: u8, value: u16 },
+}
+
+/// A USB-MIDI event packet: the cable number and Code Index Number, followed by 3 bytes of MIDI
+/// data padded with zeros. See chapter 4 in the MIDI specification.
+///
+/// Messages other than channel voice messages, such as system exclusive messages, can be sent
+/// and received as raw packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventPacket(pub [u8; 4]);
+
+impl EventPacket {
+    pub fn new(cable: u8, code_index: u8, data: [u8; 3]) -> EventPacket {
+        EventPacket([(cable << 4) | (code_index & 0xf), data[0], data[1], data[2]])
+    }
+
+    pub fn from_message(cable: u8, message: Message) -> EventPacket {
+        use self::Message::*;
+
+        let (code_index, channel, data) = match message {
+            NoteOff {
+                channel,
+                note,
+                velocity,
+            } => (code_index::NOTE_OFF, channel, [note, velocity]),
+            NoteOn {
+                channel,
+                note,
+                velocity,
+            } => (code_index::NOTE_ON, channel, [note, velocity]),
+            PolyKeyPressure {
+                channel,
+                note,
+                pressure,
+            } => (code_index::POLY_KEY_PRESSURE, channel, [note, pressure]),
+            ControlChange {
+                channel,
+                control,
+                value,
+            } => (code_index::CONTROL_CHANGE, channel, [control, value]),
+            ProgramChange { channel, program } => {
+                (code_index::PROGRAM_CHANGE, channel, [program, 0])
+            }
+            ChannelPressure { channel, pressure } => {
+                (code_index::CHANNEL_PRESSURE, channel, [pressure, 0])
+            }
+            PitchBend { channel, value } => {
+                (code_index::PITCH_BEND, channel, [value as u8, (value >> 7) as u8])
+            }
+        };
+        // For channel voice messages, the Code Index Number is the upper nibble of the status
+        let status = (code_index << 4) | (channel & 0xf);
+        EventPacket::new(cable, code_index, [status, data[0] & 0x7f, data[1] & 0x7f])
+    }
+
+    #[inline]
+    pub fn cable(&self) -> u8 {
+        self.0[0] >> 4
+    }
+
+    #[inline]
+    pub fn code_index(&self) -> u8 {
+        self.0[0] & 0xf
+    }
+
+    /// The MIDI bytes of the packet, without the padding.
+    pub fn data(&self) -> &[u8] {
+        let len = match self.code_index() {
+            code_index::SYSEX_END_1 | code_index::SINGLE_BYTE => 1,
+            code_index::SYSTEM_COMMON_2
+            | code_index::SYSEX_END_2
+            | code_index::PROGRAM_CHANGE
+            | code_index::CHANNEL_PRESSURE => 2,
+            code_index::SYSTEM_COMMON_3
+            | code_index::SYSEX_START
+            | code_index::SYSEX_END_3
+            | code_index::NOTE_OFF
+            | code_index::NOTE_ON
+            | code_index::POLY_KEY_PRESSURE
+            | code_index::CONTROL_CHANGE
+            | code_index::PITCH_BEND => 3,
+            // Reserved for future extensions
+            _ => 0,
+        };
+        &self.0[1..1 + len]
+    }
+
+    /// The channel voice message in the packet, if it holds one.
+    pub fn message(&self) -> Option<Message> {
+        let (status, data1, data2) = (self.0[1], self.0[2], self.0[3]);
+        let channel = status & 0xf;
+        if status >> 4 != self.code_index() {
+            return None;
+        }
+        Some(match self.code_index() {
+            code_index::NOTE_OFF => Message::NoteOff {
+                channel,
+                note: data1,
+                velocity: data2,
+            },
+            code_index::NOTE_ON => Message::NoteOn {
+                channel,
+                note: data1,
+                velocity: data2,
+            },
+            code_index::POLY_KEY_PRESSURE => Message::PolyKeyPressure {
+                channel,
+                note: data1,
+                pressure: data2,
+            },
+            code_index::CONTROL_CHANGE => Message::ControlChange {
+                channel,
+                control: data1,
+                value: data2,
+            },
+            code_index::PROGRAM_CHANGE => Message::ProgramChange {
+                channel,
+                program: data1,
+            },
+            code_index::CHANNEL_PRESSURE => Message::ChannelPressure {
+                channel,
+                pressure: data1,
+            },
+            code_index::PITCH_BEND => Message::PitchBend {
+                channel,
+                value: u16::from(data1) | u16::from(data2) << 7,
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// The MIDI port. It has to be given to `UsbDevice::poll`, and its endpoints have to be part of
+/// the configuration descriptor, as they are in `descriptors`.
+///
+/// Event packets are collected into a USB packet, which is sent right away if the endpoint is
+/// idle. Otherwise it is sent by the next `send_packet` or `flush` once the endpoint is done with
+/// the previous one. Everything sent while the device is not configured is discarded.
+#[cfg(not(test))]
+pub struct Midi {
+    configured: bool,
+    read_buffer: [u8; PACKET_SIZE],
+    read_pos: usize,
+    read_len: usize,
+    write_buffer: [u8; PACKET_SIZE],
+    write_len: usize,
+}
+
+#[cfg(not(test))]
+impl Midi {
+    pub fn new() -> Midi {
+        Midi {
+            configured: false,
+            read_buffer: [0; PACKET_SIZE],
+            read_pos: 0,
+            read_len: 0,
+            write_buffer: [0; PACKET_SIZE],
+            write_len: 0,
+        }
+    }
+
+    /// Starts sending the collected event packets, if there are any and the endpoint is idle.
+    fn try_send(&mut self) -> nb::Result<(), endpoint::Error> {
+        if self.write_len != 0 {
+            endpoint::write(DATA_IN, &self.write_buffer[..self.write_len])?;
+            self.write_len = 0;
+        }
+        Ok(())
+    }
+
+    /// Queues an event packet. Fails with `WouldBlock` if the previous USB packet is still being
+    /// sent and there is no room for more.
+    pub fn send_packet(&mut self, packet: EventPacket) -> nb::Result<(), endpoint::Error> {
+        if !self.configured {
+            return Ok(());
+        }
+        if self.write_len == PACKET_SIZE {
+            self.try_send()?;
+        }
+        self.write_buffer[self.write_len..self.write_len + 4].copy_from_slice(&packet.0);
+        self.write_len += 4;
+        match self.try_send() {
+            Err(nb::Error::Other(error)) => Err(nb::Error::Other(error)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Queues a channel voice message on the given cable, which is 0 for the only port in
+    /// `descriptors`.
+    pub fn send(&mut self, cable: u8, message: Message) -> nb::Result<(), endpoint::Error> {
+        self.send_packet(EventPacket::from_message(cable, message))
+    }
+
+    /// Sends the queued event packets, and waits for the host to pick them up.
+    pub fn flush(&mut self) -> nb::Result<(), endpoint::Error> {
+        if !self.configured {
+            return Ok(());
+        }
+        self.try_send()?;
+        if endpoint::in_busy((DATA_IN & 0x7f) as usize) {
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(())
+    }
+
+    /// Takes an event packet from the last USB packet received, reading a new one when it is
+    /// used up.
+    pub fn receive_packet(&mut self) -> nb::Result<EventPacket, endpoint::Error> {
+        // The host only sends whole event packets, but an incomplete one is skipped just in case
+        while self.read_len - self.read_pos < 4 {
+            if !self.configured {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.read_len = endpoint::read(DATA_OUT, &mut self.read_buffer)?;
+            self.read_pos = 0;
+        }
+        let mut packet = [0; 4];
+        packet.copy_from_slice(&self.read_buffer[self.read_pos..self.read_pos + 4]);
+        self.read_pos += 4;
+        Ok(EventPacket(packet))
+    }
+
+    /// Receives the next channel voice message along with its cable number, skipping all other
+    /// event packets.
+    pub fn receive(&mut self) -> nb::Result<(u8, Message), endpoint::Error> {
+        loop {
+            let packet = self.receive_packet()?;
+            if let Some(message) = packet.message() {
+                return Ok((packet.cable(), message));
+            }
+        }
+    }
+}
+
+#[cfg(not(test))]
+impl Default for Midi {
+    fn default() -> Midi {
+        Midi::new()
+    }
+}
+
+#[cfg(not(test))]
+impl Class for Midi {
+    fn reset(&mut self) {
+        self.set_configuration(0);
+    }
+
+    fn set_configuration(&mut self, configuration: u8) {
+        self.configured = configuration != 0;
+        self.read_pos = 0;
+        self.read_len = 0;
+        self.write_len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usb::validate_configuration;
+
+    #[test]
+    fn configuration() {
+        let configuration = descriptors(Box::leak(Box::new([0; CONFIGURATION_LENGTH])))
+            .unwrap()
+            .configuration;
+        assert_eq!(configuration.len(), CONFIGURATION_LENGTH);
+        assert_eq!(validate_configuration(configuration), Ok(()));
+        assert_eq!(configuration[4], DFU_INTERFACE + 1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let messages = [
+            Message::NoteOff {
+                channel: 0,
+                note: 60,
+                velocity: 64,
+            },
+            Message::NoteOn {
+                channel: 15,
+                note: 127,
+                velocity: 1,
+            },
+            Message::PolyKeyPressure {
+                channel: 3,
+                note: 0,
+                pressure: 100,
+            },
+            Message::ControlChange {
+                channel: 9,
+                control: 7,
+                value: 127,
+            },
+            Message::ProgramChange {
+                channel: 1,
+                program: 42,
+            },
+            Message::ChannelPressure {
+                channel: 2,
+                pressure: 5,
+            },
+            Message::PitchBend {
+                channel: 4,
+                value: 0x2000,
+            },
+        ];
+        for &message in messages.iter() {
+            let packet = EventPacket::from_message(5, message);
+            assert_eq!(packet.cable(), 5);
+            assert_eq!(packet.message(), Some(message));
+        }
+    }
+
+    #[test]
+    fn encoding() {
+        let note_on = EventPacket::from_message(
+            1,
+            Message::NoteOn {
+                channel: 2,
+                note: 60,
+                velocity: 100,
+            },
+        );
+        assert_eq!(note_on, EventPacket([0x19, 0x92, 60, 100]));
+        assert_eq!(note_on.data(), &[0x92, 60, 100]);
+
+        let program = EventPacket::from_message(
+            0,
+            Message::ProgramChange {
+                channel: 0,
+                program: 3,
+            },
+        );
+        assert_eq!(program, EventPacket([0x0c, 0xc0, 3, 0]));
+        assert_eq!(program.data(), &[0xc0, 3]);
+
+        // Data bytes are limited to 7 bits, and the channel to 4
+        let masked = EventPacket::from_message(
+            0,
+            Message::ControlChange {
+                channel: 0x13,
+                control: 0x87,
+                value: 0xff,
+            },
+        );
+        assert_eq!(masked, EventPacket([0x0b, 0xb3, 0x07, 0x7f]));
+    }
+
+    #[test]
+    fn pitch_bend() {
+        // (value, LSB, MSB)
+        let values = [
+            (0x0000, 0x00, 0x00),
+            (0x2000, 0x00, 0x40),
+            (0x1234, 0x34, 0x24),
+            (0x3fff, 0x7f, 0x7f),
+        ];
+        for &(value, lsb, msb) in values.iter() {
+            let message = Message::PitchBend { channel: 0, value };
+            let packet = EventPacket::from_message(0, message);
+            assert_eq!(packet, EventPacket([0x0e, 0xe0, lsb, msb]), "{:#x}", value);
+            assert_eq!(packet.message(), Some(message));
+        }
+    }
+
+    #[test]
+    fn data_lengths() {
+        // Table 4-1 in the MIDI specification, where 0x0 and 0x1 are reserved
+        let lengths = [0, 0, 2, 3, 3, 1, 2, 3, 3, 3, 3, 3, 2, 2, 3, 1];
+        for (code_index, &len) in lengths.iter().enumerate() {
+            let packet = EventPacket::new(0, code_index as u8, [0xf0, 0x01, 0x02]);
+            assert_eq!(packet.data().len(), len, "code index {:#x}", code_index);
+        }
+    }
+
+    #[test]
+    fn not_a_message() {
+        // A system exclusive message
+        let sysex = EventPacket::new(0, code_index::SYSEX_START, [0xf0, 0x7e, 0x00]);
+        assert_eq!(sysex.message(), None);
+        assert_eq!(sysex.data(), &[0xf0, 0x7e, 0x00]);
+
+        // A status byte which does not match the Code Index Number
+        let mismatch = EventPacket([0x09, 0x80, 60, 0]);
+        assert_eq!(mismatch.message(), None);
+    }
+}
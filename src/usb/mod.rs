@@ -0,0 +1,198 @@
+//! The USB peripheral, which is a Synopsys DesignWare OTG core used as a full speed device. See
+//! chapter 15 in EFM32HG-RM.pdf.
+//!
+//! The core is used in DMA mode, which means that it reads and writes endpoint data directly from
+//! RAM at the addresses in the `USB_DIEPx_DMAADDR` and `USB_DOEPx_DMAADDR` registers. These
+//! buffers have to be word-aligned, which is why they are declared as arrays of `u32`.
+//!
+//! The standard requests from chapter 9 of the USB 2.0 specification are handled by `UsbDevice`
+//! itself, while everything else is passed on to the `Class` implementations given to
+//! `UsbDevice::poll`. The endpoints in the configuration descriptor are activated when the host
+//! selects the configuration, after which the classes use them through `endpoint::read` and
+//! `endpoint::write`.
+//!
+//! When the host suspends the bus, the classes are told through `Class::suspend`, and the
+//! application can call `UsbDevice::sleep_while_suspended` to spend the suspension in EM2. The
+//! core then runs from a 32 kHz clock in its low energy mode, which is what the `LEMOSCCTRL`,
+//! `LEMIDLEEN` and `LEMPHYCTRL` bits in `USB_CTRL` are set up for.
+//!
+//! In the svd file, the registers of endpoint 1 to 3 are called `DIEP0_*` to `DIEP2_*` and
+//! `DOEP0_*` to `DOEP2_*`, which is easy to confuse with the `DIEP0*` and `DOEP0*` registers of
+//! endpoint 0.
+
+// Only the parts which do not touch the hardware, such as the descriptors and the MIDI event
+// packets, are built for the host tests, so much of the rest goes unused there
+#![cfg_attr(test, allow(dead_code))]
+
+#[cfg(not(test))]
+#[macro_use]
+pub mod endpoint;
+#[cfg(all(not(test), feature = "usb-device"))]
+pub mod bus;
+#[cfg(not(test))]
+pub mod cdc;
+mod descriptors;
+#[cfg(not(test))]
+mod device;
+#[cfg(not(test))]
+pub mod dfu;
+mod fifo;
+pub mod hid;
+pub mod mass_storage;
+pub mod midi;
+pub mod platform;
+pub mod vendor;
+pub use self::descriptors::{BosBuilder, ConfigurationBuilder, DescriptorError, DeviceDescriptor};
+pub use self::descriptors::{Descriptors, DEFAULT_DESCRIPTORS, STRINGS, TESTBOARD_DEVICE};
+pub use self::descriptors::{string_descriptor, validate_bos, validate_configuration};
+#[cfg(not(test))]
+pub use self::device::UsbDevice;
+
+/// The `bRequest` values of the standard requests. See table 9-4 in the USB 2.0 specification.
+pub mod request {
+    pub const GET_STATUS: u8 = 0;
+    pub const CLEAR_FEATURE: u8 = 1;
+    pub const SET_FEATURE: u8 = 3;
+    pub const SET_ADDRESS: u8 = 5;
+    pub const GET_DESCRIPTOR: u8 = 6;
+    pub const SET_DESCRIPTOR: u8 = 7;
+    pub const GET_CONFIGURATION: u8 = 8;
+    pub const SET_CONFIGURATION: u8 = 9;
+    pub const GET_INTERFACE: u8 = 10;
+    pub const SET_INTERFACE: u8 = 11;
+}
+
+/// The descriptor types. See table 9-5 in the USB 2.0 specification.
+pub mod descriptor_type {
+    pub const DEVICE: u8 = 1;
+    pub const CONFIGURATION: u8 = 2;
+    pub const STRING: u8 = 3;
+    pub const INTERFACE: u8 = 4;
+    pub const ENDPOINT: u8 = 5;
+    pub const DEVICE_QUALIFIER: u8 = 6;
+    pub const INTERFACE_ASSOCIATION: u8 = 11;
+    pub const BOS: u8 = 15;
+    pub const DEVICE_CAPABILITY: u8 = 16;
+}
+
+/// The feature selectors. See table 9-6 in the USB 2.0 specification.
+pub mod feature {
+    pub const ENDPOINT_HALT: u16 = 0;
+    pub const DEVICE_REMOTE_WAKEUP: u16 = 1;
+}
+
+const EP0_MAX_PACKET_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Standard,
+    Class,
+    Vendor,
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recipient {
+    Device,
+    Interface,
+    Endpoint,
+    Other,
+}
+
+/// The 8 bytes sent by the host in the SETUP stage of a control transfer.
+#[derive(Debug, Clone, Copy)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    fn parse(bytes: &[u8]) -> SetupPacket {
+        SetupPacket {
+            request_type: bytes[0],
+            request: bytes[1],
+            value: u16::from(bytes[2]) | u16::from(bytes[3]) << 8,
+            index: u16::from(bytes[4]) | u16::from(bytes[5]) << 8,
+            length: u16::from(bytes[6]) | u16::from(bytes[7]) << 8,
+        }
+    }
+
+    /// Whether the data stage, if any, goes from the device to the host.
+    #[inline]
+    pub fn is_in(&self) -> bool {
+        self.request_type & 0x80 != 0
+    }
+
+    #[inline]
+    pub fn kind(&self) -> RequestKind {
+        match (self.request_type >> 5) & 0b11 {
+            0 => RequestKind::Standard,
+            1 => RequestKind::Class,
+            2 => RequestKind::Vendor,
+            _ => RequestKind::Reserved,
+        }
+    }
+
+    #[inline]
+    pub fn recipient(&self) -> Recipient {
+        match self.request_type & 0b1_1111 {
+            0 => Recipient::Device,
+            1 => Recipient::Interface,
+            2 => Recipient::Endpoint,
+            _ => Recipient::Other,
+        }
+    }
+}
+
+/// The outcome of passing a control request to a `Class`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlResult {
+    /// The request is not meant for this class, so it should be passed on to the next one.
+    Ignored,
+    /// The request was handled. For requests with an IN data stage, this is the number of bytes
+    /// written to the buffer.
+    Accepted(usize),
+    /// The request was meant for this class but is not valid, so the control endpoint is stalled.
+    Rejected,
+}
+
+/// The part of a USB function which is not handled by `UsbDevice` itself.
+///
+/// All methods have default implementations which ignore everything.
+pub trait Class {
+    /// Called when the host resets the bus.
+    fn reset(&mut self) {}
+
+    /// Called when the host selects a configuration, or 0 when it deconfigures the device.
+    fn set_configuration(&mut self, _configuration: u8) {}
+
+    /// Called for `GET_DESCRIPTOR` requests for descriptors other than the device,
+    /// configuration and string descriptors.
+    fn get_descriptor(&mut self, _setup: &SetupPacket) -> Option<&'static [u8]> {
+        None
+    }
+
+    /// Called for all class and vendor requests, as well as standard requests directed at an
+    /// interface.
+    ///
+    /// For requests with an IN data stage the response is written to `data`. For requests with
+    /// an OUT data stage, `data` contains what was received.
+    fn control(&mut self, _setup: &SetupPacket, _data: &mut [u8]) -> ControlResult {
+        ControlResult::Ignored
+    }
+
+    /// Called for every control request which was not stalled, once its status stage has
+    /// completed. This is the place for actions which would otherwise disrupt the transfer,
+    /// such as resetting the device.
+    fn control_complete(&mut self, _setup: &SetupPacket) {}
+
+    /// Called when the host suspends the bus. The device may only draw 2.5 mA until it is
+    /// resumed, so this is the place to turn off anything power hungry.
+    fn suspend(&mut self) {}
+
+    /// Called when the bus is resumed, either by the host or by remote wakeup.
+    fn resume(&mut self) {}
+}
@@ -0,0 +1,463 @@
+//! Platform capabilities in the BOS descriptor, which let hosts use the device without a driver
+//! or an installation step:
+//!
+//! * WebUSB, which allows web pages to talk to the device and makes Chrome show a notification
+//!   with the landing page when the device is plugged in. See https://wicg.github.io/webusb/.
+//! * Microsoft OS 2.0 descriptors, which make Windows load WinUSB for an interface, so it can
+//!   be used through libusb or WebUSB. See the Microsoft OS 2.0 Descriptors Specification.
+//!
+//! Hosts only ask for the BOS descriptor if `bcdUSB` in the device descriptor is at least 2.01,
+//! and Chrome only looks for WebUSB if it is at least 2.10.
+
+use super::{descriptor_type, BosBuilder, Class, ControlResult, DescriptorError, Descriptors};
+use super::{Recipient, RequestKind, SetupPacket};
+use super::descriptors::MAX_STRING_LENGTH;
+
+/// The `bDevCapabilityType` of platform capabilities. See table 9-14 in the USB 3.2
+/// specification.
+const PLATFORM: u8 = 0x05;
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const WEBUSB_UUID: [u8; 16] = [
+    0x38, 0xb6, 0x08, 0x34, 0xa9, 0x09, 0xa0, 0x47,
+    0x8b, 0xfd, 0xa0, 0x76, 0x88, 0x15, 0xb6, 0x65,
+];
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const MS_OS_20_UUID: [u8; 16] = [
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c,
+    0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+];
+
+/// The `wIndex` of the WebUSB `GET_URL` request.
+const WEBUSB_GET_URL: u16 = 2;
+/// The `bDescriptorType` of the WebUSB URL descriptor.
+const WEBUSB_URL: u8 = 3;
+/// The index of the landing page URL, as given in `iLandingPage`.
+const LANDING_PAGE: u16 = 1;
+
+/// The longest URL descriptor which fits in the buffer used to answer control requests.
+const MAX_URL_DESCRIPTOR_LENGTH: usize = 2 + 2 * MAX_STRING_LENGTH;
+
+/// The `wIndex` of the request for the Microsoft OS 2.0 descriptor set.
+const MS_OS_20_DESCRIPTOR_INDEX: u16 = 7;
+/// Windows 8.1, the first version to support Microsoft OS 2.0 descriptors.
+const WINDOWS_VERSION: [u8; 4] = [0x00, 0x00, 0x03, 0x06];
+
+// The `wDescriptorType` of the Microsoft OS 2.0 descriptors
+const MS_OS_20_SET_HEADER_DESCRIPTOR: u8 = 0x00;
+const MS_OS_20_SUBSET_HEADER_CONFIGURATION: u8 = 0x01;
+const MS_OS_20_SUBSET_HEADER_FUNCTION: u8 = 0x02;
+const MS_OS_20_FEATURE_COMPATIBLE_ID: u8 = 0x03;
+
+/// The compatible id descriptor which makes Windows load WinUSB.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const WINUSB_COMPATIBLE_ID: [u8; 20] = [
+    20, 0,                                      // wLength
+    MS_OS_20_FEATURE_COMPATIBLE_ID, 0,          // wDescriptorType
+    b'W', b'I', b'N', b'U', b'S', b'B', 0, 0,   // CompatibleID
+    0, 0, 0, 0, 0, 0, 0, 0,                     // SubCompatibleID
+];
+
+/// The length of the descriptor set for a device with a single interface, which only has the set
+/// header and the compatible id.
+const MS_OS_20_SET_LENGTH: u16 = 10 + 20;
+/// The length of the descriptor set for a composite device, where the compatible id is wrapped in
+/// configuration and function subsets.
+const MS_OS_20_COMPOSITE_SET_LENGTH: u16 = 10 + 8 + 8 + 20;
+
+/// The size of the BOS descriptor with both capabilities, which is the smallest buffer
+/// `Platform::new` accepts.
+pub const BOS_LENGTH: usize = 5 + 24 + 28;
+
+/// How the platform capabilities are presented to the host.
+///
+/// The vendor codes are used as `bRequest` of the vendor requests by which the host fetches the
+/// landing page and the Microsoft OS 2.0 descriptor set, so they must not clash with the vendor
+/// requests of any class given to `UsbDevice::poll`.
+#[derive(Debug, Clone, Copy)]
+pub struct PlatformConfig {
+    /// The `bRequest` of WebUSB requests.
+    pub webusb_vendor_code: u8,
+    /// The URL shown by the browser when the device is plugged in, including the `https://` or
+    /// `http://` prefix.
+    pub landing_page: Option<&'static str>,
+    /// The `bRequest` of the request for the Microsoft OS 2.0 descriptor set.
+    pub ms_os_vendor_code: u8,
+    /// The interface which Windows should bind WinUSB to.
+    pub winusb_interface: u8,
+}
+
+/// Serves the BOS descriptor with the WebUSB and Microsoft OS 2.0 platform capabilities, and
+/// answers the vendor requests which go with them. It has to be given to `UsbDevice::poll`.
+pub struct Platform {
+    config: PlatformConfig,
+    bos: &'static [u8],
+    composite: bool,
+}
+
+impl Platform {
+    /// Builds the BOS descriptor into `buffer`, which has to hold at least `BOS_LENGTH` bytes.
+    ///
+    /// Fails if `bcdUSB` in the device descriptor is below 2.10, if `winusb_interface` does not
+    /// exist, or if the landing page is too long for a URL descriptor.
+    pub fn new(
+        buffer: &'static mut [u8],
+        descriptors: &Descriptors,
+        config: PlatformConfig,
+    ) -> Result<Platform, DescriptorError> {
+        let bcd_usb = u16::from(descriptors.device[2]) | u16::from(descriptors.device[3]) << 8;
+        if bcd_usb < 0x0210 {
+            return Err(DescriptorError::InvalidField { offset: 2 });
+        }
+        let interfaces = descriptors.configuration[4];
+        if config.winusb_interface >= interfaces {
+            return Err(DescriptorError::InvalidInterfaceNumbering { offset: 4 });
+        }
+        if let Some(url) = config.landing_page {
+            if 3 + strip_scheme(url).1.len() > MAX_URL_DESCRIPTOR_LENGTH {
+                return Err(DescriptorError::InvalidString {
+                    index: LANDING_PAGE as u8,
+                });
+            }
+        }
+
+        let composite = interfaces > 1;
+        let set_length = if composite {
+            MS_OS_20_COMPOSITE_SET_LENGTH
+        } else {
+            MS_OS_20_SET_LENGTH
+        };
+
+        let mut webusb = [0; 21];
+        webusb[1..17].copy_from_slice(&WEBUSB_UUID);
+        webusb[17..19].copy_from_slice(&[0x00, 0x01]); // bcdVersion: 1.0
+        webusb[19] = config.webusb_vendor_code;
+        webusb[20] = if config.landing_page.is_some() { LANDING_PAGE as u8 } else { 0 };
+
+        let mut ms_os = [0; 25];
+        ms_os[1..17].copy_from_slice(&MS_OS_20_UUID);
+        ms_os[17..21].copy_from_slice(&WINDOWS_VERSION);
+        ms_os[21..23].copy_from_slice(&[set_length as u8, (set_length >> 8) as u8]);
+        ms_os[23] = config.ms_os_vendor_code;
+        ms_os[24] = 0; // bAltEnumCode: No alternate enumeration
+
+        let bos = BosBuilder::new(buffer)
+            .capability(PLATFORM, &webusb)
+            .capability(PLATFORM, &ms_os)
+            .build()?;
+
+        Ok(Platform {
+            config,
+            bos,
+            composite,
+        })
+    }
+
+    /// Writes the URL descriptor of the landing page to `data`, truncated to its length.
+    fn url_descriptor(&self, data: &mut [u8]) -> Option<usize> {
+        let (scheme, url) = strip_scheme(self.config.landing_page?);
+        let header = [3 + url.len() as u8, WEBUSB_URL, scheme];
+        Some(copy_truncated(&header, url.as_bytes(), data))
+    }
+
+    /// Writes the Microsoft OS 2.0 descriptor set to `data`, truncated to its length.
+    fn ms_os_descriptor_set(&self, data: &mut [u8]) -> usize {
+        let mut set = [0; MS_OS_20_COMPOSITE_SET_LENGTH as usize];
+        let length = if self.composite {
+            MS_OS_20_COMPOSITE_SET_LENGTH
+        } else {
+            MS_OS_20_SET_LENGTH
+        };
+        set[..4].copy_from_slice(&[10, 0, MS_OS_20_SET_HEADER_DESCRIPTOR, 0]);
+        set[4..8].copy_from_slice(&WINDOWS_VERSION);
+        set[8..10].copy_from_slice(&[length as u8, (length >> 8) as u8]);
+        let mut offset = 10;
+        if self.composite {
+            // The subsets are numbered by index, so the only configuration is 0
+            let configuration_subset = [
+                8, 0, MS_OS_20_SUBSET_HEADER_CONFIGURATION, 0, 0, 0, length as u8 - 10, 0,
+            ];
+            let function_subset = [
+                8, 0, MS_OS_20_SUBSET_HEADER_FUNCTION, 0, self.config.winusb_interface, 0, 28, 0,
+            ];
+            set[offset..offset + 8].copy_from_slice(&configuration_subset);
+            set[offset + 8..offset + 16].copy_from_slice(&function_subset);
+            offset += 16;
+        }
+        set[offset..offset + 20].copy_from_slice(&WINUSB_COMPATIBLE_ID);
+
+        copy_truncated(&set[..length as usize], &[], data)
+    }
+}
+
+impl Class for Platform {
+    fn get_descriptor(&mut self, setup: &SetupPacket) -> Option<&'static [u8]> {
+        if setup.value == u16::from(descriptor_type::BOS) << 8 {
+            Some(self.bos)
+        } else {
+            None
+        }
+    }
+
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> ControlResult {
+        if setup.kind() != RequestKind::Vendor
+            || setup.recipient() != Recipient::Device
+            || !setup.is_in()
+        {
+            return ControlResult::Ignored;
+        }
+
+        let request = (setup.request, setup.index);
+        let webusb_url = (self.config.webusb_vendor_code, WEBUSB_GET_URL);
+        let ms_os_set = (self.config.ms_os_vendor_code, MS_OS_20_DESCRIPTOR_INDEX);
+        if request == webusb_url {
+            match self.url_descriptor(data) {
+                Some(len) if setup.value == LANDING_PAGE => ControlResult::Accepted(len),
+                _ => ControlResult::Rejected,
+            }
+        } else if request == ms_os_set {
+            ControlResult::Accepted(self.ms_os_descriptor_set(data))
+        } else {
+            ControlResult::Ignored
+        }
+    }
+}
+
+/// Splits a URL into the `bScheme` of the URL descriptor and the rest of the URL.
+fn strip_scheme(url: &str) -> (u8, &str) {
+    if url.starts_with("https://") {
+        (1, &url[8..])
+    } else if url.starts_with("http://") {
+        (0, &url[7..])
+    } else {
+        // The scheme is part of the URL
+        (255, url)
+    }
+}
+
+/// Copies `header` followed by `body` to `data` for as far as it goes, and returns how many
+/// bytes were copied.
+fn copy_truncated(header: &[u8], body: &[u8], data: &mut [u8]) -> usize {
+    let mut len = 0;
+    for (dst, &src) in data.iter_mut().zip(header.iter().chain(body)) {
+        *dst = src;
+        len += 1;
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usb::{validate_bos, ConfigurationBuilder, DeviceDescriptor, STRINGS, TESTBOARD_DEVICE};
+
+    fn buffer(len: usize) -> &'static mut [u8] {
+        Box::leak(vec![0; len].into_boxed_slice())
+    }
+
+    // A device with `interfaces` interfaces and the given `bcdUSB`
+    fn descriptors(usb_version: u16, interfaces: u8) -> Descriptors {
+        let device = DeviceDescriptor {
+            usb_version,
+            ..TESTBOARD_DEVICE
+        }
+        .bytes();
+        let mut builder = ConfigurationBuilder::new(buffer(64), 1, 0x80, 100);
+        for number in 0..interfaces {
+            builder = builder.interface(number, 0, 0xff, 0x00, 0x00, 0);
+        }
+        Descriptors {
+            device: Box::leak(Box::new(device)),
+            configuration: builder.build().unwrap(),
+            strings: STRINGS,
+        }
+    }
+
+    fn config(landing_page: Option<&'static str>) -> PlatformConfig {
+        PlatformConfig {
+            webusb_vendor_code: 0x21,
+            landing_page,
+            ms_os_vendor_code: 0x22,
+            winusb_interface: 0,
+        }
+    }
+
+    fn platform_for(interfaces: u8, config: PlatformConfig) -> Platform {
+        Platform::new(buffer(BOS_LENGTH), &descriptors(0x0210, interfaces), config).unwrap()
+    }
+
+    // A vendor request to the device, reading `length` bytes
+    fn vendor_in(request: u8, value: u16, index: u16, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: 0xc0,
+            request,
+            value,
+            index,
+            length,
+        }
+    }
+
+    #[test]
+    fn bos() {
+        let mut platform = platform_for(1, config(Some("https://example.com")));
+        let bos = platform
+            .get_descriptor(&SetupPacket {
+                value: u16::from(descriptor_type::BOS) << 8,
+                ..vendor_in(0x06, 0, 0, 255)
+            })
+            .unwrap();
+        assert_eq!(bos.len(), BOS_LENGTH);
+        assert_eq!(validate_bos(bos), Ok(()));
+        assert_eq!(
+            &bos[..5],
+            &[5, descriptor_type::BOS, BOS_LENGTH as u8, 0, 2]
+        );
+
+        // WebUSB, with version 1.0, the vendor code and the landing page
+        assert_eq!(&bos[5..9], &[24, 0x10, PLATFORM, 0]);
+        assert_eq!(&bos[9..25], &WEBUSB_UUID);
+        assert_eq!(&bos[25..29], &[0x00, 0x01, 0x21, 1]);
+
+        // Microsoft OS 2.0, with the Windows version, the length of the set and the vendor code
+        assert_eq!(&bos[29..33], &[28, 0x10, PLATFORM, 0]);
+        assert_eq!(&bos[33..49], &MS_OS_20_UUID);
+        assert_eq!(&bos[49..], &[0x00, 0x00, 0x03, 0x06, 30, 0, 0x22, 0]);
+
+        let bos = platform_for(2, config(None)).bos;
+        assert_eq!(&bos[28], &0);
+        assert_eq!(&bos[53..55], &[46, 0]);
+    }
+
+    #[test]
+    fn errors() {
+        let new = |buffer, usb_version, interfaces, config| {
+            Platform::new(buffer, &descriptors(usb_version, interfaces), config).err()
+        };
+        assert_eq!(
+            new(buffer(BOS_LENGTH), 0x0201, 1, config(None)),
+            Some(DescriptorError::InvalidField { offset: 2 })
+        );
+        assert_eq!(
+            new(buffer(BOS_LENGTH), 0x0210, 0, config(None)),
+            Some(DescriptorError::InvalidInterfaceNumbering { offset: 4 })
+        );
+        assert_eq!(
+            new(buffer(BOS_LENGTH - 1), 0x0210, 1, config(None)),
+            Some(DescriptorError::BufferTooSmall)
+        );
+
+        // The longest URL which fits, and one which is a byte too long
+        let longest = &*Box::leak(format!("https://{:1$}", "", 125).into_boxed_str());
+        assert_eq!(
+            new(buffer(BOS_LENGTH), 0x0210, 1, config(Some(longest))),
+            None
+        );
+        let too_long = &*Box::leak(format!("http://{:1$}", "", 126).into_boxed_str());
+        assert_eq!(
+            new(buffer(BOS_LENGTH), 0x0210, 1, config(Some(too_long))),
+            Some(DescriptorError::InvalidString { index: 1 })
+        );
+    }
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    #[test]
+    fn ms_os_descriptor_set() {
+        let mut data = [0; 64];
+        let setup = vendor_in(0x22, 0, MS_OS_20_DESCRIPTOR_INDEX, 64);
+        let result = platform_for(1, config(None)).control(&setup, &mut data);
+        assert_eq!(result, ControlResult::Accepted(30));
+        assert_eq!(&data[..30], &[
+            10, 0, 0x00, 0, 0x00, 0x00, 0x03, 0x06, 30, 0,
+            20, 0, 0x03, 0, b'W', b'I', b'N', b'U', b'S', b'B', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ][..]);
+
+        let mut data = [0; 64];
+        let config = PlatformConfig {
+            winusb_interface: 1,
+            ..config(None)
+        };
+        let result = platform_for(2, config).control(&setup, &mut data);
+        assert_eq!(result, ControlResult::Accepted(46));
+        assert_eq!(&data[..46], &[
+            10, 0, 0x00, 0, 0x00, 0x00, 0x03, 0x06, 46, 0,
+            8, 0, 0x01, 0, 0, 0, 36, 0,
+            8, 0, 0x02, 0, 1, 0, 28, 0,
+            20, 0, 0x03, 0, b'W', b'I', b'N', b'U', b'S', b'B', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ][..]);
+
+        // The host may ask for less, such as just the header
+        let mut data = [0; 10];
+        let result = platform_for(2, config).control(&setup, &mut data);
+        assert_eq!(result, ControlResult::Accepted(10));
+        assert_eq!(&data[8..], &[46, 0]);
+    }
+
+    #[test]
+    fn landing_page() {
+        let get_url = vendor_in(0x21, LANDING_PAGE, WEBUSB_GET_URL, 64);
+        let url = |landing_page, data: &mut [u8]| {
+            platform_for(1, config(Some(landing_page))).control(&get_url, data)
+        };
+
+        let mut data = [0; 64];
+        assert_eq!(
+            url("https://example.com", &mut data),
+            ControlResult::Accepted(14)
+        );
+        assert_eq!(&data[..14], b"\x0e\x03\x01example.com");
+        assert_eq!(
+            url("http://example.com", &mut data),
+            ControlResult::Accepted(14)
+        );
+        assert_eq!(&data[..3], &[14, WEBUSB_URL, 0]);
+        // Any other scheme is kept in the URL
+        assert_eq!(url("file:///a", &mut data), ControlResult::Accepted(12));
+        assert_eq!(&data[..12], b"\x0c\x03\xfffile:///a");
+
+        // Truncated to what the host asked for, with the full length in `bLength`
+        let mut data = [0; 5];
+        assert_eq!(
+            url("https://example.com", &mut data),
+            ControlResult::Accepted(5)
+        );
+        assert_eq!(&data, b"\x0e\x03\x01ex");
+
+        let mut data = [0; 64];
+        let other_index = SetupPacket {
+            value: 2,
+            ..get_url
+        };
+        let mut with_url = platform_for(1, config(Some("https://example.com")));
+        assert_eq!(
+            with_url.control(&other_index, &mut data),
+            ControlResult::Rejected
+        );
+        let mut without_url = platform_for(1, config(None));
+        assert_eq!(
+            without_url.control(&get_url, &mut data),
+            ControlResult::Rejected
+        );
+    }
+
+    #[test]
+    fn other_requests() {
+        let mut platform = platform_for(1, config(Some("https://example.com")));
+        let mut data = [0; 64];
+        let out = SetupPacket {
+            request_type: 0x40,
+            ..vendor_in(0x22, 0, MS_OS_20_DESCRIPTOR_INDEX, 64)
+        };
+        assert_eq!(platform.control(&out, &mut data), ControlResult::Ignored);
+        let other_request = vendor_in(0x23, 0, MS_OS_20_DESCRIPTOR_INDEX, 64);
+        assert_eq!(
+            platform.control(&other_request, &mut data),
+            ControlResult::Ignored
+        );
+        let class = SetupPacket {
+            request_type: 0xa1,
+            ..vendor_in(0x21, LANDING_PAGE, WEBUSB_GET_URL, 64)
+        };
+        assert_eq!(platform.control(&class, &mut data), ControlResult::Ignored);
+    }
+}
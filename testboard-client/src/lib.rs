@@ -0,0 +1,386 @@
+//! A host-side client for the vendor test protocol of the testboard firmware.
+//!
+//! The client is independent of the USB library used, which is plugged in by implementing
+//! `Transport`. This also makes it possible to test the client without any hardware.
+//!
+//! The `.cargo/config` of the firmware around it only applies when building for the
+//! microcontroller, so the crate builds for whatever host it is on.
+
+use std::fmt;
+
+#[path = "../../src/usb/vendor/protocol.rs"]
+pub mod protocol;
+
+use protocol::request;
+
+/// The USB operations needed by the client. The control transfers are vendor requests directed
+/// at the device, with `bmRequestType` being `protocol::REQUEST_TYPE_IN` or
+/// `protocol::REQUEST_TYPE_OUT`. The bulk transfers use `protocol::STREAM_IN` and
+/// `protocol::STREAM_OUT`.
+pub trait Transport {
+    type Error;
+
+    /// Performs a control transfer with an IN data stage, returning how many bytes were
+    /// received into `buffer`.
+    fn control_in(
+        &mut self,
+        request: u8,
+        value: u16,
+        index: u16,
+        buffer: &mut [u8],
+    ) -> Result<usize, Self::Error>;
+
+    /// Performs a control transfer with an OUT data stage, or without a data stage if `data` is
+    /// empty.
+    fn control_out(
+        &mut self,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<(), Self::Error>;
+
+    /// Sends `data` on the bulk OUT endpoint, returning how many bytes were sent.
+    fn bulk_out(&mut self, data: &[u8]) -> Result<usize, Self::Error>;
+
+    /// Receives from the bulk IN endpoint into `buffer`, returning how many bytes were received.
+    fn bulk_in(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+    Transport(E),
+    /// The device answered with fewer bytes than the request requires.
+    ShortResponse { expected: usize, received: usize },
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Transport(ref error) => write!(f, "USB transfer failed: {}", error),
+            Error::ShortResponse { expected, received } => write!(
+                f,
+                "expected a response of {} bytes, but received {}",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(error: E) -> Error<E> {
+        Error::Transport(error)
+    }
+}
+
+/// A pin on the board. `port` is 0 for port A, 1 for port B and so on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pin {
+    pub port: u8,
+    pub number: u8,
+}
+
+impl Pin {
+    pub fn new(port: char, number: u8) -> Pin {
+        Pin {
+            port: (port.to_ascii_uppercase() as u8).wrapping_sub(b'A'),
+            number,
+        }
+    }
+
+    fn value(&self) -> u16 {
+        protocol::pin_value(self.port, self.number)
+    }
+}
+
+pub struct Client<T> {
+    transport: T,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T) -> Client<T> {
+        Client { transport }
+    }
+
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    fn control_in_exact(
+        &mut self,
+        request: u8,
+        value: u16,
+        index: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), Error<T::Error>> {
+        let received = self.transport.control_in(request, value, index, buffer)?;
+        if received < buffer.len() {
+            return Err(Error::ShortResponse {
+                expected: buffer.len(),
+                received,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn read_register(&mut self, address: u32) -> Result<u32, Error<T::Error>> {
+        let (value, index) = protocol::address_values(address);
+        let mut buffer = [0; 4];
+        self.control_in_exact(request::READ_REGISTER, value, index, &mut buffer)?;
+        Ok(u32::from(buffer[0])
+            | u32::from(buffer[1]) << 8
+            | u32::from(buffer[2]) << 16
+            | u32::from(buffer[3]) << 24)
+    }
+
+    pub fn write_register(&mut self, address: u32, word: u32) -> Result<(), Error<T::Error>> {
+        let (value, index) = protocol::address_values(address);
+        let data = [word as u8, (word >> 8) as u8, (word >> 16) as u8, (word >> 24) as u8];
+        self.transport
+            .control_out(request::WRITE_REGISTER, value, index, &data)?;
+        Ok(())
+    }
+
+    pub fn set_pin(&mut self, pin: Pin, high: bool) -> Result<(), Error<T::Error>> {
+        self.transport
+            .control_out(request::SET_PIN, pin.value(), high as u16, &[])?;
+        Ok(())
+    }
+
+    /// Returns whether the pin is high.
+    pub fn read_pin(&mut self, pin: Pin) -> Result<bool, Error<T::Error>> {
+        let mut buffer = [0; 1];
+        self.control_in_exact(request::READ_PIN, pin.value(), 0, &mut buffer)?;
+        Ok(buffer[0] != 0)
+    }
+
+    pub fn read_adc(&mut self, channel: u8) -> Result<u16, Error<T::Error>> {
+        let mut buffer = [0; 2];
+        self.control_in_exact(request::READ_ADC, channel.into(), 0, &mut buffer)?;
+        Ok(u16::from(buffer[0]) | u16::from(buffer[1]) << 8)
+    }
+
+    /// Sends `data` on the stream, returning how many bytes were sent.
+    pub fn stream_write(&mut self, data: &[u8]) -> Result<usize, Error<T::Error>> {
+        Ok(self.transport.bulk_out(data)?)
+    }
+
+    /// Receives from the stream. The buffer should be a multiple of
+    /// `protocol::STREAM_PACKET_SIZE`, since the device may send full packets back to back.
+    pub fn stream_read(&mut self, buffer: &mut [u8]) -> Result<usize, Error<T::Error>> {
+        Ok(self.transport.bulk_in(buffer)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Transfer {
+        ControlIn { request: u8, value: u16, index: u16, length: usize },
+        ControlOut { request: u8, value: u16, index: u16, data: Vec<u8> },
+        BulkOut(Vec<u8>),
+        BulkIn(usize),
+    }
+
+    /// Records every transfer, and answers IN transfers from a queue of responses.
+    #[derive(Default)]
+    struct MockTransport {
+        transfers: Vec<Transfer>,
+        responses: VecDeque<Result<Vec<u8>, &'static str>>,
+    }
+
+    impl MockTransport {
+        fn respond(mut self, data: &[u8]) -> MockTransport {
+            self.responses.push_back(Ok(data.to_vec()));
+            self
+        }
+
+        fn fail(mut self, error: &'static str) -> MockTransport {
+            self.responses.push_back(Err(error));
+            self
+        }
+
+        fn answer(&mut self, buffer: &mut [u8]) -> Result<usize, &'static str> {
+            let data = self.responses.pop_front().expect("unexpected IN transfer")?;
+            let len = data.len().min(buffer.len());
+            buffer[..len].copy_from_slice(&data[..len]);
+            Ok(len)
+        }
+    }
+
+    impl Transport for MockTransport {
+        type Error = &'static str;
+
+        fn control_in(
+            &mut self,
+            request: u8,
+            value: u16,
+            index: u16,
+            buffer: &mut [u8],
+        ) -> Result<usize, &'static str> {
+            self.transfers.push(Transfer::ControlIn {
+                request,
+                value,
+                index,
+                length: buffer.len(),
+            });
+            self.answer(buffer)
+        }
+
+        fn control_out(
+            &mut self,
+            request: u8,
+            value: u16,
+            index: u16,
+            data: &[u8],
+        ) -> Result<(), &'static str> {
+            self.transfers.push(Transfer::ControlOut {
+                request,
+                value,
+                index,
+                data: data.to_vec(),
+            });
+            match self.responses.front() {
+                Some(&Err(error)) => {
+                    self.responses.pop_front();
+                    Err(error)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn bulk_out(&mut self, data: &[u8]) -> Result<usize, &'static str> {
+            self.transfers.push(Transfer::BulkOut(data.to_vec()));
+            Ok(data.len())
+        }
+
+        fn bulk_in(&mut self, buffer: &mut [u8]) -> Result<usize, &'static str> {
+            self.transfers.push(Transfer::BulkIn(buffer.len()));
+            self.answer(buffer)
+        }
+    }
+
+    #[test]
+    fn read_register_splits_address_and_decodes_little_endian() {
+        let mut client = Client::new(MockTransport::default().respond(&[0x78, 0x56, 0x34, 0x12]));
+        assert_eq!(client.read_register(0x400c_6000), Ok(0x1234_5678));
+        assert_eq!(
+            client.into_transport().transfers,
+            vec![Transfer::ControlIn {
+                request: request::READ_REGISTER,
+                value: 0x6000,
+                index: 0x400c,
+                length: 4,
+            }]
+        );
+    }
+
+    #[test]
+    fn write_register_sends_little_endian_word() {
+        let mut client = Client::new(MockTransport::default());
+        assert_eq!(client.write_register(0x2000_0100, 0xdead_beef), Ok(()));
+        assert_eq!(
+            client.into_transport().transfers,
+            vec![Transfer::ControlOut {
+                request: request::WRITE_REGISTER,
+                value: 0x0100,
+                index: 0x2000,
+                data: vec![0xef, 0xbe, 0xad, 0xde],
+            }]
+        );
+    }
+
+    #[test]
+    fn set_pin_encodes_port_and_level() {
+        let mut client = Client::new(MockTransport::default());
+        client.set_pin(Pin::new('c', 14), true).unwrap();
+        client.set_pin(Pin::new('A', 0), false).unwrap();
+        assert_eq!(
+            client.into_transport().transfers,
+            vec![
+                Transfer::ControlOut {
+                    request: request::SET_PIN,
+                    value: 0x020e,
+                    index: 1,
+                    data: vec![],
+                },
+                Transfer::ControlOut {
+                    request: request::SET_PIN,
+                    value: 0x0000,
+                    index: 0,
+                    data: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn read_pin_and_adc() {
+        let transport = MockTransport::default().respond(&[1]).respond(&[0xff, 0x0f]);
+        let mut client = Client::new(transport);
+        assert_eq!(client.read_pin(Pin::new('B', 13)), Ok(true));
+        assert_eq!(client.read_adc(5), Ok(0x0fff));
+        assert_eq!(
+            client.into_transport().transfers,
+            vec![
+                Transfer::ControlIn {
+                    request: request::READ_PIN,
+                    value: 0x010d,
+                    index: 0,
+                    length: 1,
+                },
+                Transfer::ControlIn {
+                    request: request::READ_ADC,
+                    value: 5,
+                    index: 0,
+                    length: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn short_responses_are_errors() {
+        let mut client = Client::new(MockTransport::default().respond(&[0x12, 0x34]));
+        assert_eq!(
+            client.read_register(0),
+            Err(Error::ShortResponse {
+                expected: 4,
+                received: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn transport_errors_are_passed_on() {
+        let mut client = Client::new(MockTransport::default().fail("stall").fail("pipe"));
+        assert_eq!(client.read_adc(0), Err(Error::Transport("stall")));
+        assert_eq!(client.set_pin(Pin::new('A', 1), true), Err(Error::Transport("pipe")));
+    }
+
+    #[test]
+    fn stream_uses_bulk_endpoints() {
+        let mut client = Client::new(MockTransport::default().respond(b"pong"));
+        assert_eq!(client.stream_write(b"ping"), Ok(4));
+        let mut buffer = [0; protocol::STREAM_PACKET_SIZE];
+        assert_eq!(client.stream_read(&mut buffer), Ok(4));
+        assert_eq!(&buffer[..4], b"pong");
+        assert_eq!(
+            client.into_transport().transfers,
+            vec![
+                Transfer::BulkOut(b"ping".to_vec()),
+                Transfer::BulkIn(protocol::STREAM_PACKET_SIZE),
+            ]
+        );
+    }
+
+    #[test]
+    fn protocol_encodings_round_trip() {
+        assert_eq!(protocol::pin_from_value(protocol::pin_value(5, 15)), (5, 15));
+        let (value, index) = protocol::address_values(0x0fe0_81f0);
+        assert_eq!(protocol::address_from_values(value, index), 0x0fe0_81f0);
+    }
+}